@@ -1,7 +1,7 @@
 -- Initial database schema for Email Automation Bot
 
 -- Users table
-CREATE TABLE users (
+CREATE TABLE IF NOT EXISTS users (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     username TEXT UNIQUE NOT NULL,
     email TEXT UNIQUE NOT NULL,
@@ -11,7 +11,7 @@ CREATE TABLE users (
 );
 
 -- Email accounts
-CREATE TABLE email_accounts (
+CREATE TABLE IF NOT EXISTS email_accounts (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     account_name TEXT NOT NULL,
@@ -27,7 +27,7 @@ CREATE TABLE email_accounts (
 );
 
 -- Email templates
-CREATE TABLE email_templates (
+CREATE TABLE IF NOT EXISTS email_templates (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     name TEXT NOT NULL,
@@ -39,7 +39,7 @@ CREATE TABLE email_templates (
 );
 
 -- Automation rules
-CREATE TABLE automation_rules (
+CREATE TABLE IF NOT EXISTS automation_rules (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     rule_name TEXT NOT NULL,
@@ -51,7 +51,7 @@ CREATE TABLE automation_rules (
 );
 
 -- Email logs
-CREATE TABLE email_logs (
+CREATE TABLE IF NOT EXISTS email_logs (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE SET NULL,
@@ -66,7 +66,7 @@ CREATE TABLE email_logs (
 );
 
 -- Scheduled emails
-CREATE TABLE scheduled_emails (
+CREATE TABLE IF NOT EXISTS scheduled_emails (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     template_id INTEGER REFERENCES email_templates(id) ON DELETE SET NULL,
@@ -78,7 +78,7 @@ CREATE TABLE scheduled_emails (
 );
 
 -- Email attachments table for attachment parser feature
-CREATE TABLE email_attachments (
+CREATE TABLE IF NOT EXISTS email_attachments (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     email_log_id INTEGER REFERENCES email_logs(id) ON DELETE CASCADE,
@@ -94,7 +94,7 @@ CREATE TABLE email_attachments (
 );
 
 -- Contact lists for batch email functionality
-CREATE TABLE contact_lists (
+CREATE TABLE IF NOT EXISTS contact_lists (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     name TEXT NOT NULL,
@@ -104,7 +104,7 @@ CREATE TABLE contact_lists (
 );
 
 -- Contacts for batch email functionality
-CREATE TABLE contacts (
+CREATE TABLE IF NOT EXISTS contacts (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     contact_list_id INTEGER REFERENCES contact_lists(id) ON DELETE CASCADE,
@@ -118,7 +118,7 @@ CREATE TABLE contacts (
 );
 
 -- Batch email campaigns
-CREATE TABLE email_campaigns (
+CREATE TABLE IF NOT EXISTS email_campaigns (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     name TEXT NOT NULL,
@@ -134,7 +134,7 @@ CREATE TABLE email_campaigns (
 );
 
 -- Inbox monitoring settings
-CREATE TABLE inbox_monitors (
+CREATE TABLE IF NOT EXISTS inbox_monitors (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
     email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE CASCADE,
@@ -146,27 +146,27 @@ CREATE TABLE inbox_monitors (
 );
 
 -- Create indexes for better performance
-CREATE INDEX idx_users_email ON users(email);
-CREATE INDEX idx_email_accounts_user_id ON email_accounts(user_id);
-CREATE INDEX idx_email_accounts_active ON email_accounts(user_id, is_active);
-CREATE INDEX idx_email_templates_user_id ON email_templates(user_id);
-CREATE INDEX idx_automation_rules_user_id ON automation_rules(user_id);
-CREATE INDEX idx_automation_rules_active ON automation_rules(user_id, is_active);
-CREATE INDEX idx_email_logs_user_id ON email_logs(user_id);
-CREATE INDEX idx_email_logs_created_at ON email_logs(created_at DESC);
-CREATE INDEX idx_scheduled_emails_user_id ON scheduled_emails(user_id);
-CREATE INDEX idx_scheduled_emails_status ON scheduled_emails(status);
-CREATE INDEX idx_scheduled_emails_time ON scheduled_emails(scheduled_time);
-CREATE INDEX idx_email_attachments_user_id ON email_attachments(user_id);
-CREATE INDEX idx_email_attachments_email_log ON email_attachments(email_log_id);
-CREATE INDEX idx_contact_lists_user_id ON contact_lists(user_id);
-CREATE INDEX idx_contacts_user_id ON contacts(user_id);
-CREATE INDEX idx_contacts_list_id ON contacts(contact_list_id);
-CREATE INDEX idx_contacts_email ON contacts(email);
-CREATE INDEX idx_email_campaigns_user_id ON email_campaigns(user_id);
-CREATE INDEX idx_email_campaigns_status ON email_campaigns(status);
-CREATE INDEX idx_inbox_monitors_user_id ON inbox_monitors(user_id);
-CREATE INDEX idx_inbox_monitors_account ON inbox_monitors(email_account_id);
+CREATE INDEX IF NOT EXISTS idx_users_email ON users(email);
+CREATE INDEX IF NOT EXISTS idx_email_accounts_user_id ON email_accounts(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_accounts_active ON email_accounts(user_id, is_active);
+CREATE INDEX IF NOT EXISTS idx_email_templates_user_id ON email_templates(user_id);
+CREATE INDEX IF NOT EXISTS idx_automation_rules_user_id ON automation_rules(user_id);
+CREATE INDEX IF NOT EXISTS idx_automation_rules_active ON automation_rules(user_id, is_active);
+CREATE INDEX IF NOT EXISTS idx_email_logs_user_id ON email_logs(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_logs_created_at ON email_logs(created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_scheduled_emails_user_id ON scheduled_emails(user_id);
+CREATE INDEX IF NOT EXISTS idx_scheduled_emails_status ON scheduled_emails(status);
+CREATE INDEX IF NOT EXISTS idx_scheduled_emails_time ON scheduled_emails(scheduled_time);
+CREATE INDEX IF NOT EXISTS idx_email_attachments_user_id ON email_attachments(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_attachments_email_log ON email_attachments(email_log_id);
+CREATE INDEX IF NOT EXISTS idx_contact_lists_user_id ON contact_lists(user_id);
+CREATE INDEX IF NOT EXISTS idx_contacts_user_id ON contacts(user_id);
+CREATE INDEX IF NOT EXISTS idx_contacts_list_id ON contacts(contact_list_id);
+CREATE INDEX IF NOT EXISTS idx_contacts_email ON contacts(email);
+CREATE INDEX IF NOT EXISTS idx_email_campaigns_user_id ON email_campaigns(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_campaigns_status ON email_campaigns(status);
+CREATE INDEX IF NOT EXISTS idx_inbox_monitors_user_id ON inbox_monitors(user_id);
+CREATE INDEX IF NOT EXISTS idx_inbox_monitors_account ON inbox_monitors(email_account_id);
 
 -- SQLite doesn't support functions and triggers in the same way as PostgreSQL
 -- Updated_at fields will need to be handled in the application code
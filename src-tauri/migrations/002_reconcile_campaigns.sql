@@ -0,0 +1,39 @@
+-- Reconcile email_campaigns and email_logs with the queries in CampaignService
+
+-- SQLite cannot alter a CHECK constraint in place, so the campaign table is rebuilt
+-- to accept the 'partial' status and to carry an optional subject/body override.
+CREATE TABLE email_campaigns_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    subject TEXT,
+    body TEXT,
+    template_id INTEGER REFERENCES email_templates(id) ON DELETE SET NULL,
+    contact_list_id INTEGER REFERENCES contact_lists(id) ON DELETE SET NULL,
+    status TEXT DEFAULT 'draft' CHECK (status IN ('draft', 'scheduled', 'sending', 'completed', 'partial', 'failed')),
+    scheduled_time DATETIME,
+    total_recipients INTEGER DEFAULT 0,
+    sent_count INTEGER DEFAULT 0,
+    failed_count INTEGER DEFAULT 0,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO email_campaigns_new (
+    id, user_id, name, template_id, contact_list_id, status, scheduled_time,
+    total_recipients, sent_count, failed_count, created_at, updated_at
+)
+SELECT
+    id, user_id, name, template_id, contact_list_id, status, scheduled_time,
+    total_recipients, sent_count, failed_count, created_at, updated_at
+FROM email_campaigns;
+
+DROP TABLE email_campaigns;
+ALTER TABLE email_campaigns_new RENAME TO email_campaigns;
+
+CREATE INDEX IF NOT EXISTS idx_email_campaigns_user_id ON email_campaigns(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_campaigns_status ON email_campaigns(status);
+
+-- Campaign sends are logged per recipient and counted back by campaign
+ALTER TABLE email_logs ADD COLUMN campaign_id INTEGER REFERENCES email_campaigns(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS idx_email_logs_campaign_id ON email_logs(campaign_id);
@@ -0,0 +1,22 @@
+-- Durable outbound mail queue. Every send path enqueues here and the queue worker delivers.
+
+CREATE TABLE IF NOT EXISTS outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE CASCADE,
+    campaign_id INTEGER REFERENCES email_campaigns(id) ON DELETE SET NULL,
+    recipient_email TEXT NOT NULL,
+    subject TEXT,
+    message TEXT NOT NULL, -- JSON-encoded EmailMessage
+    status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN ('queued', 'sending', 'sent', 'deferred', 'bounced')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TEXT NOT NULL,
+    last_error TEXT,
+    sent_at TEXT,
+    created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_outbox_due ON outbox(status, next_attempt_at);
+CREATE INDEX IF NOT EXISTS idx_outbox_user_id ON outbox(user_id);
+CREATE INDEX IF NOT EXISTS idx_outbox_campaign_id ON outbox(campaign_id);
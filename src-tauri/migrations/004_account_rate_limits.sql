@@ -0,0 +1,10 @@
+-- Per-account sending quotas and an optional daily sending window.
+-- NULL means "no limit"; window hours are local time, 0-23, and may wrap past midnight.
+ALTER TABLE email_accounts ADD COLUMN max_per_minute INTEGER;
+ALTER TABLE email_accounts ADD COLUMN max_per_hour INTEGER;
+ALTER TABLE email_accounts ADD COLUMN max_per_day INTEGER;
+ALTER TABLE email_accounts ADD COLUMN send_window_start INTEGER;
+ALTER TABLE email_accounts ADD COLUMN send_window_end INTEGER;
+
+-- Quota checks count recent deliveries per account
+CREATE INDEX IF NOT EXISTS idx_outbox_account_sent_at ON outbox(email_account_id, status, sent_at);
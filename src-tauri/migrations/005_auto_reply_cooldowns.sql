@@ -0,0 +1,9 @@
+-- Auto-reply loop protection: one reply per sender per account within the cooldown period
+ALTER TABLE inbox_monitors ADD COLUMN auto_reply_cooldown INTEGER DEFAULT 86400; -- seconds
+
+CREATE TABLE IF NOT EXISTS auto_reply_cooldowns (
+    email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE CASCADE,
+    sender_email TEXT NOT NULL, -- lowercased
+    last_replied_at TEXT NOT NULL,
+    PRIMARY KEY (email_account_id, sender_email)
+);
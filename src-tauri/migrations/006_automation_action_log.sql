@@ -0,0 +1,14 @@
+-- Outcome of every automation action run against an incoming message
+CREATE TABLE IF NOT EXISTS automation_action_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    rule_id INTEGER REFERENCES automation_rules(id) ON DELETE SET NULL,
+    email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE SET NULL,
+    message_uid TEXT NOT NULL,
+    action_type TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('success', 'failed')),
+    detail TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_automation_action_log_user_id ON automation_action_log(user_id, created_at);
@@ -0,0 +1,12 @@
+-- Rules are evaluated in ascending priority; a matching rule with stop_processing set
+-- prevents every rule after it from running on that message.
+ALTER TABLE automation_rules ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE automation_rules ADD COLUMN stop_processing BOOLEAN NOT NULL DEFAULT 0;
+
+-- Existing rules keep their creation order
+UPDATE automation_rules SET priority = (
+    SELECT COUNT(*) FROM automation_rules AS earlier
+    WHERE earlier.user_id = automation_rules.user_id AND earlier.id < automation_rules.id
+);
+
+CREATE INDEX IF NOT EXISTS idx_automation_rules_priority ON automation_rules(user_id, priority);
@@ -0,0 +1,23 @@
+-- One row per rule that matched an incoming message, with the predicates that decided it
+CREATE TABLE IF NOT EXISTS automation_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    rule_id INTEGER REFERENCES automation_rules(id) ON DELETE SET NULL,
+    rule_name TEXT NOT NULL,
+    email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE SET NULL,
+    message_uid TEXT NOT NULL,
+    message_id TEXT,
+    sender TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    predicates TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'running' CHECK (status IN ('running', 'success', 'partial', 'failed')),
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_automation_runs_user_id ON automation_runs(user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_automation_runs_rule_id ON automation_runs(rule_id, created_at);
+
+-- Every logged action belongs to the run that triggered it
+ALTER TABLE automation_action_log ADD COLUMN run_id INTEGER REFERENCES automation_runs(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_automation_action_log_run_id ON automation_action_log(run_id);
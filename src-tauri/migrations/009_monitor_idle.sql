@@ -0,0 +1,2 @@
+-- Monitors can hold an IMAP IDLE connection open and check as soon as new mail arrives
+ALTER TABLE inbox_monitors ADD COLUMN use_idle BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Sync position per account and folder, with the CONDSTORE mod-sequence when the server has one
+CREATE TABLE IF NOT EXISTS imap_sync_state (
+    email_account_id INTEGER NOT NULL REFERENCES email_accounts(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    uid_validity INTEGER NOT NULL,
+    last_uid INTEGER NOT NULL DEFAULT 0,
+    highest_modseq INTEGER,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (email_account_id, folder)
+);
@@ -0,0 +1,37 @@
+-- Received messages, parsed for display and search; the raw message is kept on disk as .eml
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email_account_id INTEGER NOT NULL REFERENCES email_accounts(id) ON DELETE CASCADE,
+    folder TEXT NOT NULL,
+    uid_validity INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    message_id TEXT,
+    in_reply_to TEXT,
+    references_header TEXT,
+    subject TEXT,
+    sender TEXT,
+    recipients TEXT NOT NULL DEFAULT '[]', -- JSON arrays of addresses
+    cc TEXT NOT NULL DEFAULT '[]',
+    reply_to TEXT NOT NULL DEFAULT '[]',
+    headers TEXT NOT NULL DEFAULT '[]', -- JSON array of [name, value] pairs
+    body_text TEXT,
+    body_html TEXT,
+    attachments TEXT NOT NULL DEFAULT '[]', -- JSON array of filenames
+    flags TEXT NOT NULL DEFAULT '[]', -- JSON array of IMAP flags, e.g. "\\Seen"
+    thread_id INTEGER,
+    raw_path TEXT NOT NULL,
+    size INTEGER NOT NULL DEFAULT 0,
+    is_auto_generated BOOLEAN NOT NULL DEFAULT 0,
+    received_at DATETIME NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (email_account_id, folder, uid_validity, uid)
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_user_received ON messages(user_id, received_at);
+CREATE INDEX IF NOT EXISTS idx_messages_message_id ON messages(message_id);
+CREATE INDEX IF NOT EXISTS idx_messages_thread_id ON messages(thread_id);
+
+-- Attachments saved from received mail point at the stored message instead of an email log
+ALTER TABLE email_attachments ADD COLUMN message_id INTEGER REFERENCES messages(id) ON DELETE CASCADE;
+CREATE INDEX IF NOT EXISTS idx_email_attachments_message ON email_attachments(message_id);
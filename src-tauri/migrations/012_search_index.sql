@@ -0,0 +1,78 @@
+-- Full-text index over stored messages and email logs. Triggers keep it in step with both
+-- tables; the UNINDEXED columns are only there to filter and label results.
+CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(
+    subject,
+    body,
+    sender,
+    recipients,
+    attachments,
+    source UNINDEXED, -- 'message' or 'log'
+    source_id UNINDEXED,
+    user_id UNINDEXED,
+    occurred_at UNINDEXED, -- UTC, 'YYYY-MM-DD HH:MM:SS'
+    has_attachment UNINDEXED,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_search_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+    VALUES (
+        new.subject,
+        COALESCE(new.body_text, ''),
+        new.sender,
+        (SELECT group_concat(value, ' ') FROM (SELECT value FROM json_each(new.recipients) UNION ALL SELECT value FROM json_each(new.cc))),
+        (SELECT group_concat(value, ' ') FROM json_each(new.attachments)),
+        'message', new.id, new.user_id, datetime(new.received_at), json_array_length(new.attachments) > 0
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_search_update AFTER UPDATE OF subject, body_text, sender, recipients, cc, attachments ON messages BEGIN
+    DELETE FROM search_index WHERE source = 'message' AND source_id = old.id;
+    INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+    VALUES (
+        new.subject,
+        COALESCE(new.body_text, ''),
+        new.sender,
+        (SELECT group_concat(value, ' ') FROM (SELECT value FROM json_each(new.recipients) UNION ALL SELECT value FROM json_each(new.cc))),
+        (SELECT group_concat(value, ' ') FROM json_each(new.attachments)),
+        'message', new.id, new.user_id, datetime(new.received_at), json_array_length(new.attachments) > 0
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_search_delete AFTER DELETE ON messages BEGIN
+    DELETE FROM search_index WHERE source = 'message' AND source_id = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS email_logs_search_insert AFTER INSERT ON email_logs BEGIN
+    INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+    VALUES (
+        new.subject, COALESCE(new.error_message, ''), new.sender_email, new.recipient_email, '',
+        'log', new.id, new.user_id, datetime(COALESCE(new.sent_at, new.created_at)), 0
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS email_logs_search_update AFTER UPDATE OF subject, error_message, sender_email, recipient_email ON email_logs BEGIN
+    DELETE FROM search_index WHERE source = 'log' AND source_id = old.id;
+    INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+    VALUES (
+        new.subject, COALESCE(new.error_message, ''), new.sender_email, new.recipient_email, '',
+        'log', new.id, new.user_id, datetime(COALESCE(new.sent_at, new.created_at)), 0
+    );
+END;
+
+CREATE TRIGGER IF NOT EXISTS email_logs_search_delete AFTER DELETE ON email_logs BEGIN
+    DELETE FROM search_index WHERE source = 'log' AND source_id = old.id;
+END;
+
+-- Index what is already there
+INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+SELECT subject, COALESCE(body_text, ''), sender,
+       (SELECT group_concat(value, ' ') FROM (SELECT value FROM json_each(recipients) UNION ALL SELECT value FROM json_each(cc))),
+       (SELECT group_concat(value, ' ') FROM json_each(attachments)),
+       'message', id, user_id, datetime(received_at), json_array_length(attachments) > 0
+FROM messages;
+
+INSERT INTO search_index (subject, body, sender, recipients, attachments, source, source_id, user_id, occurred_at, has_attachment)
+SELECT subject, COALESCE(error_message, ''), sender_email, recipient_email, '',
+       'log', id, user_id, datetime(COALESCE(sent_at, created_at)), 0
+FROM email_logs;
@@ -0,0 +1,25 @@
+-- Conversations that group received and sent mail
+CREATE TABLE IF NOT EXISTS threads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    subject TEXT NOT NULL, -- normalized: reply/forward prefixes and list tags removed, lowercase
+    last_activity DATETIME NOT NULL, -- UTC, 'YYYY-MM-DD HH:MM:SS'
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_threads_user_subject ON threads(user_id, subject, last_activity);
+
+-- Every Message-ID known to belong to a thread, including ancestors only seen in
+-- References, so a later message that refers to any of them lands in the same thread
+CREATE TABLE IF NOT EXISTS thread_message_ids (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    message_id TEXT NOT NULL,
+    thread_id INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_thread_message_ids_thread ON thread_message_ids(thread_id);
+
+ALTER TABLE outbox ADD COLUMN message_id TEXT;
+ALTER TABLE outbox ADD COLUMN thread_id INTEGER REFERENCES threads(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS idx_outbox_thread_id ON outbox(thread_id);
@@ -0,0 +1,6 @@
+-- Monitors check a list of folders, each a mailbox name or a LIST pattern such as
+-- "Projects/*"; rules only run on mail from the folders they are scoped to.
+ALTER TABLE inbox_monitors ADD COLUMN folders TEXT NOT NULL DEFAULT '["INBOX"]';
+
+-- Existing rules were written for INBOX, the only folder that used to be checked
+ALTER TABLE automation_rules ADD COLUMN folders TEXT NOT NULL DEFAULT '["INBOX"]';
@@ -0,0 +1,14 @@
+-- Accounts either log in with a password or with an OAuth2 access token (XOAUTH2/OAUTHBEARER)
+ALTER TABLE email_accounts ADD COLUMN auth_type TEXT NOT NULL DEFAULT 'password' CHECK (auth_type IN ('password', 'oauth2'));
+ALTER TABLE email_accounts ADD COLUMN oauth_provider TEXT;
+
+-- Tokens are stored encrypted; the access token is cached until shortly before it expires
+CREATE TABLE IF NOT EXISTS oauth_tokens (
+    email_account_id INTEGER PRIMARY KEY REFERENCES email_accounts(id) ON DELETE CASCADE,
+    provider TEXT NOT NULL,
+    refresh_token_encrypted TEXT NOT NULL,
+    access_token_encrypted TEXT,
+    expires_at DATETIME,
+    scope TEXT,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,12 @@
+-- How each server connection is secured, plus trust settings for self-hosted servers
+ALTER TABLE email_accounts ADD COLUMN smtp_security TEXT NOT NULL DEFAULT 'implicit_tls'
+    CHECK (smtp_security IN ('implicit_tls', 'start_tls_required', 'start_tls_opportunistic', 'plaintext'));
+ALTER TABLE email_accounts ADD COLUMN imap_security TEXT NOT NULL DEFAULT 'implicit_tls'
+    CHECK (imap_security IN ('implicit_tls', 'start_tls_required', 'start_tls_opportunistic', 'plaintext'));
+ALTER TABLE email_accounts ADD COLUMN tls_ca_certificate TEXT;
+ALTER TABLE email_accounts ADD COLUMN tls_accept_invalid_certs BOOLEAN NOT NULL DEFAULT 0;
+
+-- SMTP always used implicit TLS, which can't have worked on the submission port; those
+-- accounts were meant for STARTTLS. IMAP on 143 likewise.
+UPDATE email_accounts SET smtp_security = 'start_tls_required' WHERE COALESCE(smtp_port, 587) <> 465;
+UPDATE email_accounts SET imap_security = 'start_tls_required' WHERE imap_port = 143;
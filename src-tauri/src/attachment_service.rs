@@ -30,7 +30,7 @@ impl AttachmentService {
     pub fn save_attachment(
         &self,
         user_id: i32,
-        email_log_id: i32,
+        message_id: i32,
         filename: &str,
         content: &[u8],
         mime_type: Option<String>,
@@ -54,7 +54,8 @@ impl AttachmentService {
         // Save attachment metadata to database
         let attachment_data = CreateEmailAttachment {
             user_id,
-            email_log_id,
+            email_log_id: None,
+            message_id: Some(message_id),
             filename: unique_filename.clone(),
             original_filename: filename.to_string(),
             file_path: file_path.to_string_lossy().to_string(),
@@ -91,37 +92,38 @@ impl AttachmentService {
     }
     
     pub fn create_attachment(&self, attachment_data: CreateEmailAttachment) -> Result<EmailAttachment, AppError> {
-        let conn = self.database.get_connection();
-        
-        let mut stmt = conn.prepare(
-            "INSERT INTO email_attachments (
-                user_id, email_log_id, filename, original_filename, file_path,
-                file_size, mime_type, sender_email, received_at, category
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
-        )?;
-        
-        let attachment_id = stmt.insert((
-            attachment_data.user_id,
-            attachment_data.email_log_id,
-            &attachment_data.filename,
-            &attachment_data.original_filename,
-            &attachment_data.file_path,
-            attachment_data.file_size,
-            &attachment_data.mime_type,
-            &attachment_data.sender_email,
-            attachment_data.received_at,
-            &attachment_data.category,
-        ))?;
+        let attachment_id = self.database.transaction(|tx| {
+            let mut stmt = tx.prepare(
+                "INSERT INTO email_attachments (
+                    user_id, email_log_id, filename, original_filename, file_path,
+                    file_size, mime_type, sender_email, received_at, category, message_id
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            )?;
+            
+            Ok(stmt.insert((
+                attachment_data.user_id,
+                attachment_data.email_log_id,
+                &attachment_data.filename,
+                &attachment_data.original_filename,
+                &attachment_data.file_path,
+                attachment_data.file_size,
+                &attachment_data.mime_type,
+                &attachment_data.sender_email,
+                attachment_data.received_at,
+                &attachment_data.category,
+                attachment_data.message_id,
+            ))?)
+        })?;
         
         self.get_attachment(attachment_id as i32)
     }
     
     pub fn get_attachment(&self, attachment_id: i32) -> Result<EmailAttachment, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, user_id, email_log_id, filename, original_filename, file_path,
-                    file_size, mime_type, sender_email, received_at, category, created_at
+                    file_size, mime_type, sender_email, received_at, category, created_at, message_id
              FROM email_attachments WHERE id = ?1"
         )?;
         
@@ -139,6 +141,7 @@ impl AttachmentService {
                 received_at: row.get(9)?,
                 category: row.get(10)?,
                 created_at: row.get(11)?,
+                message_id: row.get(12)?,
             })
         })?;
         
@@ -146,19 +149,19 @@ impl AttachmentService {
     }
     
     pub fn get_user_attachments(&self, user_id: i32, limit: Option<i32>) -> Result<Vec<EmailAttachment>, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let query = if let Some(limit) = limit {
             format!(
                 "SELECT id, user_id, email_log_id, filename, original_filename, file_path,
-                        file_size, mime_type, sender_email, received_at, category, created_at
+                        file_size, mime_type, sender_email, received_at, category, created_at, message_id
                  FROM email_attachments WHERE user_id = ?1
                  ORDER BY created_at DESC LIMIT {}",
                 limit
             )
         } else {
             "SELECT id, user_id, email_log_id, filename, original_filename, file_path,
-                    file_size, mime_type, sender_email, received_at, category, created_at
+                    file_size, mime_type, sender_email, received_at, category, created_at, message_id
              FROM email_attachments WHERE user_id = ?1
              ORDER BY created_at DESC".to_string()
         };
@@ -178,6 +181,7 @@ impl AttachmentService {
                 received_at: row.get(9)?,
                 category: row.get(10)?,
                 created_at: row.get(11)?,
+                message_id: row.get(12)?,
             })
         })?;
         
@@ -190,7 +194,7 @@ impl AttachmentService {
     }
     
     pub fn get_attachment_categories(&self, user_id: i32) -> Result<Vec<AttachmentCategory>, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT category, COUNT(*) as count, COALESCE(SUM(file_size), 0) as total_size
@@ -229,34 +233,39 @@ impl AttachmentService {
         }
         
         // Delete from database
-        let conn = self.database.get_connection();
-        conn.execute(
-            "DELETE FROM email_attachments WHERE id = ?1 AND user_id = ?2",
-            [attachment_id, user_id],
-        )?;
+        self.database.transaction(|tx| {
+            tx.execute(
+                "DELETE FROM email_attachments WHERE id = ?1 AND user_id = ?2",
+                [attachment_id, user_id],
+            )?;
+            Ok(())
+        })?;
         
         info!("Deleted attachment {} for user {}", attachment_id, user_id);
         Ok(())
     }
     
     pub fn cleanup_orphaned_attachments(&self) -> Result<(), AppError> {
-        let conn = self.database.get_connection();
-        
-        // Find attachments that reference non-existent email logs
-        let mut stmt = conn.prepare(
-            "SELECT a.id, a.file_path FROM email_attachments a
-             LEFT JOIN email_logs e ON a.email_log_id = e.id
-             WHERE e.id IS NULL"
-        )?;
-        
-        let orphaned_iter = stmt.query_map([], |row| {
-            Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
-        })?;
-        
-        let mut orphaned_attachments = Vec::new();
-        for result in orphaned_iter {
-            orphaned_attachments.push(result?);
-        }
+        // Find attachments whose email log and stored message are both gone
+        let orphaned_attachments = {
+            let conn = self.database.get_connection()?;
+            let mut stmt = conn.prepare(
+                "SELECT a.id, a.file_path FROM email_attachments a
+                 LEFT JOIN email_logs e ON a.email_log_id = e.id
+                 LEFT JOIN messages m ON a.message_id = m.id
+                 WHERE e.id IS NULL AND m.id IS NULL"
+            )?;
+            
+            let orphaned_iter = stmt.query_map([], |row| {
+                Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
+            })?;
+            
+            let mut orphaned_attachments = Vec::new();
+            for result in orphaned_iter {
+                orphaned_attachments.push(result?);
+            }
+            orphaned_attachments
+        };
         
         // Delete orphaned attachments
         for (attachment_id, file_path) in orphaned_attachments {
@@ -266,7 +275,10 @@ impl AttachmentService {
             }
             
             // Delete from database
-            conn.execute("DELETE FROM email_attachments WHERE id = ?1", [attachment_id])?;
+            self.database.transaction(|tx| {
+                tx.execute("DELETE FROM email_attachments WHERE id = ?1", [attachment_id])?;
+                Ok(())
+            })?;
             info!("Cleaned up orphaned attachment {}", attachment_id);
         }
         
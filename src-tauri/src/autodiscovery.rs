@@ -0,0 +1,523 @@
+use std::cmp::Reverse;
+use std::time::Duration;
+use log::warn;
+use serde::Deserialize;
+use crate::models::*;
+
+const LOOKUP_TIMEOUT_SECS: u64 = 10;
+
+struct PresetEntry {
+    id: &'static str,
+    name: &'static str,
+    domains: &'static [&'static str],
+    imap: (&'static str, i32),
+    smtp: (&'static str, i32),
+    oauth_provider: Option<&'static str>,
+    requires_app_password: bool,
+}
+
+const PRESETS: &[PresetEntry] = &[
+    PresetEntry {
+        id: "gmail",
+        name: "Gmail",
+        domains: &["gmail.com", "googlemail.com"],
+        imap: ("imap.gmail.com", 993),
+        smtp: ("smtp.gmail.com", 465),
+        oauth_provider: Some("google"),
+        requires_app_password: true,
+    },
+    PresetEntry {
+        id: "outlook",
+        name: "Outlook",
+        domains: &["outlook.com", "hotmail.com", "live.com", "msn.com"],
+        imap: ("outlook.office365.com", 993),
+        smtp: ("smtp.office365.com", 587),
+        oauth_provider: Some("microsoft"),
+        requires_app_password: true,
+    },
+    PresetEntry {
+        id: "yahoo",
+        name: "Yahoo Mail",
+        domains: &["yahoo.com", "ymail.com", "rocketmail.com"],
+        imap: ("imap.mail.yahoo.com", 993),
+        smtp: ("smtp.mail.yahoo.com", 465),
+        oauth_provider: None,
+        requires_app_password: true,
+    },
+    PresetEntry {
+        id: "fastmail",
+        name: "Fastmail",
+        domains: &["fastmail.com", "fastmail.fm"],
+        imap: ("imap.fastmail.com", 993),
+        smtp: ("smtp.fastmail.com", 465),
+        oauth_provider: None,
+        requires_app_password: true,
+    },
+    PresetEntry {
+        id: "icloud",
+        name: "iCloud Mail",
+        domains: &["icloud.com", "me.com", "mac.com"],
+        imap: ("imap.mail.me.com", 993),
+        smtp: ("smtp.mail.me.com", 587),
+        oauth_provider: None,
+        requires_app_password: true,
+    },
+    PresetEntry {
+        id: "zoho",
+        name: "Zoho Mail",
+        domains: &["zoho.com", "zohomail.com"],
+        imap: ("imap.zoho.com", 993),
+        smtp: ("smtp.zoho.com", 465),
+        oauth_provider: None,
+        requires_app_password: false,
+    },
+];
+
+impl PresetEntry {
+    fn to_preset(&self) -> ProviderPreset {
+        ProviderPreset {
+            id: self.id.to_string(),
+            name: self.name.to_string(),
+            domains: self.domains.iter().map(|domain| domain.to_string()).collect(),
+            imap_server: self.imap.0.to_string(),
+            imap_port: self.imap.1,
+            smtp_server: self.smtp.0.to_string(),
+            smtp_port: self.smtp.1,
+            oauth_provider: self.oauth_provider.map(str::to_string),
+            requires_app_password: self.requires_app_password,
+        }
+    }
+}
+
+/// Where autodiscovery looks. URL templates take `{domain}`; tests point them at a local server.
+#[derive(Debug, Clone)]
+pub struct DiscoveryEndpoints {
+    /// The domain's own autoconfig locations, tried in order with `?emailaddress=` appended
+    pub autoconfig_urls: Vec<String>,
+    /// Mozilla's ISP database, which serves the same XML for domains that publish none
+    pub ispdb_url: String,
+    /// A DNS-over-HTTPS resolver with the JSON API, used for the RFC 6186 SRV lookups
+    pub dns_json_url: String,
+}
+
+impl Default for DiscoveryEndpoints {
+    fn default() -> Self {
+        Self {
+            autoconfig_urls: vec![
+                "https://autoconfig.{domain}/mail/config-v1.1.xml".to_string(),
+                "https://{domain}/.well-known/autoconfig/mail/config-v1.1.xml".to_string(),
+            ],
+            ispdb_url: "https://autoconfig.thunderbird.net/v1.1/{domain}".to_string(),
+            dns_json_url: "https://dns.google/resolve".to_string(),
+        }
+    }
+}
+
+/// A server entry from an autoconfig document.
+#[derive(Debug, Clone)]
+struct ConfigServer {
+    hostname: String,
+    port: i32,
+    security: ConnectionSecurity,
+    username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsJsonResponse {
+    #[serde(rename = "Status")]
+    status: i32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsJsonAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsJsonAnswer {
+    #[serde(rename = "type")]
+    record_type: i32,
+    data: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// Suggests IMAP and SMTP settings for an email address.
+///
+/// Known providers come from the preset catalog. Anything else goes through the steps
+/// Thunderbird uses: the domain's autoconfig XML (`autoconfig.<domain>`, then
+/// `/.well-known/autoconfig`), the ISPDB, and finally the SRV records of RFC 6186/8314.
+/// Suggestions are only ever pre-filled into the account form, so a domain can't silently
+/// send the user's password to a server they haven't seen.
+pub struct AutodiscoveryService {
+    endpoints: DiscoveryEndpoints,
+    http_client: reqwest::Client,
+}
+
+impl AutodiscoveryService {
+    pub fn new(endpoints: DiscoveryEndpoints) -> Self {
+        Self {
+            endpoints,
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(LOOKUP_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn presets(&self) -> Vec<ProviderPreset> {
+        PRESETS.iter().map(PresetEntry::to_preset).collect()
+    }
+
+    pub async fn discover(&self, email_address: &str) -> Result<SuggestedAccountSettings, AppError> {
+        let email_address = email_address.trim();
+        let domain = email_address.rsplit_once('@')
+            .map(|(local, domain)| (local, domain.trim_end_matches('.').to_lowercase()))
+            .filter(|(local, domain)| !local.is_empty() && domain.contains('.') && !domain.contains('/'))
+            .map(|(_, domain)| domain)
+            .ok_or_else(|| AppError::Validation(format!("'{}' is not an email address", email_address)))?;
+
+        if let Some(preset) = PRESETS.iter().find(|preset| preset.domains.contains(&domain.as_str())) {
+            return Ok(SuggestedAccountSettings {
+                source: "preset".to_string(),
+                provider_name: Some(preset.name.to_string()),
+                imap_server: Some(preset.imap.0.to_string()),
+                imap_port: Some(preset.imap.1),
+                smtp_server: Some(preset.smtp.0.to_string()),
+                smtp_port: Some(preset.smtp.1),
+                smtp_security: Some(ConnectionSecurity::for_port(preset.smtp.1)),
+                imap_security: Some(ConnectionSecurity::for_port(preset.imap.1)),
+                username: email_address.to_string(),
+                oauth_provider: preset.oauth_provider.map(str::to_string),
+                requires_app_password: preset.requires_app_password,
+            });
+        }
+
+        for template in &self.endpoints.autoconfig_urls {
+            let url = template.replace("{domain}", &domain);
+            let request = self.http_client.get(&url).query(&[("emailaddress", email_address)]);
+            if let Some(settings) = self.fetch_autoconfig(request, &url, email_address, "autoconfig").await {
+                return Ok(settings);
+            }
+        }
+
+        let url = self.endpoints.ispdb_url.replace("{domain}", &domain);
+        if let Some(settings) = self.fetch_autoconfig(self.http_client.get(&url), &url, email_address, "ispdb").await {
+            return Ok(settings);
+        }
+
+        // RFC 8314's implicit-TLS services first, then RFC 6186's STARTTLS ones
+        let imap = self.first_srv(&[
+            ("_imaps._tcp", ConnectionSecurity::ImplicitTls),
+            ("_imap._tcp", ConnectionSecurity::StartTlsRequired),
+        ], &domain).await;
+        let smtp = self.first_srv(&[
+            ("_submissions._tcp", ConnectionSecurity::ImplicitTls),
+            ("_submission._tcp", ConnectionSecurity::StartTlsRequired),
+        ], &domain).await;
+        if imap.is_some() || smtp.is_some() {
+            return Ok(SuggestedAccountSettings {
+                source: "srv".to_string(),
+                provider_name: None,
+                imap_server: imap.as_ref().map(|(record, _)| record.target.clone()),
+                imap_port: imap.as_ref().map(|(record, _)| record.port as i32),
+                smtp_server: smtp.as_ref().map(|(record, _)| record.target.clone()),
+                smtp_port: smtp.as_ref().map(|(record, _)| record.port as i32),
+                smtp_security: smtp.as_ref().map(|(_, security)| *security),
+                imap_security: imap.as_ref().map(|(_, security)| *security),
+                username: email_address.to_string(),
+                oauth_provider: None,
+                requires_app_password: false,
+            });
+        }
+
+        Err(AppError::NotFound(format!(
+            "No mail settings found for {}; enter the servers manually", domain
+        )))
+    }
+
+    /// Fetches and parses one autoconfig document; a missing or unusable one yields `None`.
+    async fn fetch_autoconfig(
+        &self,
+        request: reqwest::RequestBuilder,
+        url: &str,
+        email_address: &str,
+        source: &str,
+    ) -> Option<SuggestedAccountSettings> {
+        let response = request.send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let body = response.text().await.ok()?;
+
+        match parse_autoconfig(&body, email_address, source) {
+            Ok(settings) => Some(settings),
+            Err(e) => {
+                warn!("Ignoring autoconfig from {}: {}", url, e);
+                None
+            }
+        }
+    }
+
+    /// Resolves the services in order and returns the preferred record of the first one
+    /// published, along with how that service is secured.
+    async fn first_srv(&self, services: &[(&str, ConnectionSecurity)], domain: &str) -> Option<(SrvRecord, ConnectionSecurity)> {
+        for (service, security) in services {
+            let name = format!("{}.{}", service, domain);
+            let records = match self.resolve_srv(&name).await {
+                Ok(records) => records,
+                Err(e) => {
+                    warn!("SRV lookup for {} failed: {}", name, e);
+                    continue;
+                }
+            };
+
+            // A lone "." target means the domain explicitly doesn't offer the service
+            if records.iter().any(|record| record.target.is_empty()) {
+                continue;
+            }
+            if let Some(record) = records.into_iter().min_by_key(|record| (record.priority, Reverse(record.weight))) {
+                return Some((record, *security));
+            }
+        }
+        None
+    }
+
+    async fn resolve_srv(&self, name: &str) -> Result<Vec<SrvRecord>, AppError> {
+        let response: DnsJsonResponse = self.http_client.get(&self.endpoints.dns_json_url)
+            .query(&[("name", name), ("type", "SRV")])
+            .header("Accept", "application/dns-json")
+            .send()
+            .await
+            .map_err(|e| AppError::Internal(format!("DNS request failed: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Invalid DNS response: {}", e)))?;
+
+        // NXDOMAIN and friends just mean there is nothing published
+        if response.status != 0 {
+            return Ok(Vec::new());
+        }
+
+        Ok(response.answer.iter()
+            .filter(|answer| answer.record_type == 33)
+            .filter_map(|answer| parse_srv_data(&answer.data))
+            .collect())
+    }
+}
+
+/// Parses SRV record data, "priority weight port target."; the target comes back without the root dot.
+fn parse_srv_data(data: &str) -> Option<SrvRecord> {
+    let mut fields = data.split_whitespace();
+    let record = SrvRecord {
+        priority: fields.next()?.parse().ok()?,
+        weight: fields.next()?.parse().ok()?,
+        port: fields.next()?.parse().ok()?,
+        target: fields.next()?.trim_end_matches('.').to_lowercase(),
+    };
+    fields.next().is_none().then_some(record)
+}
+
+/// Reads an autoconfig (config-v1.1) document, picking the most secure IMAP and SMTP servers it lists.
+fn parse_autoconfig(xml: &str, email_address: &str, source: &str) -> Result<SuggestedAccountSettings, AppError> {
+    let document = roxmltree::Document::parse(xml)
+        .map_err(|e| AppError::Validation(format!("Invalid autoconfig XML: {}", e)))?;
+    let provider = document.descendants()
+        .find(|node| node.has_tag_name("emailProvider"))
+        .ok_or_else(|| AppError::Validation("Autoconfig has no emailProvider".to_string()))?;
+
+    let servers = |tag: &str, server_type: &str| -> Vec<ConfigServer> {
+        provider.children()
+            .filter(|node| node.has_tag_name(tag) && node.attribute("type") == Some(server_type))
+            .filter_map(|node| Some(ConfigServer {
+                hostname: child_text(node, "hostname")?,
+                port: child_text(node, "port")?.parse().ok()?,
+                security: match child_text(node, "socketType").unwrap_or_default().to_uppercase().as_str() {
+                    "SSL" => ConnectionSecurity::ImplicitTls,
+                    "STARTTLS" => ConnectionSecurity::StartTlsRequired,
+                    _ => ConnectionSecurity::Plaintext,
+                },
+                username: child_text(node, "username"),
+            }))
+            .collect()
+    };
+
+    // Documents list servers in the provider's order of preference; prefer TLS over that
+    let best = |candidates: Vec<ConfigServer>| {
+        candidates.into_iter().min_by_key(|server| match server.security {
+            ConnectionSecurity::ImplicitTls => 0,
+            ConnectionSecurity::StartTlsRequired | ConnectionSecurity::StartTlsOpportunistic => 1,
+            ConnectionSecurity::Plaintext => 2,
+        })
+    };
+
+    let imap = best(servers("incomingServer", "imap"));
+    let smtp = best(servers("outgoingServer", "smtp"));
+    if imap.is_none() && smtp.is_none() {
+        return Err(AppError::Validation("Autoconfig lists no IMAP or SMTP server".to_string()));
+    }
+
+    let username = imap.as_ref().and_then(|server| server.username.clone())
+        .or_else(|| smtp.as_ref().and_then(|server| server.username.clone()))
+        .map(|template| expand_placeholders(&template, email_address))
+        .unwrap_or_else(|| email_address.to_string());
+
+    Ok(SuggestedAccountSettings {
+        source: source.to_string(),
+        provider_name: child_text(provider, "displayName"),
+        imap_server: imap.as_ref().map(|server| expand_placeholders(&server.hostname, email_address)),
+        imap_port: imap.as_ref().map(|server| server.port),
+        smtp_server: smtp.as_ref().map(|server| expand_placeholders(&server.hostname, email_address)),
+        smtp_port: smtp.as_ref().map(|server| server.port),
+        smtp_security: smtp.as_ref().map(|server| server.security),
+        imap_security: imap.as_ref().map(|server| server.security),
+        username,
+        oauth_provider: None,
+        requires_app_password: false,
+    })
+}
+
+fn child_text(node: roxmltree::Node, name: &str) -> Option<String> {
+    node.children()
+        .find(|child| child.has_tag_name(name))
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// Fills in autoconfig's %EMAILADDRESS%, %EMAILLOCALPART% and %EMAILDOMAIN%.
+fn expand_placeholders(template: &str, email_address: &str) -> String {
+    let (local, domain) = email_address.rsplit_once('@').unwrap_or((email_address, ""));
+    template
+        .replace("%EMAILADDRESS%", email_address)
+        .replace("%EMAILLOCALPART%", local)
+        .replace("%EMAILDOMAIN%", domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const AUTOCONFIG: &str = r#"<?xml version="1.0"?>
+<clientConfig version="1.1">
+  <emailProvider id="selfhosted.test">
+    <domain>selfhosted.test</domain>
+    <displayName>Self Hosted</displayName>
+    <incomingServer type="pop3">
+      <hostname>pop.selfhosted.test</hostname>
+      <port>995</port>
+      <socketType>SSL</socketType>
+    </incomingServer>
+    <incomingServer type="imap">
+      <hostname>mail.%EMAILDOMAIN%</hostname>
+      <port>143</port>
+      <socketType>STARTTLS</socketType>
+      <username>%EMAILLOCALPART%</username>
+    </incomingServer>
+    <incomingServer type="imap">
+      <hostname>imap.selfhosted.test</hostname>
+      <port>993</port>
+      <socketType>SSL</socketType>
+      <username>%EMAILLOCALPART%</username>
+    </incomingServer>
+    <outgoingServer type="smtp">
+      <hostname>smtp.selfhosted.test</hostname>
+      <port>587</port>
+      <socketType>STARTTLS</socketType>
+      <username>%EMAILADDRESS%</username>
+    </outgoingServer>
+  </emailProvider>
+</clientConfig>"#;
+
+    /// Serves the autoconfig only from selfhosted.test's well-known location, ISPDB
+    /// entries for isp.test, and SRV answers for srv.test; everything else is a 404.
+    async fn fixture_server(listener: TcpListener) {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else { return };
+
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = stream.read(&mut chunk).await.unwrap();
+                assert!(read > 0, "connection closed mid-request");
+                request.extend_from_slice(&chunk[..read]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let target = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+            let body = if target.starts_with("/well-known/selfhosted.test?emailaddress=") {
+                Some(AUTOCONFIG.to_string())
+            } else if target == "/ispdb/isp.test" {
+                Some(AUTOCONFIG.replace("Self Hosted", "Some ISP"))
+            } else if target.starts_with("/dns?") {
+                let answer = match target.split_once("name=").map(|(_, rest)| rest.split('&').next().unwrap()) {
+                    Some("_imaps._tcp.srv.test") => r#"{"Status":0,"Answer":[
+                        {"type":33,"data":"10 0 993 backup.srv.test."},
+                        {"type":33,"data":"0 5 993 imap.srv.test."}]}"#,
+                    Some("_submissions._tcp.srv.test") => r#"{"Status":0,"Answer":[{"type":33,"data":"0 0 0 ."}]}"#,
+                    Some("_submission._tcp.srv.test") => r#"{"Status":0,"Answer":[{"type":33,"data":"0 1 587 smtp.srv.test."}]}"#,
+                    _ => r#"{"Status":3}"#,
+                };
+                Some(answer.to_string())
+            } else {
+                None
+            };
+
+            let response = match body {
+                Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_falls_back_from_presets_to_autoconfig_ispdb_and_srv() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(fixture_server(listener));
+
+        let service = AutodiscoveryService::new(DiscoveryEndpoints {
+            autoconfig_urls: vec![
+                format!("{}/autoconfig/{{domain}}", base),
+                format!("{}/well-known/{{domain}}", base),
+            ],
+            ispdb_url: format!("{}/ispdb/{{domain}}", base),
+            dns_json_url: format!("{}/dns", base),
+        });
+
+        let gmail = service.discover("Jane@GoogleMail.com").await.unwrap();
+        assert_eq!(gmail.source, "preset");
+        assert_eq!(gmail.imap_server.as_deref(), Some("imap.gmail.com"));
+        assert_eq!(gmail.oauth_provider.as_deref(), Some("google"));
+
+        // The SSL IMAP server wins over the STARTTLS one, and POP3 is never offered
+        let own = service.discover("jane@selfhosted.test").await.unwrap();
+        assert_eq!(own.source, "autoconfig");
+        assert_eq!(own.provider_name.as_deref(), Some("Self Hosted"));
+        assert_eq!((own.imap_server.as_deref(), own.imap_port), (Some("imap.selfhosted.test"), Some(993)));
+        assert_eq!((own.smtp_server.as_deref(), own.smtp_port), (Some("smtp.selfhosted.test"), Some(587)));
+        assert_eq!((own.imap_security, own.smtp_security), (Some(ConnectionSecurity::ImplicitTls), Some(ConnectionSecurity::StartTlsRequired)));
+        assert_eq!(own.username, "jane");
+
+        let isp = service.discover("jane@isp.test").await.unwrap();
+        assert_eq!((isp.source.as_str(), isp.provider_name.as_deref()), ("ispdb", Some("Some ISP")));
+
+        // Lowest priority wins, and a "." target rules out implicit-TLS submission
+        let srv = service.discover("jane@srv.test").await.unwrap();
+        assert_eq!(srv.source, "srv");
+        assert_eq!((srv.imap_server.as_deref(), srv.imap_port), (Some("imap.srv.test"), Some(993)));
+        assert_eq!((srv.smtp_server.as_deref(), srv.smtp_port), (Some("smtp.srv.test"), Some(587)));
+        assert_eq!(srv.smtp_security, Some(ConnectionSecurity::StartTlsRequired));
+        assert_eq!(srv.username, "jane@srv.test");
+
+        assert!(matches!(service.discover("jane@nothing.test").await, Err(AppError::NotFound(_))));
+        assert!(matches!(service.discover("not-an-address").await, Err(AppError::Validation(_))));
+    }
+}
@@ -0,0 +1,966 @@
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info, error, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::models::*;
+use crate::database::Database;
+use crate::email_service::EmailService;
+use crate::attachment_service::AttachmentService;
+use crate::contact_service::ContactService;
+use crate::conditions::{folder_matches, Condition, MessageContext};
+use crate::mime;
+use crate::connection::ImapSession;
+use crate::inbox_service::DEFAULT_AUTO_REPLY_COOLDOWN;
+use crate::outbox_service::OutboxService;
+
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// What running one rule's actions did to a message.
+struct ActionsOutcome {
+    attempted: usize,
+    failed: usize,
+    /// A move or delete succeeded, so the message's UID is gone from its folder
+    removed: bool,
+}
+
+/// Evaluates a user's automation rules against incoming mail and runs the actions of the
+/// rules that match.
+///
+/// This is the only place rules are matched, so the inbox monitor and manual checks behave
+/// the same. Every run returns a [`RuleTrace`] per active rule showing which predicates held.
+pub struct AutomationEngine {
+    database: Arc<Database>,
+    email_service: Arc<Mutex<EmailService>>,
+    attachment_service: Arc<AttachmentService>,
+    contact_service: Arc<ContactService>,
+    http_client: reqwest::Client,
+}
+
+impl AutomationEngine {
+    pub fn new(
+        database: Arc<Database>,
+        email_service: Arc<Mutex<EmailService>>,
+        attachment_service: Arc<AttachmentService>,
+        contact_service: Arc<ContactService>,
+    ) -> Self {
+        Self {
+            database,
+            email_service,
+            attachment_service,
+            contact_service,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// Runs the user's active rules against `email` in priority order, executing the actions
+    /// of each rule that matches, and returns the trace of every rule evaluated. A matching
+    /// rule with `stop_processing` set ends the run, as does one that moved or deleted the
+    /// message, since its UID no longer exists in `folder`.
+    ///
+    /// `folder` is where the message was fetched from and must be selected in `session`,
+    /// since actions refer to the message by its UID there.
+    pub async fn run(
+        &self,
+        user_id: i32,
+        account_id: i32,
+        folder: &str,
+        session: &mut ImapSession,
+        email: &InboxEmail,
+    ) -> Result<Vec<RuleTrace>, AppError> {
+        let rules = self.database.get_automation_rules(user_id)?;
+        let message = MessageContext::from_inbox_email(email, Some(account_id), Some(folder));
+        let mut traces = Vec::new();
+
+        for rule in rules.iter().filter(|rule| rule.is_active) {
+            let trace = Self::evaluate_rule(rule, &message);
+            debug!("Rule '{}' against email {}: {:?}", rule.rule_name, email.id, trace);
+
+            let mut removed = false;
+            if trace.matched {
+                info!("Email matches automation rule '{}' for user {}", rule.rule_name, user_id);
+                let run_id = self.start_run(user_id, account_id, email, &trace)?;
+                let outcome = self.execute_actions(rule, run_id, account_id, session, email).await;
+                // The actions have run either way, so a bookkeeping failure must not fail the message
+                if let Err(e) = self.finish_run(run_id, outcome.attempted, outcome.failed) {
+                    error!("Failed to finish automation run {}: {}", run_id, e);
+                }
+                removed = outcome.removed;
+            }
+
+            let stop = trace.matched && rule.stop_processing;
+            traces.push(trace);
+
+            if stop {
+                info!("Rule '{}' stops further rule processing for email {}", rule.rule_name, email.id);
+                break;
+            }
+            if removed {
+                info!("Rule '{}' moved or deleted email {}, skipping the remaining rules", rule.rule_name, email.id);
+                break;
+            }
+        }
+
+        Ok(traces)
+    }
+
+    /// Decides whether `rule` matches without side effects. A rule needs keywords or
+    /// conditions; when it has both, any keyword and the whole condition tree must match.
+    /// A message from a known folder must also be in one of the rule's folders.
+    pub fn evaluate_rule(rule: &AutomationRule, message: &MessageContext) -> RuleTrace {
+        let mut trace = RuleTrace {
+            rule_id: rule.id,
+            rule_name: rule.rule_name.clone(),
+            matched: false,
+            skipped: None,
+            predicates: Vec::new(),
+        };
+
+        let conditions = match Condition::parse(&rule.conditions) {
+            Ok(conditions) => conditions,
+            Err(e) => {
+                warn!("Skipping automation rule '{}' with invalid conditions: {}", rule.rule_name, e);
+                trace.skipped = Some(e.to_string());
+                return trace;
+            }
+        };
+
+        if rule.keywords.is_empty() && conditions.is_none() {
+            trace.skipped = Some("Rule has neither keywords nor conditions".to_string());
+            return trace;
+        }
+
+        let mut matched = true;
+
+        if let Some(folder) = message.folder {
+            let in_scope = rule.folders.iter().any(|pattern| folder_matches(pattern, folder));
+            trace.predicates.push(PredicateTrace {
+                path: "folders".to_string(),
+                predicate: serde_json::json!(rule.folders),
+                matched: in_scope,
+            });
+            matched &= in_scope;
+        }
+
+        if !rule.keywords.is_empty() {
+            let subject = message.subject.to_lowercase();
+            let body = message.body.to_lowercase();
+            let keywords_match = rule.keywords.iter().any(|keyword| {
+                let keyword = keyword.to_lowercase();
+                subject.contains(&keyword) || body.contains(&keyword)
+            });
+
+            trace.predicates.push(PredicateTrace {
+                path: "keywords".to_string(),
+                predicate: serde_json::json!(rule.keywords),
+                matched: keywords_match,
+            });
+            matched &= keywords_match;
+        }
+
+        if let Some(condition) = conditions {
+            let (conditions_match, predicates) = condition.evaluate_traced(message);
+            trace.predicates.extend(predicates);
+            matched &= conditions_match;
+        }
+
+        trace.matched = matched;
+        trace
+    }
+
+    /// Shows what `rule` would do to each message without touching the mailbox, the outbox
+    /// or any log. `account_id` and `folder` are where the messages came from, if known.
+    pub fn simulate(
+        rule: &AutomationRule,
+        emails: &[InboxEmail],
+        account_id: Option<i32>,
+        folder: Option<&str>,
+    ) -> Result<Vec<RuleSimulation>, AppError> {
+        let actions: Vec<AutomationAction> = serde_json::from_value(rule.actions.clone())
+            .map_err(|e| AppError::Validation(format!("Invalid automation actions: {}", e)))?;
+
+        Ok(emails.iter()
+            .map(|email| {
+                let trace = Self::evaluate_rule(rule, &MessageContext::from_inbox_email(email, account_id, folder));
+
+                let actions = if trace.matched {
+                    actions.iter()
+                        .map(|action| SimulatedAction {
+                            action: action.clone(),
+                            note: Self::simulation_note(action, email),
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+
+                RuleSimulation {
+                    message_id: email.id.clone(),
+                    subject: email.subject.clone(),
+                    sender: email.sender.clone(),
+                    trace,
+                    actions,
+                }
+            })
+            .collect())
+    }
+
+    fn simulation_note(action: &AutomationAction, email: &InboxEmail) -> Option<String> {
+        match action {
+            AutomationAction::AutoReply { .. } if email.is_auto_generated => {
+                Some("Would be skipped: message is automated".to_string())
+            }
+            AutomationAction::SaveAttachments if email.attachments.is_empty() => {
+                Some("Message has no attachments".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// The folders a rule or monitor covers, INBOX when none are given.
+    pub fn validate_folders(folders: Option<Vec<String>>) -> Result<Vec<String>, AppError> {
+        let folders: Vec<String> = match folders {
+            Some(folders) => folders.into_iter().map(|folder| folder.trim().to_string()).collect(),
+            None => vec!["INBOX".to_string()],
+        };
+
+        if folders.is_empty() || folders.iter().any(|folder| folder.is_empty()) {
+            return Err(AppError::Validation("folders: list at least one folder, without empty names".to_string()));
+        }
+
+        Ok(folders)
+    }
+
+    /// Parses and checks a rule's actions before the rule is saved, so that a typo or a
+    /// template from another account fails at creation instead of on the next message.
+    pub fn validate_actions(&self, user_id: i32, actions: &serde_json::Value) -> Result<Vec<AutomationAction>, AppError> {
+        let actions: Vec<AutomationAction> = serde_json::from_value(actions.clone())
+            .map_err(|e| AppError::Validation(format!("Invalid automation actions: {}", e)))?;
+
+        if actions.is_empty() {
+            return Err(AppError::Validation("A rule needs at least one action".to_string()));
+        }
+
+        for action in &actions {
+            match action {
+                AutomationAction::AutoReply { template_id } => {
+                    self.database.get_email_template(*template_id, user_id)?
+                        .ok_or_else(|| AppError::Validation(format!("auto_reply: template {} not found", template_id)))?;
+                },
+                AutomationAction::MoveToFolder { folder } => {
+                    if folder.trim().is_empty() || folder.eq_ignore_ascii_case("INBOX") {
+                        return Err(AppError::Validation("move_to_folder: folder must be a mailbox other than INBOX".to_string()));
+                    }
+                },
+                AutomationAction::Forward { to, .. } => {
+                    if to.is_empty() {
+                        return Err(AppError::Validation("forward: at least one recipient is required".to_string()));
+                    }
+
+                    if let Some(invalid) = to.iter().find(|address| address.parse::<lettre::Address>().is_err()) {
+                        return Err(AppError::Validation(format!("forward: invalid address {}", invalid)));
+                    }
+                },
+                AutomationAction::AddLabel { label } => {
+                    // IMAP keywords are atoms and may not start with a backslash (system flags)
+                    let is_atom = !label.is_empty()
+                        && !label.starts_with('\\')
+                        && !label.chars().any(|c| c.is_whitespace() || c.is_control() || "(){%*\"]".contains(c));
+
+                    if !is_atom {
+                        return Err(AppError::Validation(format!("add_label: {:?} is not a valid IMAP keyword", label)));
+                    }
+                },
+                AutomationAction::AddToContactList { contact_list_id } => {
+                    self.contact_service.get_contact_list(user_id, *contact_list_id)
+                        .map_err(|_| AppError::Validation(format!("add_to_contact_list: contact list {} not found", contact_list_id)))?;
+                },
+                AutomationAction::HttpWebhook { url } => {
+                    let parsed = reqwest::Url::parse(url)
+                        .map_err(|e| AppError::Validation(format!("http_webhook: invalid URL: {}", e)))?;
+
+                    if !matches!(parsed.scheme(), "http" | "https") {
+                        return Err(AppError::Validation("http_webhook: URL must use http or https".to_string()));
+                    }
+                },
+                AutomationAction::MarkAsRead
+                | AutomationAction::Flag
+                | AutomationAction::SaveAttachments
+                | AutomationAction::Delete => {},
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Runs a matched rule's actions in order. Once one has moved or deleted the message,
+    /// the actions that would touch its server copy are logged as failed instead of run.
+    async fn execute_actions(
+        &self,
+        rule: &AutomationRule,
+        run_id: i32,
+        account_id: i32,
+        session: &mut ImapSession,
+        email: &InboxEmail,
+    ) -> ActionsOutcome {
+        let mut outcome = ActionsOutcome { attempted: 0, failed: 0, removed: false };
+
+        if let Some(actions_array) = rule.actions.as_array() {
+            for action in actions_array {
+                let action_type = action.get("type").and_then(|t| t.as_str()).unwrap_or("unknown");
+
+                // Rules saved before validation existed may hold actions that no longer parse
+                let result = match serde_json::from_value::<AutomationAction>(action.clone()) {
+                    Ok(action) if outcome.removed && Self::uses_server_copy(&action) => {
+                        Err(AppError::NotFound("The message was already moved or deleted".to_string()))
+                    }
+                    Ok(action) => {
+                        let result = self.execute_action(rule.user_id, rule.id, account_id, session, email, &action).await;
+                        if result.is_ok() && matches!(action, AutomationAction::MoveToFolder { .. } | AutomationAction::Delete) {
+                            outcome.removed = true;
+                        }
+                        result
+                    }
+                    Err(e) => {
+                        warn!("Invalid automation action {}: {}", action, e);
+                        Err(AppError::Validation(format!("Invalid action: {}", e)))
+                    }
+                };
+
+                // Later actions still run when one fails; every outcome lands in the action log
+                outcome.attempted += 1;
+                if let Err(e) = &result {
+                    error!("Automation action '{}' failed for email {}: {}", action_type, email.id, e);
+                    outcome.failed += 1;
+                }
+                if let Err(e) = self.record_action(run_id, action_type, result) {
+                    error!("Failed to log automation action '{}' of run {}: {}", action_type, run_id, e);
+                }
+            }
+        }
+
+        outcome
+    }
+
+    /// Whether the action works on the message in the folder it was fetched from, by UID.
+    fn uses_server_copy(action: &AutomationAction) -> bool {
+        matches!(
+            action,
+            AutomationAction::MarkAsRead
+                | AutomationAction::MoveToFolder { .. }
+                | AutomationAction::Flag
+                | AutomationAction::AddLabel { .. }
+                | AutomationAction::SaveAttachments
+                | AutomationAction::Delete
+        )
+    }
+
+    async fn execute_action(
+        &self,
+        user_id: i32,
+        rule_id: i32,
+        account_id: i32,
+        session: &mut ImapSession,
+        email: &InboxEmail,
+        action: &AutomationAction,
+    ) -> Result<String, AppError> {
+        match action {
+            AutomationAction::AutoReply { template_id } => self.send_auto_reply(user_id, account_id, email, *template_id).await,
+            AutomationAction::MarkAsRead => Self::store_flag(session, &email.id, "\\Seen", "Marked as read"),
+            AutomationAction::MoveToFolder { folder } => Self::move_to_folder(session, &email.id, folder),
+            AutomationAction::Forward { to, note } => self.forward(user_id, account_id, email, to, note.as_deref()),
+            AutomationAction::Flag => Self::store_flag(session, &email.id, "\\Flagged", "Flagged"),
+            AutomationAction::AddLabel { label } => Self::store_flag(session, &email.id, label, &format!("Labelled {}", label)),
+            AutomationAction::SaveAttachments => self.save_attachments(user_id, session, email),
+            AutomationAction::AddToContactList { contact_list_id } => self.add_sender_to_contact_list(user_id, *contact_list_id, email),
+            AutomationAction::Delete => {
+                Self::store_flag(session, &email.id, "\\Deleted", "")?;
+                Self::expunge_message(session, &email.id)?;
+                Ok("Deleted".to_string())
+            },
+            AutomationAction::HttpWebhook { url } => self.call_webhook(url, rule_id, account_id, email).await,
+        }
+    }
+
+    fn store_flag(session: &mut ImapSession, uid: &str, flag: &str, detail: &str) -> Result<String, AppError> {
+        session.uid_store(uid, format!("+FLAGS ({})", flag))
+            .map_err(|e| AppError::Email(format!("Failed to set {} on message: {}", flag, e)))?;
+
+        Ok(detail.to_string())
+    }
+
+    // Plain EXPUNGE also removes anything else already flagged \Deleted in the folder, so UID
+    // EXPUNGE is preferred whenever the server supports UIDPLUS
+    fn expunge_message(session: &mut ImapSession, uid: &str) -> Result<(), AppError> {
+        let supports_uidplus = session.capabilities()
+            .map_err(|e| AppError::Email(format!("Failed to read server capabilities: {}", e)))?
+            .has_str("UIDPLUS");
+
+        let expunged = if supports_uidplus {
+            session.uid_expunge(uid)
+        } else {
+            session.expunge()
+        };
+        expunged.map_err(|e| AppError::Email(format!("Failed to expunge message: {}", e)))?;
+
+        Ok(())
+    }
+
+    fn forward(
+        &self,
+        user_id: i32,
+        account_id: i32,
+        email: &InboxEmail,
+        to: &[String],
+        note: Option<&str>,
+    ) -> Result<String, AppError> {
+        let body = format!(
+            "{}\n\n---------- Forwarded message ----------\nFrom: {}\nDate: {}\nSubject: {}\n\n{}",
+            note.unwrap_or(""),
+            email.sender,
+            email.received_at.to_rfc2822(),
+            email.subject,
+            email.body,
+        );
+
+        let forward = CreateOutboxMessage {
+            user_id,
+            email_account_id: account_id,
+            campaign_id: None,
+            message: EmailMessage {
+                to: to.to_vec(),
+                cc: None,
+                bcc: None,
+                subject: format!("Fwd: {}", email.subject),
+                body: body.trim_start().to_string(),
+                attachments: None,
+                message_id: None,
+                in_reply_to: None,
+                references: email.message_id.clone(),
+                auto_submitted: Some("auto-generated".to_string()),
+            },
+        };
+
+        self.database.transaction(|tx| OutboxService::enqueue_in(tx, &forward))?;
+
+        Ok(format!("Queued forward to {}", to.join(", ")))
+    }
+
+    /// Re-fetches the full message and stores every attachment part through AttachmentService.
+    fn save_attachments(
+        &self,
+        user_id: i32,
+        session: &mut ImapSession,
+        email: &InboxEmail,
+    ) -> Result<String, AppError> {
+        let messages = session.uid_fetch(&email.id, "BODY.PEEK[]")
+            .map_err(|e| AppError::Email(format!("IMAP fetch error: {}", e)))?;
+
+        let raw = messages.iter()
+            .find_map(|message| message.body())
+            .ok_or_else(|| AppError::NotFound("Message no longer in its folder".to_string()))?;
+
+        let message = mime::parse(raw)?;
+        let attachments = message.attached_files().collect::<Vec<_>>();
+
+        if attachments.is_empty() {
+            return Ok("No attachments".to_string());
+        }
+
+        let message_id = email.stored_id
+            .ok_or_else(|| AppError::Validation("Message is not in the local message store".to_string()))?;
+        let sender_email = Self::extract_email_address(&email.sender).ok();
+
+        for attachment in &attachments {
+            self.attachment_service.save_attachment(
+                user_id,
+                message_id,
+                &attachment.filename,
+                &attachment.content,
+                Some(attachment.mime_type.clone()),
+                sender_email.clone(),
+            )?;
+        }
+
+        Ok(format!("Saved {} attachment(s)", attachments.len()))
+    }
+
+    fn add_sender_to_contact_list(&self, user_id: i32, contact_list_id: i32, email: &InboxEmail) -> Result<String, AppError> {
+        let sender_email = Self::extract_email_address(&email.sender)?;
+
+        let already_listed = self.contact_service.get_contacts_by_list(user_id, contact_list_id)?
+            .iter()
+            .any(|contact| contact.email.eq_ignore_ascii_case(&sender_email));
+
+        if already_listed {
+            return Ok(format!("{} is already in the list", sender_email));
+        }
+
+        // "Jane Doe <jane@example.com>" gives first name Jane, last name Doe
+        let display_name = email.sender.split('<').next().unwrap_or("").trim().trim_matches('"');
+        let (first_name, last_name) = match display_name.split_once(' ') {
+            _ if display_name.is_empty() || display_name.contains('@') => (None, None),
+            Some((first, last)) => (Some(first.to_string()), Some(last.trim().to_string())),
+            None => (Some(display_name.to_string()), None),
+        };
+
+        self.contact_service.create_contact(user_id, CreateContact {
+            contact_list_id,
+            email: sender_email.clone(),
+            first_name,
+            last_name,
+            custom_fields: None,
+        })?;
+
+        Ok(format!("Added {} to contact list {}", sender_email, contact_list_id))
+    }
+
+    async fn call_webhook(&self, url: &str, rule_id: i32, account_id: i32, email: &InboxEmail) -> Result<String, AppError> {
+        let payload = serde_json::json!({
+            "rule_id": rule_id,
+            "email_account_id": account_id,
+            "message": email,
+        });
+
+        let response = self.http_client.post(url)
+            .timeout(std::time::Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| AppError::Internal(format!("Webhook request failed: {}", e)))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(AppError::Internal(format!("Webhook returned {}", status)));
+        }
+
+        Ok(format!("Webhook returned {}", status))
+    }
+
+    /// Moves a message out of the selected folder, creating the target folder first if it
+    /// does not exist.
+    ///
+    /// Uses UID MOVE where the server advertises it; otherwise copies the message, flags the
+    /// original as deleted and expunges it (by UID when UIDPLUS is available).
+    fn move_to_folder(session: &mut ImapSession, uid: &str, folder: &str) -> Result<String, AppError> {
+        let folder_exists = !session.list(None, Some(folder))
+            .map_err(|e| AppError::Email(format!("Failed to list folders: {}", e)))?
+            .is_empty();
+
+        if !folder_exists {
+            session.create(folder)
+                .map_err(|e| AppError::Email(format!("Failed to create folder {}: {}", folder, e)))?;
+            info!("Created IMAP folder {}", folder);
+        }
+
+        let supports_move = session.capabilities()
+            .map_err(|e| AppError::Email(format!("Failed to read server capabilities: {}", e)))?
+            .has_str("MOVE");
+
+        if supports_move {
+            session.uid_mv(uid, folder)
+                .map_err(|e| AppError::Email(format!("Failed to move message to {}: {}", folder, e)))?;
+
+            return Ok(format!("Moved to {}", folder));
+        }
+
+        session.uid_copy(uid, folder)
+            .map_err(|e| AppError::Email(format!("Failed to copy message to {}: {}", folder, e)))?;
+        Self::store_flag(session, uid, "\\Deleted", "")?;
+        Self::expunge_message(session, uid)?;
+
+        Ok(format!("Copied to {} and expunged from the source folder", folder))
+    }
+
+    fn start_run(&self, user_id: i32, account_id: i32, email: &InboxEmail, trace: &RuleTrace) -> Result<i32, AppError> {
+        let predicates = serde_json::to_string(&trace.predicates)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize predicates: {}", e)))?;
+
+        self.database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO automation_runs (user_id, rule_id, rule_name, email_account_id, message_uid, message_id, sender, subject, predicates)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    user_id,
+                    trace.rule_id,
+                    &trace.rule_name,
+                    account_id,
+                    &email.id,
+                    &email.message_id,
+                    &email.sender,
+                    &email.subject,
+                    predicates,
+                ],
+            )?;
+            Ok(tx.last_insert_rowid() as i32)
+        })
+    }
+
+    fn finish_run(&self, run_id: i32, attempted: usize, failed: usize) -> Result<(), AppError> {
+        let status = match failed {
+            0 => "success",
+            failed if failed == attempted => "failed",
+            _ => "partial",
+        };
+
+        self.database.transaction(|tx| {
+            tx.execute("UPDATE automation_runs SET status = ?1 WHERE id = ?2", params![status, run_id])?;
+            Ok(())
+        })
+    }
+
+    fn record_action(&self, run_id: i32, action_type: &str, result: Result<String, AppError>) -> Result<(), AppError> {
+        let (status, detail) = match result {
+            Ok(detail) => ("success", detail),
+            Err(e) => ("failed", e.to_string()),
+        };
+
+        self.database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO automation_action_log (user_id, run_id, rule_id, email_account_id, message_uid, action_type, status, detail)
+                 SELECT user_id, id, rule_id, email_account_id, message_uid, ?2, ?3, ?4 FROM automation_runs WHERE id = ?1",
+                params![run_id, action_type, status, detail],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn get_action_log(&self, user_id: i32, limit: Option<i32>) -> Result<Vec<AutomationActionLog>, AppError> {
+        let conn = self.database.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, run_id, rule_id, email_account_id, message_uid, action_type, status, detail, created_at
+             FROM automation_action_log WHERE user_id = ?1 ORDER BY created_at DESC, id DESC LIMIT ?2"
+        )?;
+
+        let entry_iter = stmt.query_map(params![user_id, limit.unwrap_or(100)], Self::action_log_from_row)?;
+
+        let mut entries = Vec::new();
+        for entry in entry_iter {
+            entries.push(entry?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns matched-rule runs, newest first, each with the actions it attempted.
+    pub fn get_runs(&self, user_id: i32, filter: &AutomationRunFilter) -> Result<Vec<AutomationRun>, AppError> {
+        let conn = self.database.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, rule_id, rule_name, email_account_id, message_uid, message_id, sender, subject, predicates, status, created_at
+             FROM automation_runs
+             WHERE user_id = ?1
+               AND (?2 IS NULL OR rule_id = ?2)
+               AND (?3 IS NULL OR datetime(created_at) >= datetime(?3))
+               AND (?4 IS NULL OR datetime(created_at) < datetime(?4))
+             ORDER BY created_at DESC, id DESC LIMIT ?5"
+        )?;
+
+        let run_iter = stmt.query_map(
+            params![user_id, filter.rule_id, filter.from, filter.to, filter.limit.unwrap_or(100)],
+            |row| {
+                Ok(AutomationRun {
+                    id: row.get(0)?,
+                    rule_id: row.get(1)?,
+                    rule_name: row.get(2)?,
+                    email_account_id: row.get(3)?,
+                    message_uid: row.get(4)?,
+                    message_id: row.get(5)?,
+                    sender: row.get(6)?,
+                    subject: row.get(7)?,
+                    predicates: serde_json::from_str(&row.get::<_, String>(8)?)
+                        .map_err(|_| rusqlite::Error::InvalidColumnType(8, "predicates".to_string(), rusqlite::types::Type::Text))?,
+                    status: row.get(9)?,
+                    actions: Vec::new(),
+                    created_at: row.get(10)?,
+                })
+            },
+        )?;
+
+        let mut runs = Vec::new();
+        for run in run_iter {
+            runs.push(run?);
+        }
+
+        let mut actions_stmt = conn.prepare(
+            "SELECT id, run_id, rule_id, email_account_id, message_uid, action_type, status, detail, created_at
+             FROM automation_action_log WHERE run_id = ?1 ORDER BY id"
+        )?;
+
+        for run in &mut runs {
+            run.actions = actions_stmt.query_map([run.id], Self::action_log_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+
+        Ok(runs)
+    }
+
+    fn action_log_from_row(row: &rusqlite::Row) -> rusqlite::Result<AutomationActionLog> {
+        Ok(AutomationActionLog {
+            id: row.get(0)?,
+            run_id: row.get(1)?,
+            rule_id: row.get(2)?,
+            email_account_id: row.get(3)?,
+            message_uid: row.get(4)?,
+            action_type: row.get(5)?,
+            status: row.get(6)?,
+            detail: row.get(7)?,
+            created_at: row.get(8)?,
+        })
+    }
+
+    /// Queues a reply to `original_email` from the account that received it.
+    ///
+    /// The reply goes through the outbox, so it shares the account's rate limits, is retried
+    /// on transient failures and is logged to `email_logs` once delivered.
+    pub async fn send_auto_reply(
+        &self,
+        user_id: i32,
+        account_id: i32,
+        original_email: &InboxEmail,
+        template_id: i32,
+    ) -> Result<String, AppError> {
+        if original_email.is_auto_generated {
+            info!("Not auto-replying to automated email {} from {}", original_email.id, original_email.sender);
+            return Ok("Skipped: message is automated".to_string());
+        }
+
+        let template = self.database.get_email_template(template_id, user_id)?
+            .ok_or_else(|| AppError::NotFound("Auto-reply template not found".to_string()))?;
+
+        // Extract sender email from original email
+        let sender_email = Self::extract_email_address(&original_email.sender)?;
+
+        let (reply_subject, reply_body) = {
+            let mut email_service = self.email_service.lock().await;
+            email_service.render_reply(&template, original_email, &sender_email)
+        };
+
+        // References carries the whole thread; In-Reply-To only the message being answered
+        let references = match (&original_email.references, &original_email.message_id) {
+            (Some(references), Some(message_id)) => Some(format!("{} {}", references, message_id)),
+            (None, Some(message_id)) => Some(message_id.clone()),
+            (references, None) => references.clone(),
+        };
+
+        let reply = CreateOutboxMessage {
+            user_id,
+            email_account_id: account_id,
+            campaign_id: None,
+            message: EmailMessage {
+                to: vec![sender_email.clone()],
+                cc: None,
+                bcc: None,
+                subject: reply_subject,
+                body: reply_body,
+                attachments: None,
+                message_id: None,
+                in_reply_to: original_email.message_id.clone(),
+                references,
+                auto_submitted: Some("auto-replied".to_string()),
+            },
+        };
+
+        // Claim the sender's cooldown and queue the reply together, so concurrent checks
+        // cannot both answer the same sender
+        let queued = self.database.transaction(|tx| {
+            let cooldown = tx.query_row(
+                "SELECT auto_reply_cooldown FROM inbox_monitors WHERE email_account_id = ?1 AND user_id = ?2",
+                [account_id, user_id],
+                |row| row.get::<_, Option<i32>>(0),
+            ).optional()?.flatten().unwrap_or(DEFAULT_AUTO_REPLY_COOLDOWN);
+
+            if !Self::claim_reply_cooldown(tx, account_id, &sender_email, cooldown, Utc::now())? {
+                return Ok(false);
+            }
+
+            OutboxService::enqueue_in(tx, &reply)?;
+            Ok(true)
+        })?;
+
+        if queued {
+            info!("Queued auto-reply to {} for email {}", sender_email, original_email.id);
+            Ok(format!("Queued auto-reply to {}", sender_email))
+        } else {
+            info!("Skipped auto-reply to {}: already answered within the cooldown period", sender_email);
+            Ok(format!("Skipped: {} was answered within the cooldown period", sender_email))
+        }
+    }
+
+    /// Records a reply to `sender_email` unless one was sent within `cooldown_secs`.
+    /// Returns whether the caller may reply.
+    fn claim_reply_cooldown(
+        conn: &Connection,
+        account_id: i32,
+        sender_email: &str,
+        cooldown_secs: i32,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AppError> {
+        let cutoff = now - Duration::seconds(cooldown_secs as i64);
+
+        let rows_affected = conn.execute(
+            "INSERT INTO auto_reply_cooldowns (email_account_id, sender_email, last_replied_at)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT (email_account_id, sender_email) DO UPDATE SET last_replied_at = excluded.last_replied_at
+             WHERE auto_reply_cooldowns.last_replied_at <= ?4",
+            params![account_id, sender_email.to_lowercase(), now.to_rfc3339(), cutoff.to_rfc3339()],
+        )?;
+
+        Ok(rows_affected > 0)
+    }
+
+    fn extract_email_address(sender_string: &str) -> Result<String, AppError> {
+        // Extract email address from "Name <email@domain.com>" format
+        if let Some(start) = sender_string.find('<') {
+            if let Some(end) = sender_string.find('>') {
+                if end > start {
+                    return Ok(sender_string[start + 1..end].to_string());
+                }
+            }
+        }
+
+        // If no angle brackets, assume the whole string is an email
+        if sender_string.contains('@') {
+            Ok(sender_string.trim().to_string())
+        } else {
+            Err(AppError::Validation("Could not extract email address from sender".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbox_service::InboxService;
+    use crate::migrations;
+
+    fn rule(keywords: &[&str], conditions: serde_json::Value) -> AutomationRule {
+        AutomationRule {
+            id: 1,
+            user_id: 1,
+            rule_name: "Invoices".to_string(),
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            conditions,
+            actions: serde_json::json!([{"type": "flag"}]),
+            is_active: true,
+            priority: 0,
+            stop_processing: false,
+            folders: vec!["INBOX".to_string(), "Billing/*".to_string()],
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_rule_trace_reports_each_predicate() {
+        let message = MessageContext {
+            account_id: Some(1),
+            folder: None,
+            from: "Billing <billing@vendor.com>",
+            to: &[],
+            cc: &[],
+            subject: "Invoice #1042",
+            body: "Amount due: 40 EUR",
+            headers: &[],
+            attachment_types: &[],
+            size: 512,
+            date: Utc::now(),
+        };
+
+        let trace = AutomationEngine::evaluate_rule(&rule(&["INVOICE"], serde_json::json!({"all": [
+            {"from": {"op": "glob", "value": "*@vendor.com"}},
+            {"not": {"has_attachment": false}}
+        ]})), &message);
+
+        assert!(!trace.matched);
+        let outcomes: Vec<_> = trace.predicates.iter().map(|p| (p.path.as_str(), p.matched)).collect();
+        assert_eq!(outcomes, vec![
+            ("keywords", true),
+            ("conditions.all[0]", true),
+            ("conditions.all[1].not", true),
+        ]);
+
+        // The legacy form matches the sender, not the recipients
+        let legacy = AutomationEngine::evaluate_rule(&rule(&[], serde_json::json!({"sender_pattern": "@vendor\\.com>?$"})), &message);
+        assert!(legacy.matched);
+
+        let empty = AutomationEngine::evaluate_rule(&rule(&[], serde_json::json!({})), &message);
+        assert!(!empty.matched && empty.skipped.is_some());
+    }
+
+    #[test]
+    fn test_legacy_rule_without_keywords_or_business_hours_never_matches() {
+        let message = MessageContext {
+            account_id: Some(1),
+            folder: Some("INBOX"),
+            from: "someone@example.com",
+            to: &[],
+            cc: &[],
+            subject: "Hello",
+            body: "Anything at all",
+            headers: &[],
+            attachment_types: &[],
+            size: 128,
+            date: Utc::now(),
+        };
+
+        let trace = AutomationEngine::evaluate_rule(&rule(&[], serde_json::json!({"business_hours_only": false})), &message);
+        assert!(!trace.matched);
+        assert!(trace.skipped.is_some());
+
+        // With a keyword it is an ordinary keyword rule again
+        let keyword = AutomationEngine::evaluate_rule(&rule(&["hello"], serde_json::json!({"business_hours_only": false})), &message);
+        assert!(keyword.matched);
+    }
+
+    #[test]
+    fn test_simulation_lists_actions_only_for_matching_messages() {
+        let newsletter = InboxService::parse_message("1".to_string(),
+            b"From: news@vendor.com\r\nSubject: Invoice tips\r\nList-Id: <news.vendor.com>\r\n\r\nHello\r\n").unwrap();
+        let question = InboxService::parse_message("2".to_string(),
+            b"From: jane@example.com\r\nSubject: Lunch?\r\n\r\nHello\r\n").unwrap();
+
+        let mut invoices = rule(&["invoice"], serde_json::json!(null));
+        invoices.actions = serde_json::json!([{"type": "auto_reply", "template_id": 3}, {"type": "mark_as_read"}]);
+
+        let emails = [newsletter, question];
+        let results = AutomationEngine::simulate(&invoices, &emails, None, Some("Billing/2024")).unwrap();
+        assert_eq!(results[0].actions.len(), 2);
+        assert!(results[0].actions[0].note.is_some());
+        assert!(results[1].actions.is_empty() && !results[1].trace.matched);
+
+        // Mail from a folder outside the rule's scope is left alone
+        let results = AutomationEngine::simulate(&invoices, &emails, None, Some("Junk")).unwrap();
+        assert!(results[0].actions.is_empty());
+        assert_eq!((results[0].trace.predicates[0].path.as_str(), results[0].trace.predicates[0].matched), ("folders", false));
+    }
+
+    #[test]
+    fn test_reply_cooldown_allows_one_reply_per_period() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run_migrations(&mut conn).unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        let now = Utc::now();
+
+        conn.execute(
+            "INSERT INTO users (username, email, password_hash) VALUES ('cooldown', 'cooldown@example.com', 'hash')",
+            [],
+        ).unwrap();
+        let user_id = conn.last_insert_rowid();
+        let mut accounts = Vec::new();
+        for address in ["support@example.com", "sales@example.com"] {
+            conn.execute(
+                "INSERT INTO email_accounts (user_id, account_name, email_address, username, password_encrypted)
+                 VALUES (?1, ?2, ?2, ?2, 'x')",
+                params![user_id, address],
+            ).unwrap();
+            accounts.push(conn.last_insert_rowid() as i32);
+        }
+        let (support, sales) = (accounts[0], accounts[1]);
+
+        assert!(AutomationEngine::claim_reply_cooldown(&conn, support, "Jane@Example.com", 3600, now).unwrap());
+        assert!(!AutomationEngine::claim_reply_cooldown(&conn, support, "jane@example.com", 3600, now + Duration::minutes(30)).unwrap());
+        assert!(AutomationEngine::claim_reply_cooldown(&conn, sales, "jane@example.com", 3600, now).unwrap());
+        assert!(AutomationEngine::claim_reply_cooldown(&conn, support, "jane@example.com", 3600, now + Duration::hours(2)).unwrap());
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use crate::models::*;
+
+// Rules are loaded afresh for every message, so their folder patterns are compiled once here
+static FOLDER_GLOBS: LazyLock<Mutex<HashMap<String, Regex>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A condition tree stored in `automation_rules.conditions`.
+///
+/// Nodes are externally tagged, for example:
+///
+/// ```json
+/// {"all": [
+///     {"from": {"op": "glob", "value": "*@vendor.com"}},
+///     {"not": {"has_attachment": true}},
+///     {"header": {"name": "X-Priority", "op": "equals", "value": "1"}}
+/// ]}
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    From(TextMatch),
+    To(TextMatch),
+    Cc(TextMatch),
+    Subject(TextMatch),
+    Body(TextMatch),
+    Header(HeaderMatch),
+    HasAttachment(bool),
+    AttachmentType(TextMatch),
+    Size(SizeRange),
+    Date(DateRange),
+    /// Monday to Friday, 09:00-17:59 UTC
+    BusinessHours(bool),
+    Account(i32),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchOp {
+    Contains,
+    Equals,
+    Regex,
+    Glob,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TextMatch {
+    pub op: MatchOp,
+    pub value: String,
+    /// Filled in by `validate`, so a parsed rule compiles its pattern once rather than per message
+    #[serde(skip)]
+    compiled: CompiledRegex,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HeaderMatch {
+    pub name: String,
+    #[serde(flatten)]
+    pub text: TextMatch,
+}
+
+/// A pattern's regex, built on first use. It is derived from the pattern, so it never
+/// affects equality.
+#[derive(Debug, Clone, Default)]
+struct CompiledRegex(OnceLock<Regex>);
+
+impl PartialEq for CompiledRegex {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// Message size in bytes, both bounds inclusive.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SizeRange {
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DateRange {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+}
+
+/// The parts of a message that conditions can look at.
+pub struct MessageContext<'a> {
+    pub account_id: Option<i32>,
+    /// The folder the message was fetched from, when it came from a mailbox
+    pub folder: Option<&'a str>,
+    pub from: &'a str,
+    pub to: &'a [String],
+    pub cc: &'a [String],
+    pub subject: &'a str,
+    pub body: &'a str,
+    pub headers: &'a [(String, String)],
+    pub attachment_types: &'a [String],
+    pub size: usize,
+    pub date: DateTime<Utc>,
+}
+
+impl<'a> MessageContext<'a> {
+    pub fn from_inbox_email(email: &'a InboxEmail, account_id: Option<i32>, folder: Option<&'a str>) -> Self {
+        Self {
+            account_id,
+            folder,
+            from: &email.sender,
+            to: &email.to,
+            cc: &email.cc,
+            subject: &email.subject,
+            body: &email.body,
+            headers: &email.headers,
+            attachment_types: &email.attachment_types,
+            size: email.size,
+            date: email.received_at,
+        }
+    }
+}
+
+impl Condition {
+    /// Reads a rule's `conditions` column. `null` and `{}` mean "no conditions"; the
+    /// original `{"sender_pattern": ..., "business_hours_only": ...}` form is still accepted.
+    pub fn parse(value: &serde_json::Value) -> Result<Option<Condition>, AppError> {
+        let condition = match value {
+            serde_json::Value::Null => return Ok(None),
+            serde_json::Value::Object(map) if map.is_empty() => return Ok(None),
+            serde_json::Value::Object(map)
+                if map.contains_key("sender_pattern") || map.contains_key("business_hours_only") =>
+            {
+                match Self::from_legacy(map) {
+                    Some(condition) => condition,
+                    None => return Ok(None),
+                }
+            }
+            _ => serde_json::from_value(value.clone())
+                .map_err(|e| AppError::Validation(format!("Invalid rule conditions: {}", e)))?,
+        };
+
+        condition.validate()?;
+        Ok(Some(condition))
+    }
+
+    /// `None` when the legacy form asks for nothing, so such a rule still needs a keyword
+    /// to match rather than matching every message through an empty `all`.
+    fn from_legacy(map: &serde_json::Map<String, serde_json::Value>) -> Option<Condition> {
+        let mut conditions = Vec::new();
+
+        if let Some(pattern) = map.get("sender_pattern").and_then(|p| p.as_str()) {
+            conditions.push(Condition::From(TextMatch::new(MatchOp::Regex, pattern)));
+        }
+
+        if map.get("business_hours_only").and_then(|b| b.as_bool()).unwrap_or(false) {
+            conditions.push(Condition::BusinessHours(true));
+        }
+
+        (!conditions.is_empty()).then_some(Condition::All(conditions))
+    }
+
+    /// Checks every pattern and range up front so a bad rule is rejected when it is saved.
+    pub fn validate(&self) -> Result<(), AppError> {
+        match self {
+            Condition::All(children) | Condition::Any(children) => {
+                children.iter().try_for_each(Condition::validate)
+            }
+            Condition::Not(child) => child.validate(),
+            Condition::From(text)
+            | Condition::To(text)
+            | Condition::Cc(text)
+            | Condition::Subject(text)
+            | Condition::Body(text)
+            | Condition::AttachmentType(text) => text.compile().map(|_| ()),
+            Condition::Header(header) => {
+                if header.name.trim().is_empty() {
+                    return Err(AppError::Validation("header condition needs a header name".to_string()));
+                }
+                header.text.compile().map(|_| ())
+            }
+            Condition::Size(SizeRange { min: Some(min), max: Some(max) }) if min > max => {
+                Err(AppError::Validation("size condition has min greater than max".to_string()))
+            }
+            Condition::Date(DateRange { after: Some(after), before: Some(before) }) if after >= before => {
+                Err(AppError::Validation("date condition has after later than before".to_string()))
+            }
+            Condition::Size(_)
+            | Condition::Date(_)
+            | Condition::HasAttachment(_)
+            | Condition::BusinessHours(_)
+            | Condition::Account(_) => Ok(()),
+        }
+    }
+
+    pub fn evaluate(&self, message: &MessageContext) -> bool {
+        match self {
+            Condition::All(children) => children.iter().all(|child| child.evaluate(message)),
+            Condition::Any(children) => children.iter().any(|child| child.evaluate(message)),
+            Condition::Not(child) => !child.evaluate(message),
+            Condition::From(text) => text.matches_address(message.from),
+            Condition::To(text) => message.to.iter().any(|address| text.matches_address(address)),
+            Condition::Cc(text) => message.cc.iter().any(|address| text.matches_address(address)),
+            Condition::Subject(text) => text.matches(message.subject),
+            Condition::Body(text) => text.matches(message.body),
+            Condition::Header(header) => message.headers.iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(&header.name))
+                .any(|(_, value)| header.text.matches(value)),
+            Condition::HasAttachment(expected) => message.attachment_types.is_empty() != *expected,
+            Condition::AttachmentType(text) => message.attachment_types.iter().any(|mime| text.matches(mime)),
+            Condition::Size(range) => {
+                range.min.is_none_or(|min| message.size >= min)
+                    && range.max.is_none_or(|max| message.size <= max)
+            }
+            Condition::Date(range) => {
+                range.after.is_none_or(|after| message.date > after)
+                    && range.before.is_none_or(|before| message.date < before)
+            }
+            Condition::BusinessHours(expected) => {
+                let now = Utc::now();
+                let in_hours = now.weekday().number_from_monday() <= 5 && (9..18).contains(&now.hour());
+                in_hours == *expected
+            }
+            Condition::Account(account_id) => message.account_id == Some(*account_id),
+        }
+    }
+
+    /// Evaluates like [`Condition::evaluate`] and also returns the outcome of every leaf
+    /// predicate. `all` and `any` do not short-circuit here, so the trace is complete.
+    pub fn evaluate_traced(&self, message: &MessageContext) -> (bool, Vec<PredicateTrace>) {
+        let mut trace = Vec::new();
+        let matched = self.trace_node(message, "conditions", &mut trace);
+        (matched, trace)
+    }
+
+    fn trace_node(&self, message: &MessageContext, path: &str, trace: &mut Vec<PredicateTrace>) -> bool {
+        match self {
+            // Every child is traced before the outcomes are combined
+            Condition::All(children) => {
+                let outcomes: Vec<bool> = children.iter().enumerate()
+                    .map(|(index, child)| child.trace_node(message, &format!("{}.all[{}]", path, index), trace))
+                    .collect();
+                outcomes.iter().all(|matched| *matched)
+            }
+            Condition::Any(children) => {
+                let outcomes: Vec<bool> = children.iter().enumerate()
+                    .map(|(index, child)| child.trace_node(message, &format!("{}.any[{}]", path, index), trace))
+                    .collect();
+                outcomes.iter().any(|matched| *matched)
+            }
+            Condition::Not(child) => !child.trace_node(message, &format!("{}.not", path), trace),
+            leaf => {
+                let matched = leaf.evaluate(message);
+                trace.push(PredicateTrace {
+                    path: path.to_string(),
+                    predicate: serde_json::to_value(leaf).unwrap_or_default(),
+                    matched,
+                });
+                matched
+            }
+        }
+    }
+}
+
+impl TextMatch {
+    pub fn new(op: MatchOp, value: &str) -> Self {
+        Self {
+            op,
+            value: value.to_string(),
+            compiled: CompiledRegex::default(),
+        }
+    }
+
+    /// Builds the matcher once and reuses it; contains, equals and glob ignore case,
+    /// regex is used as written.
+    fn compile(&self) -> Result<&Regex, AppError> {
+        if let Some(regex) = self.compiled.0.get() {
+            return Ok(regex);
+        }
+
+        let (pattern, case_insensitive) = match self.op {
+            MatchOp::Contains => (regex::escape(&self.value), true),
+            MatchOp::Equals => (format!("^{}$", regex::escape(&self.value)), true),
+            MatchOp::Regex => (self.value.clone(), false),
+            MatchOp::Glob => (glob_to_regex(&self.value), true),
+        };
+
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| AppError::Validation(format!("Invalid pattern {:?}: {}", self.value, e)))?;
+        Ok(self.compiled.0.get_or_init(|| regex))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        self.compile().is_ok_and(|regex| regex.is_match(text))
+    }
+
+    /// Matches "Name <user@host>" either as written or by its bare address.
+    fn matches_address(&self, address: &str) -> bool {
+        let bare = address.rsplit('<').next().unwrap_or(address).trim_end_matches('>').trim();
+        self.matches(address) || self.matches(bare)
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+/// Matches a folder name against a rule's folder entry, where `*` matches any run of
+/// characters. Folder names are case-sensitive, except INBOX.
+pub fn folder_matches(pattern: &str, folder: &str) -> bool {
+    if pattern.eq_ignore_ascii_case("INBOX") || folder.eq_ignore_ascii_case("INBOX") {
+        return pattern.eq_ignore_ascii_case(folder);
+    }
+
+    let mut globs = FOLDER_GLOBS.lock().unwrap_or_else(|e| e.into_inner());
+    if !globs.contains_key(pattern) {
+        let mut glob = String::from("^");
+        for (i, part) in pattern.split('*').enumerate() {
+            if i > 0 {
+                glob.push_str(".*");
+            }
+            glob.push_str(&regex::escape(part));
+        }
+        glob.push('$');
+
+        let Ok(regex) = Regex::new(&glob) else {
+            return false;
+        };
+        globs.insert(pattern.to_string(), regex);
+    }
+
+    globs[pattern].is_match(folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message<'a>(headers: &'a [(String, String)], attachment_types: &'a [String]) -> MessageContext<'a> {
+        MessageContext {
+            account_id: Some(7),
+            folder: Some("INBOX"),
+            from: "Billing <billing@vendor.com>",
+            to: &[],
+            cc: &[],
+            subject: "Invoice #1042",
+            body: "Please find the invoice attached.",
+            headers,
+            attachment_types,
+            size: 2048,
+            date: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_condition_tree_evaluates_predicates() {
+        let headers = vec![("X-Priority".to_string(), "1".to_string())];
+        let attachments = vec!["application/pdf".to_string()];
+        let invoice = message(&headers, &attachments);
+
+        let condition: Condition = serde_json::from_value(serde_json::json!({"all": [
+            {"from": {"op": "glob", "value": "*@VENDOR.com"}},
+            {"subject": {"op": "regex", "value": "^Invoice #\\d+$"}},
+            {"header": {"name": "x-priority", "op": "equals", "value": "1"}},
+            {"attachment_type": {"op": "contains", "value": "pdf"}},
+            {"size": {"min": 1024, "max": null}},
+            {"any": [{"account": 3}, {"account": 7}]},
+            {"not": {"body": {"op": "contains", "value": "unsubscribe"}}}
+        ]})).unwrap();
+        assert!(condition.evaluate(&invoice));
+
+        assert!(!Condition::HasAttachment(true).evaluate(&message(&headers, &[])));
+        assert!(!Condition::To(TextMatch::new(MatchOp::Contains, "x")).evaluate(&invoice));
+    }
+
+    #[test]
+    fn test_parse_validates_and_accepts_legacy_conditions() {
+        assert_eq!(Condition::parse(&serde_json::json!({})).unwrap(), None);
+
+        let legacy = Condition::parse(&serde_json::json!({"sender_pattern": "@vendor\\.com$"})).unwrap().unwrap();
+        assert!(legacy.evaluate(&message(&[], &[])));
+
+        assert!(Condition::parse(&serde_json::json!({"subject": {"op": "regex", "value": "("}})).is_err());
+        assert!(Condition::parse(&serde_json::json!({"size": {"min": 10, "max": 1}})).is_err());
+        assert!(Condition::parse(&serde_json::json!({"colour": "red"})).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use imap::extensions::idle::SetReadTimeout;
+use lettre::transport::smtp::client::{Certificate, Tls, TlsParameters};
+use lettre::transport::smtp::SmtpTransportBuilder;
+use lettre::SmtpTransport;
+use native_tls::{TlsConnector, TlsStream};
+use crate::models::*;
+
+// The tag of our own STARTTLS command; the imap crate numbers its commands from a1
+const STARTTLS_TAG: &str = "s0";
+
+/// An IMAP connection that may or may not have been upgraded to TLS.
+#[derive(Debug)]
+pub enum MailStream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+pub type ImapSession = imap::Session<MailStream>;
+
+impl Read for MailStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            MailStream::Tls(stream) => stream.read(buf),
+            MailStream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MailStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            MailStream::Tls(stream) => stream.write(buf),
+            MailStream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            MailStream::Tls(stream) => stream.flush(),
+            MailStream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+// IDLE's wait_timeout needs to set the socket's read timeout
+impl SetReadTimeout for MailStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::error::Result<()> {
+        let stream = match self {
+            MailStream::Tls(stream) => stream.get_ref(),
+            MailStream::Plain(stream) => stream,
+        };
+        stream.set_read_timeout(timeout).map_err(imap::error::Error::Io)
+    }
+}
+
+/// Rejects a custom CA certificate that isn't valid PEM before it is saved with an account.
+pub fn validate_ca_certificate(pem: &str) -> Result<(), AppError> {
+    native_tls::Certificate::from_pem(pem.as_bytes())
+        .map(|_| ())
+        .map_err(|e| AppError::Validation(format!("Invalid CA certificate: {}", e)))
+}
+
+/// Connects to the account's IMAP server as its `imap_security` says; the greeting has
+/// been read and the client is ready to log in.
+pub fn connect_imap(account: &EmailAccount) -> Result<imap::Client<MailStream>, AppError> {
+    let server = account.imap_server.as_deref()
+        .ok_or_else(|| AppError::Config("IMAP server not configured".to_string()))?;
+    let port = account.imap_port.unwrap_or(993) as u16;
+
+    let mut tcp = TcpStream::connect((server, port))
+        .map_err(|e| AppError::Email(format!("IMAP connection error: {}", e)))?;
+
+    let stream = match account.imap_security {
+        ConnectionSecurity::ImplicitTls => {
+            let tls = imap_tls_connector(account)?.connect(server, tcp)
+                .map_err(|e| AppError::Email(format!("IMAP TLS error: {}", e)))?;
+            return greeted(MailStream::Tls(tls));
+        }
+        ConnectionSecurity::Plaintext => return greeted(MailStream::Plain(tcp)),
+        ConnectionSecurity::StartTlsRequired | ConnectionSecurity::StartTlsOpportunistic => {
+            if imap_starttls(&mut tcp)? {
+                let tls = imap_tls_connector(account)?.connect(server, tcp)
+                    .map_err(|e| AppError::Email(format!("IMAP TLS error: {}", e)))?;
+                MailStream::Tls(tls)
+            } else if account.imap_security == ConnectionSecurity::StartTlsOpportunistic {
+                MailStream::Plain(tcp)
+            } else {
+                return Err(AppError::Email(format!("{} does not offer STARTTLS", server)));
+            }
+        }
+    };
+
+    // The greeting was read before STARTTLS, and the server doesn't repeat it afterwards
+    Ok(imap::Client::new(stream))
+}
+
+fn greeted(stream: MailStream) -> Result<imap::Client<MailStream>, AppError> {
+    let mut client = imap::Client::new(stream);
+    client.read_greeting()
+        .map_err(|e| AppError::Email(format!("IMAP greeting error: {}", e)))?;
+    Ok(client)
+}
+
+fn imap_tls_connector(account: &EmailAccount) -> Result<TlsConnector, AppError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(pem) = &account.tls_ca_certificate {
+        let certificate = native_tls::Certificate::from_pem(pem.as_bytes())
+            .map_err(|e| AppError::Config(format!("Invalid CA certificate: {}", e)))?;
+        builder.add_root_certificate(certificate);
+    }
+    builder.danger_accept_invalid_certs(account.tls_accept_invalid_certs);
+    builder.build()
+        .map_err(|e| AppError::Email(format!("TLS error: {}", e)))
+}
+
+/// Reads the greeting and asks for STARTTLS. Returns whether the server agreed; a server
+/// that doesn't support it answers NO or BAD and the connection stays usable in plaintext.
+fn imap_starttls(tcp: &mut TcpStream) -> Result<bool, AppError> {
+    let io_error = |e: std::io::Error| AppError::Email(format!("IMAP connection error: {}", e));
+
+    // Nothing is buffered past the lines read here: the server sends nothing until the
+    // greeting is answered, nor after the tagged STARTTLS response
+    let mut reader = BufReader::new(tcp.try_clone().map_err(io_error)?);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(io_error)?;
+    if !line.starts_with("* OK") {
+        return Err(AppError::Email(format!("Unexpected IMAP greeting: {}", line.trim_end())));
+    }
+
+    tcp.write_all(format!("{} STARTTLS\r\n", STARTTLS_TAG).as_bytes()).map_err(io_error)?;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(io_error)? == 0 {
+            return Err(AppError::Email("IMAP server closed the connection during STARTTLS".to_string()));
+        }
+        if let Some(status) = line.strip_prefix(STARTTLS_TAG).map(str::trim_start) {
+            return Ok(status.starts_with("OK"));
+        }
+    }
+}
+
+/// An SMTP transport builder for the account's server, secured as its `smtp_security` says.
+pub fn smtp_transport_builder(account: &EmailAccount) -> Result<SmtpTransportBuilder, AppError> {
+    let server = account.smtp_server.as_deref()
+        .ok_or_else(|| AppError::Config("SMTP server not configured".to_string()))?;
+    let port = account.smtp_port.unwrap_or(587) as u16;
+
+    let tls = match account.smtp_security {
+        ConnectionSecurity::ImplicitTls => Tls::Wrapper(smtp_tls_parameters(account, server)?),
+        ConnectionSecurity::StartTlsRequired => Tls::Required(smtp_tls_parameters(account, server)?),
+        ConnectionSecurity::StartTlsOpportunistic => Tls::Opportunistic(smtp_tls_parameters(account, server)?),
+        ConnectionSecurity::Plaintext => Tls::None,
+    };
+
+    Ok(SmtpTransport::builder_dangerous(server).port(port).tls(tls))
+}
+
+fn smtp_tls_parameters(account: &EmailAccount, server: &str) -> Result<TlsParameters, AppError> {
+    let mut builder = TlsParameters::builder(server.to_string())
+        .dangerous_accept_invalid_certs(account.tls_accept_invalid_certs);
+    if let Some(pem) = &account.tls_ca_certificate {
+        let certificate = Certificate::from_pem(pem.as_bytes())
+            .map_err(|e| AppError::Config(format!("Invalid CA certificate: {}", e)))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder.build()
+        .map_err(|e| AppError::Email(format!("TLS error: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_starttls_is_refused_or_skipped_depending_on_security() {
+        // A server without STARTTLS, answering everything else in plaintext
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as i32;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.write_all(b"* OK [CAPABILITY IMAP4rev1] ready\r\n").unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let tag = line.split_whitespace().next().unwrap_or("*").to_string();
+                    let reply = if line.contains("STARTTLS") {
+                        format!("{} BAD STARTTLS not supported\r\n", tag)
+                    } else if line.contains("LOGIN") {
+                        format!("{} OK LOGIN completed\r\n", tag)
+                    } else {
+                        format!("{} OK done\r\n", tag)
+                    };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                    line.clear();
+                }
+            }
+        });
+
+        let account = |security| EmailAccount {
+            id: 1,
+            user_id: 1,
+            account_name: "Dev".to_string(),
+            email_address: "dev@localhost".to_string(),
+            imap_server: Some("127.0.0.1".to_string()),
+            imap_port: Some(port),
+            smtp_server: None,
+            smtp_port: None,
+            username: "dev".to_string(),
+            password_encrypted: String::new(),
+            auth_type: "password".to_string(),
+            oauth_provider: None,
+            smtp_security: ConnectionSecurity::Plaintext,
+            imap_security: security,
+            tls_ca_certificate: None,
+            tls_accept_invalid_certs: false,
+            is_active: true,
+            rate_limits: RateLimits::default(),
+            created_at: chrono::Utc::now(),
+        };
+
+        let error = connect_imap(&account(ConnectionSecurity::StartTlsRequired)).err().unwrap();
+        assert!(error.to_string().contains("does not offer STARTTLS"));
+
+        for security in [ConnectionSecurity::StartTlsOpportunistic, ConnectionSecurity::Plaintext] {
+            let client = connect_imap(&account(security)).unwrap();
+            let mut session = client.login("dev", "secret").map_err(|e| e.0).unwrap();
+            session.noop().unwrap();
+        }
+    }
+}
@@ -4,6 +4,7 @@ use log::{info, error};
 use csv::ReaderBuilder;
 use crate::models::*;
 use crate::database::Database;
+use rusqlite::Transaction;
 
 pub struct ContactService {
     database: std::sync::Arc<Database>,
@@ -16,23 +17,23 @@ impl ContactService {
     
     // Contact List Management
     pub fn create_contact_list(&self, user_id: i32, list_data: CreateContactList) -> Result<ContactList, AppError> {
-        let conn = self.database.get_connection();
-        
-        let mut stmt = conn.prepare(
-            "INSERT INTO contact_lists (user_id, name, description) VALUES (?1, ?2, ?3)"
-        )?;
-        
-        let list_id = stmt.insert((
-            user_id,
-            &list_data.name,
-            &list_data.description,
-        ))?;
+        let list_id = self.database.transaction(|tx| {
+            let mut stmt = tx.prepare(
+                "INSERT INTO contact_lists (user_id, name, description) VALUES (?1, ?2, ?3)"
+            )?;
+            
+            Ok(stmt.insert((
+                user_id,
+                &list_data.name,
+                &list_data.description,
+            ))?)
+        })?;
         
         self.get_contact_list(user_id, list_id as i32)
     }
     
     pub fn get_contact_list(&self, user_id: i32, list_id: i32) -> Result<ContactList, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, description, created_at, updated_at
@@ -54,7 +55,7 @@ impl ContactService {
     }
     
     pub fn get_user_contact_lists(&self, user_id: i32) -> Result<Vec<ContactList>, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, description, created_at, updated_at
@@ -81,35 +82,38 @@ impl ContactService {
     }
     
     pub fn update_contact_list(&self, user_id: i32, list_id: i32, list_data: CreateContactList) -> Result<ContactList, AppError> {
-        let conn = self.database.get_connection();
-        
-        conn.execute(
-            "UPDATE contact_lists SET name = ?1, description = ?2, updated_at = CURRENT_TIMESTAMP
-             WHERE id = ?3 AND user_id = ?4",
-            (&list_data.name, &list_data.description, list_id, user_id),
-        )?;
+        self.database.transaction(|tx| {
+            tx.execute(
+                "UPDATE contact_lists SET name = ?1, description = ?2, updated_at = CURRENT_TIMESTAMP
+                 WHERE id = ?3 AND user_id = ?4",
+                (&list_data.name, &list_data.description, list_id, user_id),
+            )?;
+            Ok(())
+        })?;
         
         self.get_contact_list(user_id, list_id)
     }
     
     pub fn delete_contact_list(&self, user_id: i32, list_id: i32) -> Result<(), AppError> {
-        let conn = self.database.get_connection();
-        
-        // First delete all contacts in the list
-        conn.execute(
-            "DELETE FROM contacts WHERE contact_list_id = ?1 AND user_id = ?2",
-            [list_id, user_id],
-        )?;
-        
-        // Then delete the list
-        let rows_affected = conn.execute(
-            "DELETE FROM contact_lists WHERE id = ?1 AND user_id = ?2",
-            [list_id, user_id],
-        )?;
-        
-        if rows_affected == 0 {
-            return Err(AppError::NotFound("Contact list not found".to_string()));
-        }
+        self.database.transaction(|tx| {
+            // First delete all contacts in the list
+            tx.execute(
+                "DELETE FROM contacts WHERE contact_list_id = ?1 AND user_id = ?2",
+                [list_id, user_id],
+            )?;
+            
+            // Then delete the list
+            let rows_affected = tx.execute(
+                "DELETE FROM contact_lists WHERE id = ?1 AND user_id = ?2",
+                [list_id, user_id],
+            )?;
+            
+            if rows_affected == 0 {
+                return Err(AppError::NotFound("Contact list not found".to_string()));
+            }
+            
+            Ok(())
+        })?;
         
         info!("Deleted contact list {} for user {}", list_id, user_id);
         Ok(())
@@ -117,26 +121,32 @@ impl ContactService {
     
     // Contact Management
     pub fn create_contact(&self, user_id: i32, contact_data: CreateContact) -> Result<Contact, AppError> {
-        let conn = self.database.get_connection();
-        
-        // Verify the contact list belongs to the user
-        let list_exists = conn.query_row(
-            "SELECT 1 FROM contact_lists WHERE id = ?1 AND user_id = ?2",
-            [contact_data.contact_list_id, user_id],
-            |_| Ok(())
-        );
-        
-        if list_exists.is_err() {
-            return Err(AppError::NotFound("Contact list not found".to_string()));
-        }
+        let contact_id = self.database.transaction(|tx| {
+            // Verify the contact list belongs to the user
+            let list_exists = tx.query_row(
+                "SELECT 1 FROM contact_lists WHERE id = ?1 AND user_id = ?2",
+                [contact_data.contact_list_id, user_id],
+                |_| Ok(())
+            );
+            
+            if list_exists.is_err() {
+                return Err(AppError::NotFound("Contact list not found".to_string()));
+            }
+            
+            Self::insert_contact(tx, user_id, &contact_data)
+        })?;
         
+        self.get_contact(user_id, contact_id)
+    }
+    
+    fn insert_contact(tx: &Transaction, user_id: i32, contact_data: &CreateContact) -> Result<i32, AppError> {
         let custom_fields_json = contact_data.custom_fields
             .as_ref()
             .map(|cf| serde_json::to_string(cf))
             .transpose()
             .map_err(|e| AppError::Internal(format!("Failed to serialize custom fields: {}", e)))?;
         
-        let mut stmt = conn.prepare(
+        let mut stmt = tx.prepare_cached(
             "INSERT INTO contacts (user_id, contact_list_id, email, first_name, last_name, custom_fields)
              VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
         )?;
@@ -150,11 +160,11 @@ impl ContactService {
             &custom_fields_json,
         ))?;
         
-        self.get_contact(user_id, contact_id as i32)
+        Ok(contact_id as i32)
     }
     
     pub fn get_contact(&self, user_id: i32, contact_id: i32) -> Result<Contact, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, user_id, contact_list_id, email, first_name, last_name, custom_fields, is_active, created_at, updated_at
@@ -187,7 +197,7 @@ impl ContactService {
     }
     
     pub fn get_contacts_by_list(&self, user_id: i32, list_id: i32) -> Result<Vec<Contact>, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
             "SELECT id, user_id, contact_list_id, email, first_name, last_name, custom_fields, is_active, created_at, updated_at
@@ -225,38 +235,39 @@ impl ContactService {
     }
     
     pub fn update_contact(&self, user_id: i32, contact_id: i32, contact_data: CreateContact) -> Result<Contact, AppError> {
-        let conn = self.database.get_connection();
-        
         let custom_fields_json = contact_data.custom_fields
             .as_ref()
             .map(|cf| serde_json::to_string(cf))
             .transpose()
             .map_err(|e| AppError::Internal(format!("Failed to serialize custom fields: {}", e)))?;
         
-        conn.execute(
-            "UPDATE contacts SET contact_list_id = ?1, email = ?2, first_name = ?3, last_name = ?4, custom_fields = ?5, updated_at = CURRENT_TIMESTAMP
-             WHERE id = ?6 AND user_id = ?7",
-            (
-                contact_data.contact_list_id,
-                &contact_data.email,
-                &contact_data.first_name,
-                &contact_data.last_name,
-                &custom_fields_json,
-                contact_id,
-                user_id,
-            ),
-        )?;
+        self.database.transaction(|tx| {
+            tx.execute(
+                "UPDATE contacts SET contact_list_id = ?1, email = ?2, first_name = ?3, last_name = ?4, custom_fields = ?5, updated_at = CURRENT_TIMESTAMP
+                 WHERE id = ?6 AND user_id = ?7",
+                (
+                    contact_data.contact_list_id,
+                    &contact_data.email,
+                    &contact_data.first_name,
+                    &contact_data.last_name,
+                    &custom_fields_json,
+                    contact_id,
+                    user_id,
+                ),
+            )?;
+            Ok(())
+        })?;
         
         self.get_contact(user_id, contact_id)
     }
     
     pub fn delete_contact(&self, user_id: i32, contact_id: i32) -> Result<(), AppError> {
-        let conn = self.database.get_connection();
-        
-        let rows_affected = conn.execute(
-            "DELETE FROM contacts WHERE id = ?1 AND user_id = ?2",
-            [contact_id, user_id],
-        )?;
+        let rows_affected = self.database.transaction(|tx| {
+            Ok(tx.execute(
+                "DELETE FROM contacts WHERE id = ?1 AND user_id = ?2",
+                [contact_id, user_id],
+            )?)
+        })?;
         
         if rows_affected == 0 {
             return Err(AppError::NotFound("Contact not found".to_string()));
@@ -269,16 +280,8 @@ impl ContactService {
     // CSV Import functionality
     pub fn import_contacts_from_csv(&self, user_id: i32, import_request: ImportContactsRequest) -> Result<Vec<Contact>, AppError> {
         // Verify the contact list belongs to the user
-        let conn = self.database.get_connection();
-        let list_exists = conn.query_row(
-            "SELECT 1 FROM contact_lists WHERE id = ?1 AND user_id = ?2",
-            [import_request.contact_list_id, user_id],
-            |_| Ok(())
-        );
-        
-        if list_exists.is_err() {
-            return Err(AppError::NotFound("Contact list not found".to_string()));
-        }
+        self.get_contact_list(user_id, import_request.contact_list_id)
+            .map_err(|_| AppError::NotFound("Contact list not found".to_string()))?;
         
         // Parse CSV data
         let mut reader = ReaderBuilder::new()
@@ -296,7 +299,7 @@ impl ContactService {
         let first_name_col = headers.iter().position(|h| h.to_lowercase() == "first_name" || h.to_lowercase() == "firstname");
         let last_name_col = headers.iter().position(|h| h.to_lowercase() == "last_name" || h.to_lowercase() == "lastname");
         
-        let mut imported_contacts = Vec::new();
+        let mut parsed_contacts = Vec::new();
         let mut errors = Vec::new();
         
         for (line_num, result) in reader.records().enumerate() {
@@ -345,18 +348,13 @@ impl ContactService {
                             .map_err(|e| AppError::Internal(format!("Failed to serialize custom fields: {}", e)))?)
                     };
                     
-                    let contact_data = CreateContact {
+                    parsed_contacts.push(CreateContact {
                         contact_list_id: import_request.contact_list_id,
                         email: email.trim().to_string(),
                         first_name,
                         last_name,
                         custom_fields: custom_fields_value,
-                    };
-                    
-                    match self.create_contact(user_id, contact_data) {
-                        Ok(contact) => imported_contacts.push(contact),
-                        Err(e) => errors.push(format!("Line {}: {}", line_num + 2, e)),
-                    }
+                    });
                 },
                 Err(e) => {
                     errors.push(format!("Line {}: CSV parsing error: {}", line_num + 2, e));
@@ -364,6 +362,18 @@ impl ContactService {
             }
         }
         
+        // Insert every parsed row in one transaction so the import is all-or-nothing and
+        // holds the write lock only for the inserts, not for the CSV parsing above
+        let contact_ids = self.database.transaction(|tx| {
+            parsed_contacts.iter()
+                .map(|contact_data| Self::insert_contact(tx, user_id, contact_data))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        
+        let imported_contacts = contact_ids.into_iter()
+            .map(|contact_id| self.get_contact(user_id, contact_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        
         if !errors.is_empty() {
             error!("CSV import errors: {:?}", errors);
             // You might want to return partial success with errors
@@ -375,7 +385,7 @@ impl ContactService {
     }
     
     pub fn get_total_contacts_count(&self, user_id: i32) -> Result<i32, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let count: i32 = conn.query_row(
             "SELECT COUNT(*) FROM contacts WHERE user_id = ?1 AND is_active = 1",
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use crate::models::*;
+use crate::database::Database;
+use crate::encryption::EncryptionService;
+use crate::oauth::OAuthService;
+
+/// Turns an email account into credentials its servers accept.
+///
+/// This is the one place stored secrets are read: passwords are sealed and opened with
+/// the `EncryptionService`, and OAuth2 accounts get a current access token from the
+/// `OAuthService`, refreshed if it is about to expire. Services that connect to a mail
+/// server ask here instead of reading `password_encrypted` themselves.
+pub struct CredentialService {
+    database: Arc<Database>,
+    encryption_service: Arc<EncryptionService>,
+    oauth_service: Arc<OAuthService>,
+}
+
+impl CredentialService {
+    pub fn new(database: Arc<Database>, encryption_service: Arc<EncryptionService>, oauth_service: Arc<OAuthService>) -> Self {
+        Self {
+            database,
+            encryption_service,
+            oauth_service,
+        }
+    }
+
+    /// Encrypts a password for storing as an account's `password_encrypted`.
+    pub fn seal_password(&self, password: &str) -> Result<String, AppError> {
+        self.encryption_service.encrypt(password)
+    }
+
+    /// Loads the user's account along with ready-to-use credentials for it.
+    pub async fn resolve(&self, user_id: i32, account_id: i32) -> Result<(EmailAccount, MailCredential), AppError> {
+        let account = self.database.get_email_account(user_id, account_id)?
+            .ok_or_else(|| AppError::NotFound("Email account not found".to_string()))?;
+        let credential = self.credential(&account).await?;
+        Ok((account, credential))
+    }
+
+    /// Credentials for an account already loaded. A password that can't be decrypted is a
+    /// `Config` error, since it won't decrypt until the encryption key is put back.
+    pub async fn credential(&self, account: &EmailAccount) -> Result<MailCredential, AppError> {
+        match account.auth_type.as_str() {
+            "oauth2" => self.oauth_service.credential(account.id).await,
+            _ => self.encryption_service.decrypt(&account.password_encrypted)
+                .map(MailCredential::Password)
+                .map_err(|e| AppError::Config(format!(
+                    "The stored password for account {} can't be decrypted: {}", account.id, e
+                ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_support::temp_database;
+
+    #[tokio::test]
+    async fn test_resolves_sealed_passwords_and_rejects_plaintext() {
+        if std::env::var("ENCRYPTION_KEY").is_err() {
+            std::env::set_var("ENCRYPTION_KEY", "0".repeat(64));
+        }
+        let database = temp_database();
+        let encryption_service = Arc::new(EncryptionService::new().unwrap());
+        let oauth_service = Arc::new(OAuthService::new(database.shared(), Arc::clone(&encryption_service), Vec::new()));
+        let service = CredentialService::new(database.shared(), encryption_service, oauth_service);
+
+        let user = database.create_user(CreateUser {
+            username: "credentials".to_string(),
+            email: "credentials@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+
+        let create = |password_encrypted: String| CreateEmailAccountWithUser {
+            user_id: user.id,
+            account_name: "Work".to_string(),
+            email_address: "jane@example.com".to_string(),
+            imap_server: Some("imap.example.com".to_string()),
+            imap_port: Some(993),
+            smtp_server: Some("smtp.example.com".to_string()),
+            smtp_port: Some(465),
+            username: "jane@example.com".to_string(),
+            password_encrypted,
+            auth_type: "password".to_string(),
+            oauth_provider: None,
+            smtp_security: ConnectionSecurity::ImplicitTls,
+            imap_security: ConnectionSecurity::ImplicitTls,
+            tls_ca_certificate: None,
+            tls_accept_invalid_certs: false,
+            is_active: Some(true),
+            rate_limits: RateLimits::default(),
+        };
+
+        let sealed = database.create_email_account(create(service.seal_password("hunter2").unwrap())).unwrap();
+        assert_ne!(sealed.password_encrypted, "hunter2");
+        match service.resolve(user.id, sealed.id).await.unwrap() {
+            (account, MailCredential::Password(password)) => {
+                assert_eq!(account.id, sealed.id);
+                assert_eq!(password, "hunter2");
+            }
+            (_, MailCredential::OAuth2 { .. }) => panic!("expected a password"),
+        }
+
+        // Plaintext left in the column is never handed to a server as the password
+        let legacy = database.create_email_account(create("hunter2".to_string())).unwrap();
+        assert!(matches!(service.resolve(user.id, legacy.id).await, Err(AppError::Config(_))));
+        assert!(matches!(service.resolve(user.id, legacy.id + 1).await, Err(AppError::NotFound(_))));
+    }
+}
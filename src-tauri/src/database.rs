@@ -1,247 +1,96 @@
-use rusqlite::{Connection, Result as SqliteResult, params};
+use rusqlite::{Connection, OpenFlags, Transaction, TransactionBehavior, params};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
 use crate::models::*;
+use crate::migrations;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 
+/// A read-only connection checked out of the reader pool; returned to the pool on drop.
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+const READER_POOL_SIZE: u32 = 4;
+const BUSY_TIMEOUT_MS: u32 = 5000;
+
+const ACCOUNT_COLUMNS: &str = "id, user_id, account_name, email_address, imap_server, imap_port, smtp_server, smtp_port, username, password_encrypted, is_active, created_at, max_per_minute, max_per_hour, max_per_day, send_window_start, send_window_end, auth_type, oauth_provider, smtp_security, imap_security, tls_ca_certificate, tls_accept_invalid_certs";
+
+/// SQLite access split into a pool of readers and a single writer.
+///
+/// The database runs in WAL mode, so readers never wait on the writer and a long write
+/// (such as a CSV import) only serializes other writes. All writes go through
+/// [`Database::transaction`].
 pub struct Database {
-    conn: Arc<Mutex<Connection>>,
+    readers: Pool<SqliteConnectionManager>,
+    writer: Mutex<Connection>,
 }
 
 impl Database {
     pub fn new<P: AsRef<Path>>(db_path: P) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
-        let db = Database { conn: Arc::new(Mutex::new(conn)) };
-        db.init_tables()?;
-        Ok(db)
+        let db_path = db_path.as_ref();
+
+        let mut writer = Connection::open(db_path)?;
+        writer.execute_batch(&format!(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA busy_timeout = {};",
+            BUSY_TIMEOUT_MS
+        ))?;
+        migrations::run_migrations(&mut writer)?;
+        // Only after migrating: a migration that rebuilds a table must not cascade its drop
+        writer.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+        let manager = SqliteConnectionManager::file(db_path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_init(|conn| conn.execute_batch(&format!("PRAGMA busy_timeout = {}; PRAGMA foreign_keys = ON;", BUSY_TIMEOUT_MS)));
+
+        let readers = Pool::builder()
+            .max_size(READER_POOL_SIZE)
+            .build(manager)?;
+
+        Ok(Database {
+            readers,
+            writer: Mutex::new(writer),
+        })
     }
 
-    pub fn get_connection(&self) -> std::sync::MutexGuard<Connection> {
-        self.conn.lock().unwrap()
+    /// Checks out a read-only connection from the pool.
+    pub fn get_connection(&self) -> Result<DbConnection, AppError> {
+        Ok(self.readers.get()?)
     }
 
-    fn init_tables(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        
-        // Create users table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS users (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                username TEXT UNIQUE NOT NULL,
-                email TEXT UNIQUE NOT NULL,
-                password_hash TEXT NOT NULL,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-                updated_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create email_accounts table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS email_accounts (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                account_name TEXT NOT NULL,
-                email_address TEXT NOT NULL,
-                imap_server TEXT,
-                imap_port INTEGER,
-                smtp_server TEXT,
-                smtp_port INTEGER,
-                username TEXT NOT NULL,
-                password_encrypted TEXT NOT NULL,
-                is_active BOOLEAN DEFAULT 1,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create email_templates table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS email_templates (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                name TEXT NOT NULL,
-                subject TEXT,
-                body TEXT,
-                template_type TEXT,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-                updated_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create automation_rules table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS automation_rules (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                rule_name TEXT NOT NULL,
-                keywords TEXT,
-                conditions TEXT,
-                actions TEXT,
-                is_active BOOLEAN DEFAULT 1,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create email_logs table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS email_logs (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE SET NULL,
-                direction TEXT CHECK (direction IN ('sent', 'received')),
-                recipient_email TEXT,
-                sender_email TEXT,
-                subject TEXT,
-                status TEXT,
-                error_message TEXT,
-                sent_at TEXT,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create scheduled_emails table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS scheduled_emails (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                template_id INTEGER REFERENCES email_templates(id) ON DELETE SET NULL,
-                recipient_list TEXT,
-                scheduled_time TEXT,
-                recurrence_pattern TEXT,
-                status TEXT DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'failed')),
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create email_attachments table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS email_attachments (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                email_log_id INTEGER REFERENCES email_logs(id) ON DELETE CASCADE,
-                filename TEXT NOT NULL,
-                original_filename TEXT NOT NULL,
-                file_path TEXT NOT NULL,
-                file_size INTEGER,
-                mime_type TEXT,
-                sender_email TEXT,
-                received_at TEXT,
-                category TEXT,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create contact_lists table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS contact_lists (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                name TEXT NOT NULL,
-                description TEXT,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-                updated_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create contacts table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS contacts (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                contact_list_id INTEGER REFERENCES contact_lists(id) ON DELETE CASCADE,
-                email TEXT NOT NULL,
-                first_name TEXT,
-                last_name TEXT,
-                custom_fields TEXT,
-                is_active BOOLEAN DEFAULT 1,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-                updated_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create email_campaigns table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS email_campaigns (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                name TEXT NOT NULL,
-                template_id INTEGER REFERENCES email_templates(id) ON DELETE SET NULL,
-                contact_list_id INTEGER REFERENCES contact_lists(id) ON DELETE SET NULL,
-                status TEXT DEFAULT 'draft' CHECK (status IN ('draft', 'scheduled', 'sending', 'completed', 'failed')),
-                scheduled_time TEXT,
-                total_recipients INTEGER DEFAULT 0,
-                sent_count INTEGER DEFAULT 0,
-                failed_count INTEGER DEFAULT 0,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP,
-                updated_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        // Create inbox_monitors table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS inbox_monitors (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
-                email_account_id INTEGER REFERENCES email_accounts(id) ON DELETE CASCADE,
-                is_active BOOLEAN DEFAULT 1,
-                check_interval INTEGER DEFAULT 300,
-                last_check TEXT,
-                auto_reply_template_id INTEGER REFERENCES email_templates(id) ON DELETE SET NULL,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-            [],
-        )?;
-
-        Ok(())
+    /// Runs `f` inside an immediate transaction on the writer connection.
+    ///
+    /// The transaction commits when `f` returns `Ok` and rolls back otherwise. Keep the
+    /// closure short and free of network I/O: it holds the only write lock.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, AppError>,
+    {
+        let mut conn = self.writer.lock()
+            .map_err(|_| AppError::Internal("Database writer lock poisoned".to_string()))?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let value = f(&tx)?;
+        tx.commit()?;
+
+        Ok(value)
     }
 
     // User operations
     pub fn create_user(&self, user: CreateUser) -> Result<User> {
         let password_hash = bcrypt::hash(&user.password, bcrypt::DEFAULT_COST)?;
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
-        conn.execute(
-            r#"
-            INSERT INTO users (username, email, password_hash, created_at, updated_at)
-            VALUES (?1, ?2, ?3, ?4, ?5)
-            "#,
-            params![&user.username, &user.email, &password_hash, &now, &now],
-        )?;
-
-        let user_id = conn.last_insert_rowid() as i32;
+        let user_id = self.transaction(|tx| {
+            tx.execute(
+                r#"
+                INSERT INTO users (username, email, password_hash, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+                params![&user.username, &user.email, &password_hash, &now, &now],
+            )?;
+
+            Ok(tx.last_insert_rowid() as i32)
+        })?;
         
         Ok(User {
             id: user_id,
@@ -254,7 +103,7 @@ impl Database {
     }
 
     pub fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
             "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE email = ?1"
         )?;
@@ -278,7 +127,7 @@ impl Database {
     }
 
     pub fn get_user_by_id(&self, user_id: i32) -> Result<Option<User>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
             "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE id = ?1"
         )?;
@@ -303,13 +152,19 @@ impl Database {
 
     // Email account operations
     pub fn create_email_account(&self, account: CreateEmailAccountWithUser) -> Result<EmailAccount> {
+        Ok(self.transaction(|tx| Self::create_email_account_in(tx, account))?)
+    }
+
+    /// Inserts an account as part of a larger write, such as an OAuth2 sign-in that stores
+    /// the account's tokens alongside it.
+    pub fn create_email_account_in(tx: &Transaction, account: CreateEmailAccountWithUser) -> Result<EmailAccount, AppError> {
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
-        conn.execute(
+        tx.execute(
             r#"
-            INSERT INTO email_accounts (user_id, account_name, email_address, imap_server, imap_port, smtp_server, smtp_port, username, password_encrypted, is_active, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            INSERT INTO email_accounts (user_id, account_name, email_address, imap_server, imap_port, smtp_server, smtp_port, username, password_encrypted, is_active, created_at,
+                                        max_per_minute, max_per_hour, max_per_day, send_window_start, send_window_end, auth_type, oauth_provider,
+                                        smtp_security, imap_security, tls_ca_certificate, tls_accept_invalid_certs)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)
             "#,
             params![
                 account.user_id,
@@ -322,14 +177,23 @@ impl Database {
                 &account.username,
                 &account.password_encrypted,
                 account.is_active.unwrap_or(true),
-                &now
+                &now,
+                account.rate_limits.max_per_minute,
+                account.rate_limits.max_per_hour,
+                account.rate_limits.max_per_day,
+                account.rate_limits.send_window_start,
+                account.rate_limits.send_window_end,
+                &account.auth_type,
+                &account.oauth_provider,
+                account.smtp_security.as_str(),
+                account.imap_security.as_str(),
+                &account.tls_ca_certificate,
+                account.tls_accept_invalid_certs,
             ],
         )?;
-
-        let account_id = conn.last_insert_rowid() as i32;
         
         Ok(EmailAccount {
-            id: account_id,
+            id: tx.last_insert_rowid() as i32,
             user_id: account.user_id,
             account_name: account.account_name,
             email_address: account.email_address,
@@ -339,33 +203,49 @@ impl Database {
             smtp_port: account.smtp_port,
             username: account.username,
             password_encrypted: account.password_encrypted,
+            auth_type: account.auth_type,
+            oauth_provider: account.oauth_provider,
+            smtp_security: account.smtp_security,
+            imap_security: account.imap_security,
+            tls_ca_certificate: account.tls_ca_certificate,
+            tls_accept_invalid_certs: account.tls_accept_invalid_certs,
             is_active: account.is_active.unwrap_or(true),
+            rate_limits: account.rate_limits,
             created_at: Utc::now(),
         })
     }
 
+    pub fn update_email_account_rate_limits(&self, user_id: i32, account_id: i32, limits: &RateLimits) -> Result<bool> {
+        let rows_affected = self.transaction(|tx| {
+            Ok(tx.execute(
+                r#"
+                UPDATE email_accounts
+                SET max_per_minute = ?1, max_per_hour = ?2, max_per_day = ?3, send_window_start = ?4, send_window_end = ?5
+                WHERE id = ?6 AND user_id = ?7
+                "#,
+                params![
+                    limits.max_per_minute,
+                    limits.max_per_hour,
+                    limits.max_per_day,
+                    limits.send_window_start,
+                    limits.send_window_end,
+                    account_id,
+                    user_id
+                ],
+            )?)
+        })?;
+
+        Ok(rows_affected > 0)
+    }
+
     pub fn get_email_accounts(&self, user_id: i32) -> Result<Vec<EmailAccount>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, account_name, email_address, imap_server, imap_port, smtp_server, smtp_port, username, password_encrypted, is_active, created_at FROM email_accounts WHERE user_id = ?1"
-        )?;
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM email_accounts WHERE user_id = ?1",
+            ACCOUNT_COLUMNS
+        ))?;
         
-        let account_iter = stmt.query_map([user_id], |row| {
-            Ok(EmailAccount {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                account_name: row.get(2)?,
-                email_address: row.get(3)?,
-                imap_server: row.get(4)?,
-                imap_port: row.get(5)?,
-                smtp_server: row.get(6)?,
-                smtp_port: row.get(7)?,
-                username: row.get(8)?,
-                password_encrypted: row.get(9)?,
-                is_active: row.get(10)?,
-                created_at: row.get(11)?,
-            })
-        })?;
+        let account_iter = stmt.query_map([user_id], Self::account_from_row)?;
 
         let mut accounts = Vec::new();
         for account in account_iter {
@@ -376,27 +256,13 @@ impl Database {
     }
 
     pub fn get_email_account(&self, user_id: i32, account_id: i32) -> Result<Option<EmailAccount>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT id, user_id, account_name, email_address, imap_server, imap_port, smtp_server, smtp_port, username, password_encrypted, is_active, created_at FROM email_accounts WHERE id = ?1 AND user_id = ?2"
-        )?;
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM email_accounts WHERE id = ?1 AND user_id = ?2",
+            ACCOUNT_COLUMNS
+        ))?;
         
-        let account_iter = stmt.query_map([account_id, user_id], |row| {
-            Ok(EmailAccount {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                account_name: row.get(2)?,
-                email_address: row.get(3)?,
-                imap_server: row.get(4)?,
-                imap_port: row.get(5)?,
-                smtp_server: row.get(6)?,
-                smtp_port: row.get(7)?,
-                username: row.get(8)?,
-                password_encrypted: row.get(9)?,
-                is_active: row.get(10)?,
-                created_at: row.get(11)?,
-            })
-        })?;
+        let account_iter = stmt.query_map([account_id, user_id], Self::account_from_row)?;
 
         for account in account_iter {
             return Ok(Some(account?));
@@ -405,28 +271,67 @@ impl Database {
         Ok(None)
     }
 
+    fn account_from_row(row: &rusqlite::Row) -> rusqlite::Result<EmailAccount> {
+        let security = |index: usize| -> rusqlite::Result<ConnectionSecurity> {
+            let value: String = row.get(index)?;
+            ConnectionSecurity::parse(&value).ok_or_else(|| rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                format!("unknown connection security '{}'", value).into(),
+            ))
+        };
+
+        Ok(EmailAccount {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            account_name: row.get(2)?,
+            email_address: row.get(3)?,
+            imap_server: row.get(4)?,
+            imap_port: row.get(5)?,
+            smtp_server: row.get(6)?,
+            smtp_port: row.get(7)?,
+            username: row.get(8)?,
+            password_encrypted: row.get(9)?,
+            is_active: row.get(10)?,
+            created_at: row.get(11)?,
+            rate_limits: RateLimits {
+                max_per_minute: row.get(12)?,
+                max_per_hour: row.get(13)?,
+                max_per_day: row.get(14)?,
+                send_window_start: row.get(15)?,
+                send_window_end: row.get(16)?,
+            },
+            auth_type: row.get(17)?,
+            oauth_provider: row.get(18)?,
+            smtp_security: security(19)?,
+            imap_security: security(20)?,
+            tls_ca_certificate: row.get(21)?,
+            tls_accept_invalid_certs: row.get(22)?,
+        })
+    }
+
     // Email template operations
     pub fn create_email_template(&self, template: CreateEmailTemplateWithUser) -> Result<EmailTemplate> {
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
-        conn.execute(
-            r#"
-            INSERT INTO email_templates (user_id, name, subject, body, template_type, created_at, updated_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-            "#,
-            params![
-                template.user_id,
-                &template.name,
-                &template.subject,
-                &template.body,
-                &template.template_type,
-                &now,
-                &now
-            ],
-        )?;
-
-        let template_id = conn.last_insert_rowid() as i32;
+        let template_id = self.transaction(|tx| {
+            tx.execute(
+                r#"
+                INSERT INTO email_templates (user_id, name, subject, body, template_type, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                "#,
+                params![
+                    template.user_id,
+                    &template.name,
+                    &template.subject,
+                    &template.body,
+                    &template.template_type,
+                    &now,
+                    &now
+                ],
+            )?;
+
+            Ok(tx.last_insert_rowid() as i32)
+        })?;
         
         Ok(EmailTemplate {
             id: template_id,
@@ -441,7 +346,7 @@ impl Database {
     }
 
     pub fn get_email_templates(&self, user_id: i32) -> Result<Vec<EmailTemplate>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, subject, body, template_type, created_at, updated_at FROM email_templates WHERE user_id = ?1"
         )?;
@@ -468,7 +373,7 @@ impl Database {
     }
 
     pub fn get_email_template(&self, template_id: i32, user_id: i32) -> Result<Option<EmailTemplate>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, name, subject, body, template_type, created_at, updated_at FROM email_templates WHERE id = ?1 AND user_id = ?2"
         )?;
@@ -496,32 +401,47 @@ impl Database {
     // Automation rule operations
     pub fn create_automation_rule(&self, rule: CreateAutomationRuleWithUser) -> Result<AutomationRule> {
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
         let keywords_json = serde_json::to_string(&rule.keywords)
             .map_err(|e| anyhow::anyhow!("Failed to serialize keywords: {}", e))?;
         let conditions_json = serde_json::to_string(&rule.conditions)
             .map_err(|e| anyhow::anyhow!("Failed to serialize conditions: {}", e))?;
         let actions_json = serde_json::to_string(&rule.actions)
             .map_err(|e| anyhow::anyhow!("Failed to serialize actions: {}", e))?;
-        
-        conn.execute(
-            r#"
-            INSERT INTO automation_rules (user_id, rule_name, keywords, conditions, actions, is_active, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-            "#,
-            params![
-                rule.user_id,
-                &rule.rule_name,
-                &keywords_json,
-                &conditions_json,
-                &actions_json,
-                rule.is_active.unwrap_or(true),
-                &now
-            ],
-        )?;
-
-        let rule_id = conn.last_insert_rowid() as i32;
+        let folders_json = serde_json::to_string(&rule.folders)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize folders: {}", e))?;
+        
+        let (rule_id, priority) = self.transaction(|tx| {
+            // New rules go after the user's existing ones unless a priority is given
+            let priority = match rule.priority {
+                Some(priority) => priority,
+                None => tx.query_row(
+                    "SELECT COALESCE(MAX(priority) + 1, 0) FROM automation_rules WHERE user_id = ?1",
+                    [rule.user_id],
+                    |row| row.get(0),
+                )?,
+            };
+
+            tx.execute(
+                r#"
+                INSERT INTO automation_rules (user_id, rule_name, keywords, conditions, actions, is_active, priority, stop_processing, folders, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                "#,
+                params![
+                    rule.user_id,
+                    &rule.rule_name,
+                    &keywords_json,
+                    &conditions_json,
+                    &actions_json,
+                    rule.is_active.unwrap_or(true),
+                    priority,
+                    rule.stop_processing,
+                    &folders_json,
+                    &now
+                ],
+            )?;
+
+            Ok((tx.last_insert_rowid() as i32, priority))
+        })?;
         
         Ok(AutomationRule {
             id: rule_id,
@@ -531,14 +451,19 @@ impl Database {
             conditions: rule.conditions,
             actions: rule.actions,
             is_active: rule.is_active.unwrap_or(true),
+            priority,
+            stop_processing: rule.stop_processing,
+            folders: rule.folders,
             created_at: Utc::now(),
         })
     }
 
+    /// Returns the user's rules in evaluation order: ascending priority, then creation order.
     pub fn get_automation_rules(&self, user_id: i32) -> Result<Vec<AutomationRule>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
-            "SELECT id, user_id, rule_name, keywords, conditions, actions, is_active, created_at FROM automation_rules WHERE user_id = ?1"
+            "SELECT id, user_id, rule_name, keywords, conditions, actions, is_active, priority, stop_processing, folders, created_at
+             FROM automation_rules WHERE user_id = ?1 ORDER BY priority, id"
         )?;
         
         let rule_iter = stmt.query_map([user_id], |row| {
@@ -554,7 +479,11 @@ impl Database {
                 actions: serde_json::from_str(&row.get::<_, String>(5)?)
                     .map_err(|_| rusqlite::Error::InvalidColumnType(5, "actions".to_string(), rusqlite::types::Type::Text))?,
                 is_active: row.get(6)?,
-                created_at: row.get(7)?,
+                priority: row.get(7)?,
+                stop_processing: row.get(8)?,
+                folders: serde_json::from_str(&row.get::<_, String>(9)?)
+                    .map_err(|_| rusqlite::Error::InvalidColumnType(9, "folders".to_string(), rusqlite::types::Type::Text))?,
+                created_at: row.get(10)?,
             })
         })?;
         
@@ -565,31 +494,70 @@ impl Database {
         Ok(rules)
     }
 
+    /// Renumbers the user's rules so they run in the order of `rule_ids`, which must list
+    /// every one of the user's rules exactly once.
+    pub fn reorder_automation_rules(&self, user_id: i32, rule_ids: &[i32]) -> Result<()> {
+        self.transaction(|tx| {
+            let mut stmt = tx.prepare("SELECT id FROM automation_rules WHERE user_id = ?1")?;
+            let mut existing = stmt.query_map([user_id], |row| row.get::<_, i32>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut requested = rule_ids.to_vec();
+            existing.sort_unstable();
+            requested.sort_unstable();
+
+            if existing != requested {
+                return Err(AppError::Validation("Rule order must list each of your rules exactly once".to_string()));
+            }
+
+            for (priority, rule_id) in rule_ids.iter().enumerate() {
+                tx.execute(
+                    "UPDATE automation_rules SET priority = ?1 WHERE id = ?2 AND user_id = ?3",
+                    params![priority as i32, rule_id, user_id],
+                )?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn set_automation_rule_stop_processing(&self, user_id: i32, rule_id: i32, stop_processing: bool) -> Result<bool> {
+        let rows_affected = self.transaction(|tx| {
+            Ok(tx.execute(
+                "UPDATE automation_rules SET stop_processing = ?1 WHERE id = ?2 AND user_id = ?3",
+                params![stop_processing, rule_id, user_id],
+            )?)
+        })?;
+
+        Ok(rows_affected > 0)
+    }
+
     // Email logging operations
     pub fn log_email(&self, log: CreateEmailLog) -> Result<EmailLog> {
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
-        conn.execute(
-            r#"
-            INSERT INTO email_logs (user_id, email_account_id, direction, recipient_email, sender_email, subject, status, error_message, sent_at, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-            "#,
-            params![
-                log.user_id,
-                log.email_account_id,
-                &log.direction,
-                &log.recipient_email,
-                &log.sender_email,
-                &log.subject,
-                &log.status,
-                &log.error_message,
-                &log.sent_at,
-                &now
-            ],
-        )?;
-
-        let log_id = conn.last_insert_rowid() as i32;
+        let log_id = self.transaction(|tx| {
+            tx.execute(
+                r#"
+                INSERT INTO email_logs (user_id, email_account_id, direction, recipient_email, sender_email, subject, status, error_message, sent_at, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                "#,
+                params![
+                    log.user_id,
+                    log.email_account_id,
+                    &log.direction,
+                    &log.recipient_email,
+                    &log.sender_email,
+                    &log.subject,
+                    &log.status,
+                    &log.error_message,
+                    &log.sent_at,
+                    &now
+                ],
+            )?;
+
+            Ok(tx.last_insert_rowid() as i32)
+        })?;
         
         Ok(EmailLog {
             id: log_id,
@@ -607,7 +575,7 @@ impl Database {
     }
 
     pub fn get_email_logs(&self, user_id: i32, limit: Option<i32>) -> Result<Vec<EmailLog>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let query = if let Some(limit) = limit {
             format!("SELECT id, user_id, email_account_id, direction, recipient_email, sender_email, subject, status, error_message, sent_at, created_at FROM email_logs WHERE user_id = ?1 ORDER BY created_at DESC LIMIT {}", limit)
         } else {
@@ -640,7 +608,7 @@ impl Database {
 
     // Scheduled email operations
     pub fn get_pending_scheduled_emails(&self) -> Result<Vec<ScheduledEmail>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let mut stmt = conn.prepare(
             "SELECT id, user_id, template_id, recipient_list, scheduled_time, recurrence_pattern, status, created_at FROM scheduled_emails WHERE status = 'pending' AND scheduled_time <= datetime('now')"
         )?;
@@ -675,39 +643,41 @@ impl Database {
     }
 
     pub fn update_scheduled_email_status(&self, email_id: i32, status: &str) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "UPDATE scheduled_emails SET status = ?1 WHERE id = ?2",
-            params![status, email_id],
-        )?;
+        self.transaction(|tx| {
+            tx.execute(
+                "UPDATE scheduled_emails SET status = ?1 WHERE id = ?2",
+                params![status, email_id],
+            )?;
+            Ok(())
+        })?;
         Ok(())
     }
 
     pub fn create_scheduled_email(&self, email: CreateScheduledEmailWithUser) -> Result<ScheduledEmail> {
         let now = Utc::now().to_rfc3339();
-        let conn = self.conn.lock().unwrap();
-        
         let recipient_list_json = serde_json::to_string(&email.recipient_list)
             .map_err(|e| anyhow::anyhow!("Failed to serialize recipient list: {}", e))?;
         let scheduled_time_str = email.scheduled_time.to_rfc3339();
         
-        conn.execute(
-            r#"
-            INSERT INTO scheduled_emails (user_id, template_id, recipient_list, scheduled_time, recurrence_pattern, status, created_at)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-            "#,
-            params![
-                email.user_id,
-                email.template_id,
-                &recipient_list_json,
-                &scheduled_time_str,
-                &email.recurrence_pattern,
-                "pending",
-                &now
-            ],
-        )?;
-        
-        let email_id = conn.last_insert_rowid() as i32;
+        let email_id = self.transaction(|tx| {
+            tx.execute(
+                r#"
+                INSERT INTO scheduled_emails (user_id, template_id, recipient_list, scheduled_time, recurrence_pattern, status, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                "#,
+                params![
+                    email.user_id,
+                    email.template_id,
+                    &recipient_list_json,
+                    &scheduled_time_str,
+                    &email.recurrence_pattern,
+                    "pending",
+                    &now
+                ],
+            )?;
+
+            Ok(tx.last_insert_rowid() as i32)
+        })?;
         
         Ok(ScheduledEmail {
             id: email_id,
@@ -723,7 +693,7 @@ impl Database {
 
     // Statistics operations
     pub fn get_email_stats(&self, user_id: i32) -> Result<EmailStats> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         
         let total_sent: i32 = conn.query_row(
             "SELECT COUNT(*) FROM email_logs WHERE user_id = ?1 AND direction = 'sent'",
@@ -758,7 +728,7 @@ impl Database {
     }
 
     pub fn get_recent_activity(&self, user_id: i32, limit: Option<i32>) -> Result<Vec<RecentActivity>> {
-        let conn = self.conn.lock().unwrap();
+        let conn = self.get_connection()?;
         let limit_value = limit.unwrap_or(10);
         
         let mut stmt = conn.prepare(
@@ -789,4 +759,142 @@ impl Database {
         
         Ok(activities)
     }
-}
\ No newline at end of file
+}
+
+/// Fixtures for the tests of modules that need a real, migrated database.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use std::ops::Deref;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// A database in its own temporary directory, which is removed on drop along with the
+    /// WAL files and anything else a test wrote there.
+    pub(crate) struct TempDatabase {
+        database: Arc<Database>,
+        dir: PathBuf,
+    }
+
+    impl TempDatabase {
+        pub(crate) fn shared(&self) -> Arc<Database> {
+            Arc::clone(&self.database)
+        }
+
+        pub(crate) fn dir(&self) -> &Path {
+            &self.dir
+        }
+    }
+
+    impl Deref for TempDatabase {
+        type Target = Database;
+
+        fn deref(&self) -> &Database {
+            &self.database
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    pub(crate) fn temp_database() -> TempDatabase {
+        let dir = std::env::temp_dir().join(format!("email_automation_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = Arc::new(Database::new(dir.join("test.db")).unwrap());
+        TempDatabase { database, dir }
+    }
+
+    /// Adds a bare password account for `user_id`, returning its id.
+    pub(crate) fn seed_account(database: &Database, user_id: i32, address: &str) -> i32 {
+        database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO email_accounts (user_id, account_name, email_address, username, password_encrypted)
+                 VALUES (?1, ?2, ?2, ?2, 'x')",
+                params![user_id, address],
+            )?;
+            Ok(tx.last_insert_rowid() as i32)
+        }).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::temp_database;
+
+    #[test]
+    fn test_transaction_rolls_back_on_error() {
+        let db = temp_database();
+
+        let result: Result<(), AppError> = db.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO users (username, email, password_hash) VALUES ('a', 'a@example.com', 'x')",
+                [],
+            )?;
+            Err(AppError::Validation("abort".to_string()))
+        });
+        assert!(result.is_err());
+
+        assert!(db.get_user_by_email("a@example.com").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rules_run_in_priority_order() {
+        let db = temp_database();
+        let user = db.create_user(CreateUser {
+            username: "rules".to_string(),
+            email: "rules@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+
+        let create = |name: &str, priority: Option<i32>| db.create_automation_rule(CreateAutomationRuleWithUser {
+            user_id: user.id,
+            rule_name: name.to_string(),
+            keywords: vec!["x".to_string()],
+            conditions: serde_json::json!(null),
+            actions: serde_json::json!([{"type": "flag"}]),
+            is_active: Some(true),
+            priority,
+            stop_processing: false,
+            folders: vec!["INBOX".to_string()],
+        }).unwrap().id;
+
+        let generic = create("generic", None);
+        let vip = create("vip", Some(-1));
+        let other = create("other", None);
+
+        let names = |db: &Database| db.get_automation_rules(user.id).unwrap()
+            .into_iter().map(|rule| rule.rule_name).collect::<Vec<_>>();
+        assert_eq!(names(&db), vec!["vip", "generic", "other"]);
+
+        db.reorder_automation_rules(user.id, &[other, generic, vip]).unwrap();
+        assert_eq!(names(&db), vec!["other", "generic", "vip"]);
+
+        assert!(db.reorder_automation_rules(user.id, &[other, generic]).is_err());
+        assert!(db.reorder_automation_rules(user.id, &[other, generic, vip, vip]).is_err());
+    }
+
+    #[test]
+    fn test_readers_are_independent_of_writer() {
+        let db = temp_database();
+
+        // A reader checked out while the writer holds a transaction still sees committed data
+        db.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO users (username, email, password_hash) VALUES ('b', 'b@example.com', 'x')",
+                [],
+            )?;
+            Ok(())
+        }).unwrap();
+
+        db.transaction(|tx| {
+            tx.execute("UPDATE users SET username = 'c' WHERE email = 'b@example.com'", [])?;
+            let user = db.get_user_by_email("b@example.com").unwrap().unwrap();
+            assert_eq!(user.username, "b");
+            Ok(())
+        }).unwrap();
+    }
+}
@@ -1,20 +1,54 @@
-use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
 use lettre::{Message, SmtpTransport, Transport};
-use lettre::message::{header::ContentType, Mailbox, MultiPart, SinglePart};
-use imap::Session;
-use std::net::TcpStream;
-use native_tls::{TlsConnector, TlsStream};
+use lettre::message::{header::{ContentType, Header, HeaderName, HeaderValue}, Mailbox, MultiPart, SinglePart};
 use crate::models::*;
-use anyhow::Result;
-use std::collections::HashMap;
+use crate::connection::{self, ImapSession};
+use crate::oauth;
+use anyhow::{Context as _, Result};
 use regex::Regex;
 use tera::{Tera, Context};
-use chrono::{Utc, Datelike, Timelike};
+use chrono::Utc;
 
 pub struct EmailService {
     template_engine: Tera,
 }
 
+/// The RFC 3834 `Auto-Submitted` header, which tells other responders not to answer us.
+#[derive(Clone)]
+struct AutoSubmitted(String);
+
+impl Header for AutoSubmitted {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("Auto-Submitted")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.trim().to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+/// A message ready to go out over its account's SMTP transport.
+pub struct PreparedEmail {
+    mailer: SmtpTransport,
+    message: Message,
+}
+
+impl PreparedEmail {
+    /// Connects and sends. This blocks on network I/O, so async callers run it in
+    /// `spawn_blocking`.
+    pub fn send(self) -> Result<()> {
+        // Keep the SMTP error as the source so callers can tell transient from permanent failures
+        self.mailer.send(&self.message)
+            .context("Failed to send email")?;
+        
+        Ok(())
+    }
+}
+
 impl EmailService {
     pub fn new() -> Self {
         let mut tera = Tera::new("templates/**/*").unwrap_or_else(|_| Tera::default());
@@ -28,8 +62,11 @@ impl EmailService {
         }
     }
 
-    pub async fn test_smtp_connection(&self, account: &EmailAccount, password: &str) -> Result<ConnectionTest> {
-        match self.create_smtp_transport(account, password) {
+    /// Connects to the account's SMTP server and reports whether it accepted us. The
+    /// connection tests block on network I/O and need no service state, so async callers
+    /// run them in `spawn_blocking` without taking the service lock.
+    pub fn test_smtp_connection(account: &EmailAccount, credential: &MailCredential) -> Result<ConnectionTest> {
+        match Self::create_smtp_transport(account, credential) {
             Ok(mailer) => {
                 match mailer.test_connection() {
                     Ok(true) => Ok(ConnectionTest {
@@ -53,18 +90,19 @@ impl EmailService {
         }
     }
 
-    pub async fn test_imap_connection(&self, account: &EmailAccount, password: &str) -> Result<ConnectionTest> {
-        let imap_server = account.imap_server.as_ref().ok_or_else(|| {
-            anyhow::anyhow!("IMAP server not configured")
-        })?;
-        
-        let imap_port = account.imap_port.unwrap_or(993);
+    pub fn test_imap_connection(account: &EmailAccount, credential: &MailCredential) -> Result<ConnectionTest> {
+        if account.imap_server.is_none() {
+            anyhow::bail!("IMAP server not configured");
+        }
         
-        match self.create_imap_session(imap_server, imap_port as u16, &account.username, password) {
-            Ok(_) => Ok(ConnectionTest {
-                success: true,
-                message: "IMAP connection successful".to_string(),
-            }),
+        match Self::create_imap_session(account, credential) {
+            Ok(mut session) => {
+                let _ = session.logout();
+                Ok(ConnectionTest {
+                    success: true,
+                    message: "IMAP connection successful".to_string(),
+                })
+            }
             Err(e) => Ok(ConnectionTest {
                 success: false,
                 message: format!("IMAP connection error: {}", e),
@@ -72,12 +110,14 @@ impl EmailService {
         }
     }
 
-    pub async fn send_email(&self, account: &EmailAccount, password: &str, email: &EmailMessage) -> Result<()> {
-        let mailer = self.create_smtp_transport(account, password)?;
+    /// Builds the message and an SMTP transport for it. Nothing touches the network until
+    /// `PreparedEmail::send`, so this is cheap enough to do while holding the service lock.
+    pub fn prepare_email(&self, account: &EmailAccount, credential: &MailCredential, email: &EmailMessage) -> Result<PreparedEmail> {
+        let mailer = Self::create_smtp_transport(account, credential)?;
         
         let from_mailbox: Mailbox = format!("{} <{}>", account.account_name, account.email_address)
             .parse()
-            .map_err(|e| anyhow::anyhow!("Invalid from address: {}", e))?;
+            .context("Invalid from address")?;
         
         let mut message_builder = Message::builder()
             .from(from_mailbox)
@@ -86,7 +126,7 @@ impl EmailService {
         // Add recipients
         for to_addr in &email.to {
             let to_mailbox: Mailbox = to_addr.parse()
-                .map_err(|e| anyhow::anyhow!("Invalid to address {}: {}", to_addr, e))?;
+                .with_context(|| format!("Invalid to address {}", to_addr))?;
             message_builder = message_builder.to(to_mailbox);
         }
         
@@ -94,7 +134,7 @@ impl EmailService {
         if let Some(cc_list) = &email.cc {
             for cc_addr in cc_list {
                 let cc_mailbox: Mailbox = cc_addr.parse()
-                    .map_err(|e| anyhow::anyhow!("Invalid CC address {}: {}", cc_addr, e))?;
+                    .with_context(|| format!("Invalid CC address {}", cc_addr))?;
                 message_builder = message_builder.cc(cc_mailbox);
             }
         }
@@ -103,11 +143,28 @@ impl EmailService {
         if let Some(bcc_list) = &email.bcc {
             for bcc_addr in bcc_list {
                 let bcc_mailbox: Mailbox = bcc_addr.parse()
-                    .map_err(|e| anyhow::anyhow!("Invalid BCC address {}: {}", bcc_addr, e))?;
+                    .with_context(|| format!("Invalid BCC address {}", bcc_addr))?;
                 message_builder = message_builder.bcc(bcc_mailbox);
             }
         }
         
+        if let Some(message_id) = &email.message_id {
+            message_builder = message_builder.message_id(Some(message_id.clone()));
+        }
+        
+        // Thread replies under the message they answer
+        if let Some(in_reply_to) = &email.in_reply_to {
+            message_builder = message_builder.in_reply_to(in_reply_to.clone());
+        }
+        
+        if let Some(references) = &email.references {
+            message_builder = message_builder.references(references.clone());
+        }
+        
+        if let Some(auto_submitted) = &email.auto_submitted {
+            message_builder = message_builder.header(AutoSubmitted(auto_submitted.clone()));
+        }
+        
         // Create message body
         let message = if email.body.contains("<html>") || email.body.contains("<HTML>") {
             // HTML email
@@ -131,30 +188,11 @@ impl EmailService {
                 .body(email.body.clone())?
         };
         
-        mailer.send(&message)
-            .map_err(|e| anyhow::anyhow!("Failed to send email: {}", e))?;
-        
-        Ok(())
+        Ok(PreparedEmail { mailer, message })
     }
 
-    pub async fn send_batch_emails(&mut self, account: &EmailAccount, password: &str, template: &EmailTemplate, recipients: &[RecipientData]) -> Result<Vec<String>> {
-        let mut results = Vec::new();
-        let mailer = self.create_smtp_transport(account, password)?;
-        
-        for recipient in recipients.iter() {
-            match self.send_templated_email(&mailer, account, template, recipient).await {
-                Ok(_) => results.push(format!("Success: {}", recipient.email)),
-                Err(e) => results.push(format!("Failed {}: {}", recipient.email, e)),
-            }
-            
-            // Add delay to prevent rate limiting
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        }
-        
-        Ok(results)
-    }
-
-    async fn send_templated_email(&mut self, mailer: &SmtpTransport, account: &EmailAccount, template: &EmailTemplate, recipient: &RecipientData) -> Result<()> {
+    /// Renders a template for one recipient, returning the personalized subject and body.
+    pub fn render_template(&mut self, template: &EmailTemplate, recipient: &RecipientData) -> (String, String) {
         let mut context = Context::new();
         
         // Add recipient variables to context
@@ -167,174 +205,63 @@ impl EmailService {
         context.insert("date", &Utc::now().format("%Y-%m-%d").to_string());
         
         // Render subject and body
-        let subject = if let Some(subj) = &template.subject {
-            self.template_engine.render_str(subj, &context)
-                .unwrap_or_else(|_| subj.clone())
-        } else {
-            "No Subject".to_string()
-        };
+        let subject = template.subject.as_ref()
+            .map(|subj| self.render_or_raw(subj, &context))
+            .unwrap_or_else(|| "No Subject".to_string());
         
-        let body = if let Some(body_template) = &template.body {
-            self.template_engine.render_str(body_template, &context)
-                .unwrap_or_else(|_| body_template.clone())
-        } else {
-            "No Content".to_string()
-        };
-        
-        let from_mailbox: Mailbox = format!("{} <{}>", account.account_name, account.email_address)
-            .parse()
-            .map_err(|e| anyhow::anyhow!("Invalid from address: {}", e))?;
+        let body = template.body.as_ref()
+            .map(|body_template| self.render_or_raw(body_template, &context))
+            .unwrap_or_else(|| "No Content".to_string());
         
-        let to_mailbox: Mailbox = recipient.email.parse()
-            .map_err(|e| anyhow::anyhow!("Invalid to address: {}", e))?;
-        
-        let message = Message::builder()
-            .from(from_mailbox)
-            .to(to_mailbox)
-            .subject(subject)
-            .body(body)?;
-        
-        mailer.send(&message)
-            .map_err(|e| anyhow::anyhow!("Failed to send email: {}", e))?;
-        
-        Ok(())
+        (subject, body)
     }
 
-    pub async fn check_emails(&self, account: &EmailAccount, password: &str) -> Result<Vec<EmailMessage>> {
-        let imap_server = account.imap_server.as_ref().ok_or_else(|| {
-            anyhow::anyhow!("IMAP server not configured")
-        })?;
-        
-        let imap_port = account.imap_port.unwrap_or(993);
-        let mut session = self.create_imap_session(imap_server, imap_port as u16, &account.username, password)?;
+    /// Renders an auto-reply template with the original message's sender, subject and body.
+    pub fn render_reply(&mut self, template: &EmailTemplate, original: &InboxEmail, sender_email: &str) -> (String, String) {
+        let mut context = Context::new();
+        context.insert("sender", &original.sender);
+        context.insert("sender_email", sender_email);
+        context.insert("subject", &original.subject);
+        context.insert("body", &original.body);
+        context.insert("date", &Utc::now().format("%Y-%m-%d").to_string());
         
-        // Select INBOX
-        session.select("INBOX")?;
+        let subject = template.subject.as_ref()
+            .map(|subj| self.render_or_raw(subj, &context))
+            .unwrap_or_else(|| format!("Re: {}", original.subject));
         
-        // Search for unseen emails
-        let messages = session.search("UNSEEN")?;
-        let mut emails = Vec::new();
+        let body = template.body.as_ref()
+            .map(|body_template| self.render_or_raw(body_template, &context))
+            .unwrap_or_else(|| "Thank you for your email. This is an automated response.".to_string());
         
-        for msg_id in messages.iter().take(10) { // Limit to 10 recent emails
-            if let Ok(messages) = session.fetch(msg_id.to_string(), "(ENVELOPE BODY[TEXT])") {
-                for message in messages.iter() {
-                    if let Some(envelope) = message.envelope() {
-                        let subject = envelope.subject
-                            .and_then(|s| std::str::from_utf8(s).ok())
-                            .unwrap_or("No Subject")
-                            .to_string();
-                        
-                        let _from = envelope.from
-                            .as_ref()
-                            .and_then(|addrs| addrs.first())
-                            .and_then(|addr| addr.mailbox)
-                            .and_then(|mb| std::str::from_utf8(mb).ok())
-                            .unwrap_or("Unknown")
-                            .to_string();
-                        
-                        let body = message.text()
-                            .and_then(|b| std::str::from_utf8(b).ok())
-                            .unwrap_or("No Content")
-                            .to_string();
-                        
-                        emails.push(EmailMessage {
-                            to: vec![account.email_address.clone()],
-                            cc: None,
-                            bcc: None,
-                            subject,
-                            body,
-                            attachments: None,
-                        });
-                    }
-                }
-            }
-        }
-        
-        session.logout()?;
-        Ok(emails)
+        (subject, body)
     }
 
-    pub async fn process_automation_rules(&self, email: &EmailMessage, rules: &[AutomationRule]) -> Vec<serde_json::Value> {
-        let mut triggered_actions = Vec::new();
-        
-        for rule in rules {
-            if !rule.is_active {
-                continue;
-            }
-            
-            let mut rule_triggered = false;
-            
-            // Check keywords in subject and body
-            let email_content = format!("{} {}", email.subject.to_lowercase(), email.body.to_lowercase());
-            
-            for keyword in &rule.keywords {
-                if email_content.contains(&keyword.to_lowercase()) {
-                    rule_triggered = true;
-                    break;
-                }
-            }
-            
-            // Check additional conditions
-            if rule_triggered {
-                if let Ok(conditions) = serde_json::from_value::<HashMap<String, serde_json::Value>>(rule.conditions.clone()) {
-                    // Check sender conditions
-                    if let Some(sender_pattern) = conditions.get("sender_pattern") {
-                        if let Some(pattern_str) = sender_pattern.as_str() {
-                            if let Ok(regex) = Regex::new(pattern_str) {
-                                if let Some(from_addr) = email.to.first() {
-                                    if !regex.is_match(from_addr) {
-                                        rule_triggered = false;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    
-                    // Check time conditions (business hours, etc.)
-                    if let Some(time_condition) = conditions.get("business_hours_only") {
-                        if time_condition.as_bool().unwrap_or(false) {
-                            let now = Utc::now();
-                            let hour = now.hour();
-                            let weekday = now.weekday().number_from_monday();
-                            
-                            if weekday > 5 || hour < 9 || hour > 17 {
-                                rule_triggered = false;
-                            }
-                        }
-                    }
-                }
-            }
-            
-            if rule_triggered {
-                triggered_actions.push(rule.actions.clone());
-            }
-        }
-        
-        triggered_actions
+    // A template with a syntax error is sent as written rather than dropped
+    fn render_or_raw(&mut self, source: &str, context: &Context) -> String {
+        self.template_engine.render_str(source, context)
+            .unwrap_or_else(|_| source.to_string())
     }
 
-    fn create_smtp_transport(&self, account: &EmailAccount, password: &str) -> Result<SmtpTransport> {
-        let smtp_server = account.smtp_server.as_ref().ok_or_else(|| {
-            anyhow::anyhow!("SMTP server not configured")
-        })?;
-        
-        let smtp_port = account.smtp_port.unwrap_or(587);
-        
-        let creds = Credentials::new(account.username.clone(), password.to_string());
-        
-        let mailer = SmtpTransport::relay(smtp_server)?
-            .port(smtp_port as u16)
-            .credentials(creds)
-            .build();
+    fn create_smtp_transport(account: &EmailAccount, credential: &MailCredential) -> Result<SmtpTransport> {
+        let builder = connection::smtp_transport_builder(account)?;
+        
+        let builder = match credential {
+            MailCredential::Password(password) => builder
+                .credentials(Credentials::new(account.username.clone(), password.clone())),
+            // lettre only speaks XOAUTH2, which the providers offering OAUTHBEARER accept as well
+            MailCredential::OAuth2 { access_token, .. } => builder
+                .credentials(Credentials::new(account.username.clone(), access_token.clone()))
+                .authentication(vec![Mechanism::Xoauth2]),
+        };
         
-        Ok(mailer)
+        Ok(builder.build())
     }
 
-    fn create_imap_session(&self, server: &str, port: u16, username: &str, password: &str) -> Result<Session<TlsStream<TcpStream>>> {
-        let tls = TlsConnector::builder().build()?;
-        let client = imap::connect((server, port), server, &tls)?;
-        let session = client.login(username, password)
-            .map_err(|e| anyhow::anyhow!("IMAP login failed: {:?}", e.0))?;
+    fn create_imap_session(account: &EmailAccount, credential: &MailCredential) -> Result<ImapSession> {
+        let client = connection::connect_imap(account)?;
+        let server = account.imap_server.as_deref().unwrap_or_default();
+        let port = account.imap_port.unwrap_or(993) as u16;
+        let session = oauth::imap_login(client, &account.username, server, port, credential)?;
         
         Ok(session)
     }
@@ -359,9 +286,50 @@ mod tests {
         assert_eq!(text, "HelloWorld");
     }
 
+    #[test]
+    fn test_reply_rendering_uses_original_message() {
+        let mut service = EmailService::new();
+        let template = EmailTemplate {
+            id: 1,
+            user_id: 1,
+            name: "Out of office".to_string(),
+            subject: Some("Re: {{ subject }}".to_string()),
+            body: Some("Hi {{ sender_email }}, thanks for writing.".to_string()),
+            template_type: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let original = InboxEmail {
+            id: "42".to_string(),
+            message_id: Some("<abc@example.com>".to_string()),
+            in_reply_to: None,
+            references: None,
+            subject: "Invoice".to_string(),
+            sender: "Jane <jane@example.com>".to_string(),
+            to: vec!["support@example.com".to_string()],
+            cc: Vec::new(),
+            reply_to: Vec::new(),
+            received_at: Utc::now(),
+            body: "Please see attached.".to_string(),
+            html_body: None,
+            headers: Vec::new(),
+            attachments: Vec::new(),
+            attachment_types: Vec::new(),
+            size: 0,
+            is_read: false,
+            is_auto_generated: false,
+            automation_trace: Vec::new(),
+            stored_id: None,
+        };
+
+        let (subject, body) = service.render_reply(&template, &original, "jane@example.com");
+        assert_eq!(subject, "Re: Invoice");
+        assert_eq!(body, "Hi jane@example.com, thanks for writing.");
+    }
+
     #[test]
     fn test_template_rendering() {
-        let service = EmailService::new();
+        let mut service = EmailService::new();
         let mut context = Context::new();
         context.insert("name", "John");
         
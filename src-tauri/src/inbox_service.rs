@@ -1,110 +1,144 @@
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
 use chrono::Utc;
 use log::{info, error, warn};
-use imap::Session;
-use native_tls::TlsStream;
-use std::net::TcpStream;
+use imap::types::NameAttribute;
+use mailparse::MailHeaderMap;
+use regex::Regex;
+use rusqlite::{params, OptionalExtension};
 use crate::models::*;
 use crate::database::Database;
-use crate::email_service::EmailService;
-use crate::attachment_service::AttachmentService;
+use crate::automation::AutomationEngine;
+use crate::mime::MimeMessage;
+use crate::message_store::{MessageLocation, MessageStore};
+use crate::oauth;
+use crate::credentials::CredentialService;
+use crate::connection::{self, ImapSession};
+
+pub const DEFAULT_AUTO_REPLY_COOLDOWN: i32 = 86400; // seconds
+const FETCH_BATCH_SIZE: usize = 50;
+
+static UIDVALIDITY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[UIDVALIDITY (\d+)\]").unwrap());
+static UIDNEXT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[UIDNEXT (\d+)\]").unwrap());
+static HIGHESTMODSEQ: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[HIGHESTMODSEQ (\d+)\]").unwrap());
+
+
+/// Where the last check of a folder left off.
+struct FolderSync {
+    uid_validity: u32,
+    last_uid: u32,
+    highest_modseq: Option<u64>,
+}
+
+/// A message as fetched from the server, before it is stored and handed to the rules.
+struct FetchedMessage {
+    uid: u32,
+    email: InboxEmail,
+    raw: Vec<u8>,
+    flags: Vec<String>,
+}
+
+/// The new mail in a folder, fetched but not yet run through the rules.
+struct FolderFetch {
+    status: FolderStatus,
+    /// The sync position once every message has been processed
+    last_uid: u32,
+    messages: Vec<(u32, InboxEmail)>,
+}
+
+/// What SELECT reported about a folder.
+#[derive(Debug, PartialEq)]
+struct FolderStatus {
+    uid_validity: u32,
+    uid_next: Option<u32>,
+    highest_modseq: Option<u64>,
+}
 
 pub struct InboxService {
     database: Arc<Database>,
-    email_service: Arc<Mutex<EmailService>>,
-    attachment_service: Arc<AttachmentService>,
+    automation: Arc<AutomationEngine>,
+    message_store: Arc<MessageStore>,
+    credential_service: Arc<CredentialService>,
+    // One per account, held for a whole check so the manual check, the poller and the
+    // IDLE watcher never fetch and process the same UIDs twice
+    check_locks: Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>,
 }
 
 impl InboxService {
     pub fn new(
         database: Arc<Database>,
-        email_service: Arc<Mutex<EmailService>>,
-        attachment_service: Arc<AttachmentService>,
+        automation: Arc<AutomationEngine>,
+        message_store: Arc<MessageStore>,
+        credential_service: Arc<CredentialService>,
     ) -> Self {
         Self {
             database,
-            email_service,
-            attachment_service,
+            automation,
+            message_store,
+            credential_service,
+            check_locks: Mutex::new(HashMap::new()),
         }
     }
     
     // Inbox Monitor Management
     pub fn create_inbox_monitor(&self, user_id: i32, monitor_data: CreateInboxMonitor) -> Result<InboxMonitor, AppError> {
-        let conn = self.database.get_connection();
-        
-        // Verify the email account belongs to the user
-        let account_exists = conn.query_row(
-            "SELECT 1 FROM email_accounts WHERE id = ?1 AND user_id = ?2",
-            [monitor_data.email_account_id, user_id],
-            |_| Ok(())
-        );
-        
-        if account_exists.is_err() {
-            return Err(AppError::NotFound("Email account not found".to_string()));
-        }
-        
         let check_interval = monitor_data.check_interval.unwrap_or(300); // Default 5 minutes
-        
-        let mut stmt = conn.prepare(
-            "INSERT INTO inbox_monitors (user_id, email_account_id, check_interval, auto_reply_template_id)
-             VALUES (?1, ?2, ?3, ?4)"
-        )?;
-        
-        let monitor_id = stmt.insert((
-            user_id,
-            monitor_data.email_account_id,
-            check_interval,
-            monitor_data.auto_reply_template_id,
-        ))?;
+        let auto_reply_cooldown = monitor_data.auto_reply_cooldown.unwrap_or(DEFAULT_AUTO_REPLY_COOLDOWN);
+        let folders = Self::folders_json(monitor_data.folders.as_deref())?;
+        
+        let monitor_id = self.database.transaction(|tx| {
+            // Verify the email account belongs to the user
+            let account_exists = tx.query_row(
+                "SELECT 1 FROM email_accounts WHERE id = ?1 AND user_id = ?2",
+                [monitor_data.email_account_id, user_id],
+                |_| Ok(())
+            );
+            
+            if account_exists.is_err() {
+                return Err(AppError::NotFound("Email account not found".to_string()));
+            }
+            
+            let mut stmt = tx.prepare(
+                "INSERT INTO inbox_monitors (user_id, email_account_id, check_interval, auto_reply_template_id, auto_reply_cooldown, use_idle, folders)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+            )?;
+            
+            Ok(stmt.insert((
+                user_id,
+                monitor_data.email_account_id,
+                check_interval,
+                monitor_data.auto_reply_template_id,
+                auto_reply_cooldown,
+                monitor_data.use_idle,
+                &folders,
+            ))?)
+        })?;
         
         self.get_inbox_monitor(user_id, monitor_id as i32)
     }
     
     pub fn get_inbox_monitor(&self, user_id: i32, monitor_id: i32) -> Result<InboxMonitor, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
-            "SELECT id, user_id, email_account_id, is_active, check_interval, last_check, auto_reply_template_id, created_at
+            "SELECT id, user_id, email_account_id, is_active, check_interval, last_check, auto_reply_template_id, auto_reply_cooldown, use_idle, folders, created_at
              FROM inbox_monitors WHERE id = ?1 AND user_id = ?2"
         )?;
         
-        let monitor = stmt.query_row([monitor_id, user_id], |row| {
-            Ok(InboxMonitor {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                email_account_id: row.get(2)?,
-                is_active: row.get(3)?,
-                check_interval: row.get(4)?,
-                last_check: row.get(5)?,
-                auto_reply_template_id: row.get(6)?,
-                created_at: row.get(7)?,
-            })
-        })?;
+        let monitor = stmt.query_row([monitor_id, user_id], Self::monitor_from_row)?;
         
         Ok(monitor)
     }
     
     pub fn get_user_inbox_monitors(&self, user_id: i32) -> Result<Vec<InboxMonitor>, AppError> {
-        let conn = self.database.get_connection();
+        let conn = self.database.get_connection()?;
         
         let mut stmt = conn.prepare(
-            "SELECT id, user_id, email_account_id, is_active, check_interval, last_check, auto_reply_template_id, created_at
+            "SELECT id, user_id, email_account_id, is_active, check_interval, last_check, auto_reply_template_id, auto_reply_cooldown, use_idle, folders, created_at
              FROM inbox_monitors WHERE user_id = ?1 ORDER BY created_at DESC"
         )?;
         
-        let monitor_iter = stmt.query_map([user_id], |row| {
-            Ok(InboxMonitor {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                email_account_id: row.get(2)?,
-                is_active: row.get(3)?,
-                check_interval: row.get(4)?,
-                last_check: row.get(5)?,
-                auto_reply_template_id: row.get(6)?,
-                created_at: row.get(7)?,
-            })
-        })?;
+        let monitor_iter = stmt.query_map([user_id], Self::monitor_from_row)?;
         
         let mut monitors = Vec::new();
         for monitor in monitor_iter {
@@ -114,44 +148,81 @@ impl InboxService {
         Ok(monitors)
     }
     
-    pub fn update_inbox_monitor(&self, user_id: i32, monitor_id: i32, monitor_data: CreateInboxMonitor) -> Result<InboxMonitor, AppError> {
-        let conn = self.database.get_connection();
-        
-        let check_interval = monitor_data.check_interval.unwrap_or(300);
+    /// Returns the active monitors of every user, for the background monitor service.
+    pub fn get_active_inbox_monitors(&self) -> Result<Vec<InboxMonitor>, AppError> {
+        let conn = self.database.get_connection()?;
         
-        conn.execute(
-            "UPDATE inbox_monitors SET email_account_id = ?1, check_interval = ?2, auto_reply_template_id = ?3
-             WHERE id = ?4 AND user_id = ?5",
-            (
-                monitor_data.email_account_id,
-                check_interval,
-                monitor_data.auto_reply_template_id,
-                monitor_id,
-                user_id,
-            ),
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, email_account_id, is_active, check_interval, last_check, auto_reply_template_id, auto_reply_cooldown, use_idle, folders, created_at
+             FROM inbox_monitors WHERE is_active = 1"
         )?;
         
+        let monitor_iter = stmt.query_map([], Self::monitor_from_row)?;
+        
+        let mut monitors = Vec::new();
+        for monitor in monitor_iter {
+            monitors.push(monitor?);
+        }
+        
+        Ok(monitors)
+    }
+    
+    pub fn update_inbox_monitor(&self, user_id: i32, monitor_id: i32, monitor_data: CreateInboxMonitor) -> Result<InboxMonitor, AppError> {
+        let check_interval = monitor_data.check_interval.unwrap_or(300);
+        let auto_reply_cooldown = monitor_data.auto_reply_cooldown.unwrap_or(DEFAULT_AUTO_REPLY_COOLDOWN);
+        let folders = Self::folders_json(monitor_data.folders.as_deref())?;
+        
+        self.database.transaction(|tx| {
+            tx.execute(
+                "UPDATE inbox_monitors SET email_account_id = ?1, check_interval = ?2, auto_reply_template_id = ?3, auto_reply_cooldown = ?4, use_idle = ?5, folders = ?6
+                 WHERE id = ?7 AND user_id = ?8",
+                (
+                    monitor_data.email_account_id,
+                    check_interval,
+                    monitor_data.auto_reply_template_id,
+                    auto_reply_cooldown,
+                    monitor_data.use_idle,
+                    &folders,
+                    monitor_id,
+                    user_id,
+                ),
+            )?;
+            Ok(())
+        })?;
+        
         self.get_inbox_monitor(user_id, monitor_id)
     }
     
     pub fn toggle_inbox_monitor(&self, user_id: i32, monitor_id: i32, is_active: bool) -> Result<InboxMonitor, AppError> {
-        let conn = self.database.get_connection();
-        
-        conn.execute(
-            "UPDATE inbox_monitors SET is_active = ?1 WHERE id = ?2 AND user_id = ?3",
-            [is_active as i32, monitor_id, user_id],
-        )?;
+        self.database.transaction(|tx| {
+            tx.execute(
+                "UPDATE inbox_monitors SET is_active = ?1 WHERE id = ?2 AND user_id = ?3",
+                [is_active as i32, monitor_id, user_id],
+            )?;
+            Ok(())
+        })?;
         
         self.get_inbox_monitor(user_id, monitor_id)
     }
     
+    /// Pauses or resumes every monitor of the user, returning how many there are. The
+    /// monitor service picks the change up on its next tick.
+    pub fn set_user_monitors_active(&self, user_id: i32, is_active: bool) -> Result<usize, AppError> {
+        self.database.transaction(|tx| {
+            Ok(tx.execute(
+                "UPDATE inbox_monitors SET is_active = ?1 WHERE user_id = ?2",
+                [is_active as i32, user_id],
+            )?)
+        })
+    }
+    
     pub fn delete_inbox_monitor(&self, user_id: i32, monitor_id: i32) -> Result<(), AppError> {
-        let conn = self.database.get_connection();
-        
-        let rows_affected = conn.execute(
-            "DELETE FROM inbox_monitors WHERE id = ?1 AND user_id = ?2",
-            [monitor_id, user_id],
-        )?;
+        let rows_affected = self.database.transaction(|tx| {
+            Ok(tx.execute(
+                "DELETE FROM inbox_monitors WHERE id = ?1 AND user_id = ?2",
+                [monitor_id, user_id],
+            )?)
+        })?;
         
         if rows_affected == 0 {
             return Err(AppError::NotFound("Inbox monitor not found".to_string()));
@@ -161,286 +232,571 @@ impl InboxService {
         Ok(())
     }
     
+    fn monitor_from_row(row: &rusqlite::Row) -> rusqlite::Result<InboxMonitor> {
+        Ok(InboxMonitor {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            email_account_id: row.get(2)?,
+            is_active: row.get(3)?,
+            check_interval: row.get(4)?,
+            last_check: row.get(5)?,
+            auto_reply_template_id: row.get(6)?,
+            auto_reply_cooldown: row.get(7)?,
+            use_idle: row.get(8)?,
+            folders: serde_json::from_str(&row.get::<_, String>(9)?)
+                .map_err(|_| rusqlite::Error::InvalidColumnType(9, "folders".to_string(), rusqlite::types::Type::Text))?,
+            created_at: row.get(10)?,
+        })
+    }
+    
+    fn folders_json(folders: Option<&[String]>) -> Result<String, AppError> {
+        let folders = AutomationEngine::validate_folders(folders.map(|folders| folders.to_vec()))?;
+        serde_json::to_string(&folders)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize folders: {}", e)))
+    }
+    
     // Email checking functionality
+    /// Checks every folder the account's monitor covers (INBOX without a monitor) and runs
+    /// the rules on new mail while its folder is selected. A folder that fails is logged
+    /// and skipped; the check only fails if no folder could be checked.
+    ///
+    /// Checks of the same account run one at a time. The sync position moves past each
+    /// message once its rules have run, so a check cut short resumes where it stopped.
     pub async fn check_inbox(&self, user_id: i32, account_id: i32) -> Result<Vec<InboxEmail>, AppError> {
-        // Get email account details
-        let account_data = {
-            let conn = self.database.get_connection();
-            let mut stmt = conn.prepare(
-                "SELECT email_address, imap_server, imap_port, username, password_encrypted
-                 FROM email_accounts WHERE id = ?1 AND user_id = ?2"
-            )?;
-            
-            stmt.query_row([account_id, user_id], |row| {
-                Ok((
-                    row.get::<_, String>(0)?, // email_address
-                    row.get::<_, Option<String>>(1)?, // imap_server
-                    row.get::<_, Option<i32>>(2)?, // imap_port
-                    row.get::<_, String>(3)?, // username
-                    row.get::<_, String>(4)?, // password_encrypted
-                ))
-            })?
+        let check_lock = Arc::clone(
+            self.check_locks.lock().unwrap_or_else(|e| e.into_inner()).entry(account_id).or_default()
+        );
+        let _checking = check_lock.lock().await;
+        
+        // The monitor's folders, and an active monitor's default auto-reply for the messages no rule matched
+        let (patterns, default_template_id) = {
+            let conn = self.database.get_connection()?;
+            conn.query_row(
+                "SELECT folders, CASE WHEN is_active = 1 THEN auto_reply_template_id END
+                 FROM inbox_monitors WHERE email_account_id = ?1 AND user_id = ?2
+                 ORDER BY is_active DESC, id LIMIT 1",
+                [account_id, user_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i32>>(1)?)),
+            ).optional()?
+                .map(|(folders, template_id)| (serde_json::from_str::<Vec<String>>(&folders).unwrap_or_default(), template_id))
+                .unwrap_or_default()
         };
+        let patterns = if patterns.is_empty() { vec!["INBOX".to_string()] } else { patterns };
         
-        let (email_address, imap_server, imap_port, username, password_encrypted) = account_data;
+        // Connect to IMAP server; the session stays open so rule actions can act on the messages
+        let mut session = self.open_session(user_id, account_id).await?;
+        let folders = Self::resolve_folders(&mut session, &patterns)?;
         
-        let imap_server = imap_server.ok_or_else(|| AppError::Config("IMAP server not configured".to_string()))?;
-        let imap_port = imap_port.unwrap_or(993);
+        let mut emails = Vec::new();
+        let mut failures = Vec::new();
+        
+        for folder in &folders {
+            let fetched = match self.fetch_emails_from_imap(&mut session, user_id, account_id, folder) {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    error!("Failed to check {} for account {}: {}", folder, account_id, e);
+                    failures.push(e);
+                    continue;
+                }
+            };
+            
+            // A failing rule must not hide the fetched mail from the caller
+            for (uid, mut email) in fetched.messages {
+                match self.automation.run(user_id, account_id, folder, &mut session, &email).await {
+                    Ok(trace) => {
+                        email.automation_trace = trace;
+                        
+                        // Only mail that arrives in INBOX gets the default auto-reply, never Sent or Junk
+                        let handled = email.automation_trace.iter().any(|trace| trace.matched);
+                        if let (Some(template_id), false, true) = (default_template_id, handled, folder.eq_ignore_ascii_case("INBOX")) {
+                            match self.automation.send_auto_reply(user_id, account_id, &email, template_id).await {
+                                Ok(outcome) => info!("Default auto-reply for email {}: {}", email.id, outcome),
+                                Err(e) => error!("Default auto-reply failed for email {}: {}", email.id, e),
+                            }
+                        }
+                    }
+                    Err(e) => error!("Failed to process automation rules for email {}: {}", email.id, e),
+                }
+                
+                // Without the mod-sequence, so the folder isn't taken as unchanged until the check completes
+                if let Err(e) = self.save_sync_state(account_id, folder, fetched.status.uid_validity, uid, None) {
+                    error!("Failed to save the sync position of {} for account {}: {}", folder, account_id, e);
+                }
+                emails.push(email);
+            }
+            
+            if let Err(e) = self.save_sync_state(account_id, folder, fetched.status.uid_validity, fetched.last_uid, fetched.status.highest_modseq) {
+                error!("Failed to save the sync position of {} for account {}: {}", folder, account_id, e);
+            }
+        }
         
-        // Decrypt password (you'll need to implement this based on your encryption service)
-        // For now, assuming password is stored in plain text (not recommended for production)
-        let password = password_encrypted; // TODO: Decrypt this
+        session.logout()
+            .map_err(|e| AppError::Email(format!("IMAP logout error: {}", e)))?;
         
-        // Connect to IMAP server
-        let emails = self.fetch_emails_from_imap(&imap_server, imap_port, &username, &password).await?;
+        if failures.len() == folders.len() {
+            if let Some(e) = failures.pop() {
+                return Err(e);
+            }
+        }
         
         // Update last check time
-        {
-            let conn = self.database.get_connection();
-            conn.execute(
+        self.database.transaction(|tx| {
+            tx.execute(
                 "UPDATE inbox_monitors SET last_check = CURRENT_TIMESTAMP WHERE email_account_id = ?1 AND user_id = ?2",
                 [account_id, user_id],
             )?;
-        }
+            Ok(())
+        })?;
         
         Ok(emails)
     }
     
-    async fn fetch_emails_from_imap(
-        &self,
-        server: &str,
-        port: i32,
-        username: &str,
-        password: &str,
-    ) -> Result<Vec<InboxEmail>, AppError> {
-        // This is a simplified IMAP implementation
-        // In a real application, you'd want more robust error handling and connection management
-        
-        let tls = native_tls::TlsConnector::builder().build()
-            .map_err(|e| AppError::Email(format!("TLS error: {}", e)))?;
-        
-        let client = imap::connect((server, port as u16), server, &tls)
-            .map_err(|e| AppError::Email(format!("IMAP connection error: {}", e)))?;
+    /// Runs the automation rules again against a stored message, on the server copy it
+    /// was fetched from. Fails if the folder was recreated since (UIDVALIDITY changed).
+    pub async fn rerun_rules(&self, user_id: i32, message_id: i32) -> Result<InboxEmail, AppError> {
+        let (location, raw) = self.message_store.get_raw(user_id, message_id)?;
+        let mut email = Self::parse_message(location.uid.to_string(), &raw)?;
+        email.stored_id = Some(message_id);
+        
+        let mut session = self.open_session(user_id, location.account_id).await?;
+        let mailbox = session.select(&location.folder)
+            .map_err(|e| AppError::Email(format!("Failed to select {}: {}", location.folder, e)))?;
+        
+        if mailbox.uid_validity.is_some_and(|validity| validity != location.uid_validity) {
+            let _ = session.logout();
+            return Err(AppError::NotFound(format!("{} has been recreated on the server since this message was stored", location.folder)));
+        }
         
-        let mut session = client.login(username, password)
-            .map_err(|e| AppError::Email(format!("IMAP login error: {:?}", e.0)))?;
+        let result = self.automation.run(user_id, location.account_id, &location.folder, &mut session, &email).await;
+        let _ = session.logout();
         
-        // Select INBOX
-        session.select("INBOX")
-            .map_err(|e| AppError::Email(format!("Failed to select INBOX: {}", e)))?;
+        email.automation_trace = result?;
+        Ok(email)
+    }
+    
+    /// Returns the most recent `limit` messages in `folder`, read or not, without changing
+    /// anything on the server or the sync position. Used to try rules out against real mail.
+    pub async fn fetch_recent(&self, user_id: i32, account_id: i32, folder: &str, limit: usize) -> Result<Vec<InboxEmail>, AppError> {
+        let mut session = self.open_session(user_id, account_id).await?;
+        
+        // EXAMINE opens the mailbox read-only, so not even \Recent changes
+        session.examine(folder)
+            .map_err(|e| AppError::Email(format!("Failed to examine {}: {}", folder, e)))?;
+        
+        let mut uids: Vec<u32> = session.uid_search("ALL")
+            .map_err(|e| AppError::Email(format!("IMAP search error: {}", e)))?
+            .into_iter()
+            .collect();
+        uids.sort_unstable_by(|a, b| b.cmp(a));
+        uids.truncate(limit);
+        
+        let emails = Self::fetch_uids(&mut session, &uids)?
+            .into_iter()
+            .map(|fetched| fetched.email)
+            .collect();
         
-        // Search for recent unread emails
-        let sequences = session.search("UNSEEN")
-            .map_err(|e| AppError::Email(format!("IMAP search error: {}", e)))?;
+        session.logout()
+            .map_err(|e| AppError::Email(format!("IMAP logout error: {}", e)))?;
         
-        let mut emails = Vec::new();
+        Ok(emails)
+    }
+    
+    /// Lists every mailbox on the account's server with its attributes and special use.
+    pub async fn list_mailboxes(&self, user_id: i32, account_id: i32) -> Result<Vec<Mailbox>, AppError> {
+        let mut session = self.open_session(user_id, account_id).await?;
+        
+        let names = session.list(Some(""), Some("*"))
+            .map_err(|e| AppError::Email(format!("Failed to list folders: {}", e)))?;
+        
+        let mailboxes = names.iter()
+            .map(|name| {
+                let attributes: Vec<String> = name.attributes().iter()
+                    .map(|attribute| match attribute {
+                        NameAttribute::NoInferiors => "\\Noinferiors".to_string(),
+                        NameAttribute::NoSelect => "\\Noselect".to_string(),
+                        NameAttribute::Marked => "\\Marked".to_string(),
+                        NameAttribute::Unmarked => "\\Unmarked".to_string(),
+                        NameAttribute::Custom(custom) => custom.to_string(),
+                    })
+                    .collect();
+                
+                Mailbox {
+                    name: name.name().to_string(),
+                    delimiter: name.delimiter().map(str::to_string),
+                    special_use: Self::special_use(name.name(), name.delimiter(), &attributes),
+                    selectable: !attributes.iter().any(|attribute| attribute.eq_ignore_ascii_case("\\Noselect")),
+                    attributes,
+                }
+            })
+            .collect();
         
-        // Limit to last 50 emails to avoid overwhelming the system
-        let mut sequences: Vec<_> = sequences.into_iter().collect();
-        sequences.reverse();
-        let sequences: Vec<_> = sequences.into_iter().take(50).collect();
+        session.logout()
+            .map_err(|e| AppError::Email(format!("IMAP logout error: {}", e)))?;
         
-        if !sequences.is_empty() {
-            let messages = session.fetch(sequences.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(","), "(ENVELOPE BODY[])") 
-                .map_err(|e| AppError::Email(format!("IMAP fetch error: {}", e)))?;
+        Ok(mailboxes)
+    }
+    
+    /// Expands a monitor's folder list: plain entries are used as they are, entries with
+    /// `*` or `%` are LIST patterns. Each folder is checked once, in the order listed.
+    fn resolve_folders(session: &mut ImapSession, patterns: &[String]) -> Result<Vec<String>, AppError> {
+        let mut folders: Vec<String> = Vec::new();
+        
+        for pattern in patterns {
+            if !pattern.contains(['*', '%']) {
+                if !folders.contains(pattern) {
+                    folders.push(pattern.clone());
+                }
+                continue;
+            }
             
-            for message in messages.iter() {
-                if let Some(envelope) = message.envelope() {
-                    let subject = envelope.subject
-                        .and_then(|s| std::str::from_utf8(s).ok())
-                        .unwrap_or("No Subject")
-                        .to_string();
-                    
-                    let sender = envelope.from
-                        .as_ref()
-                        .and_then(|addrs| addrs.first())
-                        .and_then(|addr| {
-                            let name = addr.name.and_then(|n| std::str::from_utf8(n).ok());
-                            let mailbox = addr.mailbox.and_then(|m| std::str::from_utf8(m).ok());
-                            let host = addr.host.and_then(|h| std::str::from_utf8(h).ok());
-                            
-                            match (name, mailbox, host) {
-                                (Some(name), Some(mailbox), Some(host)) => Some(format!("{} <{}@{}>", name, mailbox, host)),
-                                (None, Some(mailbox), Some(host)) => Some(format!("{}@{}", mailbox, host)),
-                                _ => None,
-                            }
-                        })
-                        .unwrap_or_else(|| "Unknown Sender".to_string());
-                    
-                    let received_at = envelope.date
-                        .and_then(|d| std::str::from_utf8(d).ok())
-                        .and_then(|d| chrono::DateTime::parse_from_rfc2822(d).ok())
-                        .map(|d| d.with_timezone(&chrono::Utc))
-                        .unwrap_or_else(|| Utc::now());
-                    
-                    let body = message.body()
-                        .and_then(|b| std::str::from_utf8(b).ok())
-                        .unwrap_or("")
-                        .to_string();
-                    
-                    let email = InboxEmail {
-                        id: message.message.to_string(),
-                        subject,
-                        sender,
-                        received_at,
-                        body,
-                        attachments: Vec::new(), // TODO: Parse attachments
-                        is_read: false,
-                    };
-                    
-                    emails.push(email);
+            let names = session.list(Some(""), Some(pattern))
+                .map_err(|e| AppError::Email(format!("Failed to list folders matching {}: {}", pattern, e)))?;
+            for name in names.iter() {
+                let selectable = !name.attributes().iter().any(|attribute| matches!(attribute, NameAttribute::NoSelect));
+                if selectable && !folders.iter().any(|folder| folder == name.name()) {
+                    folders.push(name.name().to_string());
                 }
             }
         }
         
-        session.logout()
-            .map_err(|e| AppError::Email(format!("IMAP logout error: {}", e)))?;
-        
-        Ok(emails)
+        Ok(folders)
     }
     
-    pub async fn process_automation_rules(&self, user_id: i32, email: &InboxEmail) -> Result<(), AppError> {
-        // Get active automation rules for the user
-        let conn = self.database.get_connection();
-        let mut stmt = conn.prepare(
-            "SELECT id, rule_name, keywords, conditions, actions
-             FROM automation_rules WHERE user_id = ?1 AND is_active = 1"
-        )?;
+    /// The RFC 6154 role of a mailbox. Servers without SPECIAL-USE don't mark their folders,
+    /// so the common names clients give them are recognized too.
+    fn special_use(name: &str, delimiter: Option<&str>, attributes: &[String]) -> Option<String> {
+        const ROLES: [&str; 7] = ["all", "archive", "drafts", "flagged", "junk", "sent", "trash"];
+        
+        let marked = attributes.iter()
+            .filter_map(|attribute| attribute.strip_prefix('\\'))
+            .map(str::to_lowercase)
+            .find(|attribute| ROLES.contains(&attribute.as_str()));
+        if marked.is_some() {
+            return marked;
+        }
         
-        let rule_iter = stmt.query_map([user_id], |row| {
-            Ok((
-                row.get::<_, i32>(0)?, // id
-                row.get::<_, String>(1)?, // rule_name
-                row.get::<_, String>(2)?, // keywords
-                row.get::<_, String>(3)?, // conditions
-                row.get::<_, String>(4)?, // actions
-            ))
-        })?;
+        let leaf = match delimiter {
+            Some(delimiter) if !delimiter.is_empty() => name.rsplit(delimiter).next().unwrap_or(name),
+            _ => name,
+        };
+        let role = match leaf.to_lowercase().as_str() {
+            "sent" | "sent items" | "sent mail" | "sent messages" => "sent",
+            "junk" | "spam" | "junk e-mail" | "junk email" | "bulk mail" => "junk",
+            "archive" | "archives" => "archive",
+            "trash" | "bin" | "deleted items" | "deleted messages" => "trash",
+            "drafts" => "drafts",
+            _ => return None,
+        };
+        Some(role.to_string())
+    }
+    
+    /// Connects and logs in to the account's IMAP server. OAuth2 accounts may refresh
+    /// their access token first, which is why this is async.
+    pub async fn open_session(&self, user_id: i32, account_id: i32) -> Result<ImapSession, AppError> {
+        let (account, credential) = self.credential_service.resolve(user_id, account_id).await?;
+        
+        let client = connection::connect_imap(&account)?;
+        let server = account.imap_server.as_deref().unwrap_or_default();
+        oauth::imap_login(client, &account.username, server, account.imap_port.unwrap_or(993) as u16, &credential)
+    }
+    
+    /// Fetches the messages that arrived in `folder` since the last check, read or not.
+    ///
+    /// Messages are read with `BODY.PEEK[]` so fetching does not mark them as read. The
+    /// sync position is kept per account and folder: while UIDVALIDITY is unchanged only
+    /// UIDs above the last one seen are fetched, and an unchanged UIDNEXT or HIGHESTMODSEQ
+    /// skips the search entirely. The first sync, and one after UIDVALIDITY changed, only
+    /// records where the folder ends; nothing already in it is processed.
+    ///
+    /// The sync position is left alone here; the caller saves it as it processes the mail.
+    fn fetch_emails_from_imap(&self, session: &mut ImapSession, user_id: i32, account_id: i32, folder: &str) -> Result<FolderFetch, AppError> {
+        let condstore = session.capabilities()
+            .map(|capabilities| capabilities.has_str("CONDSTORE"))
+            .unwrap_or(false);
+        let status = Self::select_folder(session, folder, condstore)?;
+        
+        let (mut uids, baseline) = match self.get_sync_state(account_id, folder)? {
+            Some(sync) if sync.uid_validity == status.uid_validity => {
+                let unchanged = status.highest_modseq.is_some() && status.highest_modseq == sync.highest_modseq;
+                let nothing_new = status.uid_next.is_some_and(|next| next <= sync.last_uid + 1);
+                
+                let mut uids = if unchanged || nothing_new {
+                    Vec::new()
+                } else {
+                    Self::search_uids(session, &format!("UID {}:*", sync.last_uid + 1))?
+                };
+                // "n:*" always matches the highest UID, so drop anything already processed
+                uids.retain(|uid| *uid > sync.last_uid);
+                (uids, sync.last_uid)
+            }
+            // First sync, or a recreated folder: start from what is there now. Mail already
+            // in the folder may have been handled before, so rules never run on it again
+            Some(_) => {
+                warn!("UIDVALIDITY of {} changed for account {}, starting over from its newest message", folder, account_id);
+                (Vec::new(), Self::highest_uid(session, &status)?)
+            }
+            None => (Vec::new(), Self::highest_uid(session, &status)?),
+        };
         
-        for rule_result in rule_iter {
-            let (rule_id, rule_name, keywords_str, conditions_str, actions_str) = rule_result?;
-            
-            // Parse keywords
-            let keywords: Vec<String> = serde_json::from_str(&keywords_str)
-                .unwrap_or_else(|_| vec![]);
-            
-            // Check if email matches any keywords
-            let matches_keywords = keywords.iter().any(|keyword| {
-                email.subject.to_lowercase().contains(&keyword.to_lowercase()) ||
-                email.body.to_lowercase().contains(&keyword.to_lowercase())
-            });
-            
-            if matches_keywords {
-                info!("Email matches automation rule '{}' for user {}", rule_name, user_id);
+        uids.sort_unstable();
+        
+        // Oldest first, in batches so a large backlog doesn't become one huge FETCH
+        let mut messages = Vec::new();
+        for batch in uids.chunks(FETCH_BATCH_SIZE) {
+            for mut fetched in Self::fetch_uids(session, batch)? {
+                let location = MessageLocation {
+                    account_id,
+                    folder: folder.to_string(),
+                    uid_validity: status.uid_validity,
+                    uid: fetched.uid,
+                };
                 
-                // Parse and execute actions
-                if let Ok(actions) = serde_json::from_str::<serde_json::Value>(&actions_str) {
-                    self.execute_automation_actions(user_id, email, &actions).await?;
+                // A message that can't be stored is still processed, just not kept
+                match self.message_store.store(user_id, &location, &fetched.email, &fetched.raw, &fetched.flags) {
+                    Ok(id) => fetched.email.stored_id = Some(id),
+                    Err(e) => error!("Failed to store message {} for account {}: {}", fetched.uid, account_id, e),
                 }
+                messages.push((fetched.uid, fetched.email));
             }
         }
         
-        Ok(())
+        let last_uid = uids.last().copied().unwrap_or(0).max(baseline);
+        Ok(FolderFetch { status, last_uid, messages })
     }
     
-    async fn execute_automation_actions(
-        &self,
-        user_id: i32,
-        email: &InboxEmail,
-        actions: &serde_json::Value,
-    ) -> Result<(), AppError> {
-        if let Some(actions_array) = actions.as_array() {
-            for action in actions_array {
-                if let Some(action_type) = action.get("type").and_then(|t| t.as_str()) {
-                    match action_type {
-                        "auto_reply" => {
-                            if let Some(template_id) = action.get("template_id").and_then(|t| t.as_i64()) {
-                                self.send_auto_reply(user_id, email, template_id as i32).await?;
-                            }
-                        },
-                        "mark_as_read" => {
-                            // TODO: Mark email as read in IMAP
-                            info!("Would mark email {} as read", email.id);
-                        },
-                        "move_to_folder" => {
-                            if let Some(folder) = action.get("folder").and_then(|f| f.as_str()) {
-                                // TODO: Move email to specified folder
-                                info!("Would move email {} to folder {}", email.id, folder);
-                            }
-                        },
-                        _ => {
-                            warn!("Unknown automation action type: {}", action_type);
-                        }
-                    }
-                }
-            }
+    fn save_sync_state(&self, account_id: i32, folder: &str, uid_validity: u32, last_uid: u32, highest_modseq: Option<u64>) -> Result<(), AppError> {
+        self.database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO imap_sync_state (email_account_id, folder, uid_validity, last_uid, highest_modseq, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, CURRENT_TIMESTAMP)
+                 ON CONFLICT (email_account_id, folder) DO UPDATE SET
+                     uid_validity = excluded.uid_validity, last_uid = excluded.last_uid,
+                     highest_modseq = excluded.highest_modseq, updated_at = excluded.updated_at",
+                params![account_id, folder, uid_validity, last_uid, highest_modseq.map(|modseq| modseq as i64)],
+            )?;
+            Ok(())
+        })
+    }
+    
+    fn get_sync_state(&self, account_id: i32, folder: &str) -> Result<Option<FolderSync>, AppError> {
+        let conn = self.database.get_connection()?;
+        Ok(conn.query_row(
+            "SELECT uid_validity, last_uid, highest_modseq FROM imap_sync_state
+             WHERE email_account_id = ?1 AND folder = ?2",
+            params![account_id, folder],
+            |row| Ok(FolderSync {
+                uid_validity: row.get(0)?,
+                last_uid: row.get(1)?,
+                highest_modseq: row.get::<_, Option<i64>>(2)?.map(|modseq| modseq as u64),
+            }),
+        ).optional()?)
+    }
+    
+    /// Selects `folder` read-write. With CONDSTORE the SELECT is issued by hand, since the
+    /// IMAP client's `select` neither sends the parameter nor reports HIGHESTMODSEQ.
+    fn select_folder(session: &mut ImapSession, folder: &str, condstore: bool) -> Result<FolderStatus, AppError> {
+        if condstore {
+            let quoted = folder.replace('\\', "\\\\").replace('"', "\\\"");
+            let response = session.run_command_and_read_response(format!("SELECT \"{}\" (CONDSTORE)", quoted))
+                .map_err(|e| AppError::Email(format!("Failed to select {}: {}", folder, e)))?;
+            return Ok(Self::parse_select_response(&String::from_utf8_lossy(&response)));
         }
         
-        Ok(())
+        let mailbox = session.select(folder)
+            .map_err(|e| AppError::Email(format!("Failed to select {}: {}", folder, e)))?;
+        Ok(FolderStatus {
+            uid_validity: mailbox.uid_validity.unwrap_or(0),
+            uid_next: mailbox.uid_next,
+            highest_modseq: None,
+        })
     }
     
-    async fn send_auto_reply(
-        &self,
-        user_id: i32,
-        original_email: &InboxEmail,
-        template_id: i32,
-    ) -> Result<(), AppError> {
-        // Get template
-        let conn = self.database.get_connection();
-        let mut stmt = conn.prepare(
-            "SELECT subject, body FROM email_templates WHERE id = ?1 AND user_id = ?2"
-        )?;
-        
-        let template_data = stmt.query_row([template_id, user_id], |row| {
-            Ok((
-                row.get::<_, Option<String>>(0)?, // subject
-                row.get::<_, Option<String>>(1)?, // body
-            ))
-        })?;
-        
-        let (template_subject, template_body) = template_data;
-        
-        // Extract sender email from original email
-        let sender_email = self.extract_email_address(&original_email.sender)?;
-        
-        // Create reply email
-        let reply_subject = template_subject
-            .unwrap_or_else(|| format!("Re: {}", original_email.subject));
-        
-        let reply_body = template_body
-            .unwrap_or_else(|| "Thank you for your email. This is an automated response.".to_string());
-        
-        let email_message = EmailMessage {
-            to: vec![sender_email],
-            cc: None,
-            bcc: None,
-            subject: reply_subject,
-            body: reply_body,
-            attachments: None,
-        };
-        
-        // Send the reply (you'll need to implement this based on your email service)
-        // For now, just log it
-        info!("Would send auto-reply to {} for email {}", original_email.sender, original_email.id);
-        
-        Ok(())
+    fn parse_select_response(response: &str) -> FolderStatus {
+        let code = |pattern: &Regex| pattern.captures(response)
+            .and_then(|captures| captures[1].parse::<u64>().ok());
+        
+        FolderStatus {
+            uid_validity: code(&UIDVALIDITY).unwrap_or(0) as u32,
+            uid_next: code(&UIDNEXT).map(|uid| uid as u32),
+            // Servers answer NOMODSEQ for folders that can't keep mod-sequences
+            highest_modseq: code(&HIGHESTMODSEQ),
+        }
+    }
+    
+    fn search_uids(session: &mut ImapSession, query: &str) -> Result<Vec<u32>, AppError> {
+        Ok(session.uid_search(query)
+            .map_err(|e| AppError::Email(format!("IMAP search error: {}", e)))?
+            .into_iter()
+            .collect())
+    }
+    
+    /// The highest UID currently in the selected folder, where a new sync position starts.
+    fn highest_uid(session: &mut ImapSession, status: &FolderStatus) -> Result<u32, AppError> {
+        match status.uid_next {
+            Some(uid_next) => Ok(uid_next.saturating_sub(1)),
+            None => Ok(Self::search_uids(session, "UID *")?.into_iter().max().unwrap_or(0)),
+        }
     }
     
-    fn extract_email_address(&self, sender_string: &str) -> Result<String, AppError> {
-        // Extract email address from "Name <email@domain.com>" format
-        if let Some(start) = sender_string.find('<') {
-            if let Some(end) = sender_string.find('>') {
-                if end > start {
-                    return Ok(sender_string[start + 1..end].to_string());
+    fn fetch_uids(session: &mut ImapSession, uids: &[u32]) -> Result<Vec<FetchedMessage>, AppError> {
+        if uids.is_empty() {
+            return Ok(Vec::new());
+        }
+        
+        let uid_set = uids.iter().map(|uid| uid.to_string()).collect::<Vec<_>>().join(",");
+        let messages = session.uid_fetch(uid_set, "(UID FLAGS BODY.PEEK[])")
+            .map_err(|e| AppError::Email(format!("IMAP fetch error: {}", e)))?;
+        
+        let mut fetched = Vec::new();
+        for message in messages.iter() {
+            let (uid, raw) = match (message.uid, message.body()) {
+                (Some(uid), Some(raw)) => (uid, raw),
+                _ => continue,
+            };
+            let flags: Vec<String> = message.flags().iter().map(|flag| flag.to_string()).collect();
+            
+            match Self::parse_message(uid.to_string(), raw) {
+                Ok(mut email) => {
+                    email.is_read = flags.iter().any(|flag| flag == "\\Seen");
+                    fetched.push(FetchedMessage { uid, email, raw: raw.to_vec(), flags });
                 }
+                Err(e) => warn!("Skipping message {}: {}", uid, e),
             }
         }
         
-        // If no angle brackets, assume the whole string is an email
-        if sender_string.contains('@') {
-            Ok(sender_string.trim().to_string())
-        } else {
-            Err(AppError::Validation("Could not extract email address from sender".to_string()))
+        Ok(fetched)
+    }
+    
+    /// Builds an [`InboxEmail`] from a raw RFC 5322 message.
+    pub fn parse_message(id: String, raw: &[u8]) -> Result<InboxEmail, AppError> {
+        let mail = mailparse::parse_mail(raw)
+            .map_err(|e| AppError::Validation(format!("Failed to parse message: {}", e)))?;
+        let message = MimeMessage::from_mail(&mail);
+        
+        let sender = message.from.clone().unwrap_or_else(|| "Unknown Sender".to_string());
+        let is_auto_generated = Self::is_auto_generated(&mail.headers, &sender);
+        let (attachments, attachment_types) = message.attached_files()
+            .map(|attachment| (attachment.filename.clone(), attachment.mime_type.clone()))
+            .unzip();
+        
+        Ok(InboxEmail {
+            id,
+            message_id: message.message_id,
+            in_reply_to: message.in_reply_to,
+            references: message.references,
+            subject: message.subject.unwrap_or_else(|| "No Subject".to_string()),
+            sender,
+            to: message.to,
+            cc: message.cc,
+            reply_to: message.reply_to,
+            received_at: message.date.unwrap_or_else(Utc::now),
+            body: message.text_body.unwrap_or_default(),
+            html_body: message.html_body,
+            headers: message.headers,
+            attachments,
+            attachment_types,
+            size: raw.len(),
+            is_read: false,
+            is_auto_generated,
+            automation_trace: Vec::new(),
+            stored_id: None,
+        })
+    }
+    
+    /// Detects mail that must not get an automatic response under RFC 3834: anything
+    /// already auto-submitted, bulk or list traffic, and bounces from null or daemon senders.
+    fn is_auto_generated(headers: &[mailparse::MailHeader], sender: &str) -> bool {
+        let header = |name: &str| headers.get_first_value(name)
+            .map(|value| value.trim().to_lowercase());
+        
+        if matches!(header("Auto-Submitted"), Some(value) if value != "no") {
+            return true;
+        }
+        
+        if matches!(header("Precedence").as_deref(), Some("bulk" | "list" | "junk")) {
+            return true;
+        }
+        
+        if header("List-Id").is_some() || matches!(header("Return-Path").as_deref(), Some("<>" | "")) {
+            return true;
+        }
+        
+        let address = sender.rsplit('<').next().unwrap_or(sender).trim_end_matches('>').to_lowercase();
+        match address.split_once('@') {
+            Some((local_part, _)) => matches!(local_part, "mailer-daemon" | "postmaster"),
+            // No usable sender address to reply to
+            None => true,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn headers(raw: &[u8]) -> Vec<mailparse::MailHeader<'_>> {
+        mailparse::parse_headers(raw).unwrap().0
+    }
+
+    #[test]
+    fn test_automated_mail_is_never_answered() {
+        let person = "Jane <jane@example.com>";
+        assert!(!InboxService::is_auto_generated(&headers(b"Subject: Hi\r\n\r\n"), person));
+        assert!(!InboxService::is_auto_generated(&headers(b"Auto-Submitted: no\r\n\r\n"), person));
+
+        assert!(InboxService::is_auto_generated(&headers(b"Auto-Submitted: auto-replied\r\n\r\n"), person));
+        assert!(InboxService::is_auto_generated(&headers(b"Precedence: Bulk\r\n\r\n"), person));
+        assert!(InboxService::is_auto_generated(&headers(b"List-Id: <news.example.com>\r\n\r\n"), person));
+        assert!(InboxService::is_auto_generated(&headers(b"Return-Path: <>\r\n\r\n"), person));
+        assert!(InboxService::is_auto_generated(&headers(b"Subject: Undeliverable\r\n\r\n"), "MAILER-DAEMON@mx.example.com"));
+    }
+
+    #[test]
+    fn test_parse_condstore_select_response() {
+        let response = "* 12 EXISTS\r\n\
+            * OK [UIDVALIDITY 1700000000] UIDs valid\r\n\
+            * OK [UIDNEXT 4392] Predicted next UID\r\n\
+            * OK [HIGHESTMODSEQ 715194045007] Highest\r\n\
+            A0003 OK [READ-WRITE] SELECT completed, CONDSTORE is now enabled\r\n";
+        
+        assert_eq!(InboxService::parse_select_response(response), FolderStatus {
+            uid_validity: 1700000000,
+            uid_next: Some(4392),
+            highest_modseq: Some(715194045007),
+        });
+        
+        let response = "* OK [UIDVALIDITY 3857529045] UIDs valid\r\n* OK [NOMODSEQ] Sorry, this mailbox format doesn't support modsequences\r\n";
+        assert_eq!(InboxService::parse_select_response(response).highest_modseq, None);
+    }
+    
+    #[test]
+    fn test_special_use_prefers_attributes_over_names() {
+        let attributes = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        
+        assert_eq!(InboxService::special_use("[Gmail]/Spam", Some("/"), &attributes(&["\\HasNoChildren", "\\Junk"])).as_deref(), Some("junk"));
+        assert_eq!(InboxService::special_use("Gesendet", Some("."), &attributes(&["\\Sent"])).as_deref(), Some("sent"));
+        assert_eq!(InboxService::special_use("INBOX.Sent Items", Some("."), &[]).as_deref(), Some("sent"));
+        assert_eq!(InboxService::special_use("Projects/Archive", Some("/"), &[]).as_deref(), Some("archive"));
+        assert_eq!(InboxService::special_use("INBOX", None, &[]), None);
+    }
+    
+    #[test]
+    fn test_parse_raw_message() {
+        let raw = b"From: =?UTF-8?Q?J=C3=BCrgen?= <jurgen@example.com>\r\n\
+            To: support@example.com, Sales <sales@example.com>\r\n\
+            Subject: Order question\r\n\
+            Date: Mon, 4 Mar 2024 10:30:00 +0100\r\n\
+            Message-ID: <q1@example.com>\r\n\
+            \r\n\
+            Where is my order?\r\n";
+
+        let email = InboxService::parse_message("raw".to_string(), raw).unwrap();
+        assert_eq!(email.sender, "J\u{fc}rgen <jurgen@example.com>");
+        assert_eq!(email.to, vec!["support@example.com", "sales@example.com"]);
+        assert_eq!(email.message_id.as_deref(), Some("<q1@example.com>"));
+        assert_eq!(email.received_at, Utc.with_ymd_and_hms(2024, 3, 4, 9, 30, 0).unwrap());
+        assert!(email.body.starts_with("Where is my order?"));
+        assert!(!email.is_auto_generated);
+    }
+}
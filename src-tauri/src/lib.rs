@@ -10,25 +10,50 @@ use chrono::Utc;
 
 mod models;
 mod database;
+mod migrations;
 mod auth;
 mod email_service;
 mod encryption;
+mod oauth;
+mod credentials;
+mod connection;
+mod autodiscovery;
 mod scheduler;
 mod attachment_service;
 mod contact_service;
 mod inbox_service;
+mod mime;
+mod message_store;
+mod search;
+mod threading;
 mod campaign_service;
+mod outbox_service;
+mod rate_limiter;
+mod conditions;
+mod automation;
+mod monitor_service;
 
 use models::*;
 use database::Database;
 use auth::AuthService;
 use email_service::EmailService;
 use encryption::EncryptionService;
+use oauth::{OAuthProvider, OAuthService};
+use credentials::CredentialService;
+use autodiscovery::{AutodiscoveryService, DiscoveryEndpoints};
 use scheduler::SchedulerService;
 use attachment_service::AttachmentService;
 use contact_service::ContactService;
 use inbox_service::InboxService;
+use message_store::MessageStore;
+use search::SearchService;
+use threading::ThreadingService;
 use campaign_service::CampaignService;
+use outbox_service::OutboxService;
+use rate_limiter::{RateLimiter, RateLimitStatus};
+use conditions::Condition;
+use automation::AutomationEngine;
+use monitor_service::{MonitorService, MonitorServiceStatus};
 
 // Application state
 #[derive(Clone)]
@@ -36,12 +61,21 @@ struct AppState {
     database: Arc<Database>,
     auth_service: Arc<AuthService>,
     email_service: Arc<Mutex<EmailService>>,
-    encryption_service: Arc<EncryptionService>,
+    oauth_service: Arc<OAuthService>,
+    credential_service: Arc<CredentialService>,
+    autodiscovery_service: Arc<AutodiscoveryService>,
     scheduler_service: Arc<SchedulerService>,
     attachment_service: Arc<AttachmentService>,
     contact_service: Arc<ContactService>,
     inbox_service: Arc<InboxService>,
+    message_store: Arc<MessageStore>,
+    search_service: Arc<SearchService>,
+    threading_service: Arc<ThreadingService>,
+    automation_engine: Arc<AutomationEngine>,
+    monitor_service: Arc<MonitorService>,
     campaign_service: Arc<CampaignService>,
+    outbox_service: Arc<OutboxService>,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 fn initialize_app(app_handle: tauri::AppHandle) -> Result<String, String> {
@@ -94,14 +128,42 @@ fn initialize_app(app_handle: tauri::AppHandle) -> Result<String, String> {
          EncryptionService::new()
              .map_err(|e| format!("Failed to initialize encryption service: {}", e))?
      );
+     let oauth_service = Arc::new(
+         OAuthService::new(
+             Arc::clone(&database),
+             Arc::clone(&encryption_service),
+             OAuthProvider::from_env(),
+         )
+     );
+     let credential_service = Arc::new(
+         CredentialService::new(
+             Arc::clone(&database),
+             Arc::clone(&encryption_service),
+             Arc::clone(&oauth_service),
+         )
+     );
+     let autodiscovery_service = Arc::new(
+         AutodiscoveryService::new(DiscoveryEndpoints::default())
+     );
      let scheduler_service = Arc::new(
          SchedulerService::new(
              Arc::clone(&database),
              Arc::clone(&email_service),
-             Arc::clone(&encryption_service),
          )
      );
      
+     let outbox_service = Arc::new(
+         OutboxService::new(
+             Arc::clone(&database),
+             Arc::clone(&email_service),
+             Arc::clone(&credential_service),
+         )
+     );
+     
+     let rate_limiter = Arc::new(
+         RateLimiter::new(Arc::clone(&database))
+     );
+     
      let attachment_service = Arc::new(
          AttachmentService::new(Arc::clone(&database), &app_data_dir)
              .map_err(|e| format!("Failed to initialize attachment service: {}", e))?
@@ -111,14 +173,46 @@ fn initialize_app(app_handle: tauri::AppHandle) -> Result<String, String> {
          ContactService::new(Arc::clone(&database))
      );
      
-     let inbox_service = Arc::new(
-         InboxService::new(
+     let automation_engine = Arc::new(
+         AutomationEngine::new(
              Arc::clone(&database),
              Arc::clone(&email_service),
              Arc::clone(&attachment_service),
+             Arc::clone(&contact_service),
          )
      );
      
+     let message_store = Arc::new(
+         MessageStore::new(Arc::clone(&database), &app_data_dir)
+             .map_err(|e| format!("Failed to initialize message store: {}", e))?
+     );
+     
+     let search_service = Arc::new(
+         SearchService::new(Arc::clone(&database))
+     );
+     
+     let threading_service = Arc::new(
+         ThreadingService::new(Arc::clone(&database))
+     );
+     
+     // Thread mail stored before threading existed
+     if let Err(e) = threading_service.assign_missing() {
+         eprintln!("Failed to thread existing messages: {}", e);
+     }
+     
+     let inbox_service = Arc::new(
+         InboxService::new(
+             Arc::clone(&database),
+             Arc::clone(&automation_engine),
+             Arc::clone(&message_store),
+             Arc::clone(&credential_service),
+         )
+     );
+     
+     let monitor_service = Arc::new(
+         MonitorService::new(Arc::clone(&inbox_service))
+     );
+     
      let campaign_service = Arc::new(
          CampaignService::new(
              Arc::clone(&database),
@@ -132,16 +226,39 @@ fn initialize_app(app_handle: tauri::AppHandle) -> Result<String, String> {
          database,
          auth_service,
          email_service,
-         encryption_service,
+         oauth_service,
+         credential_service,
+         autodiscovery_service,
          scheduler_service,
          attachment_service,
          contact_service,
          inbox_service,
+         message_store,
+         search_service,
+         threading_service,
+         automation_engine,
+         monitor_service: Arc::clone(&monitor_service),
          campaign_service,
+         outbox_service: Arc::clone(&outbox_service),
+         rate_limiter,
      };
     
     app_handle.manage(app_state);
     
+    // Deliver queued mail in the background, resuming anything a previous run left unfinished
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = outbox_service.start().await {
+            eprintln!("Failed to start outbox worker: {}", e);
+        }
+    });
+    
+    // Poll active inbox monitors on their own intervals
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = monitor_service.start().await {
+            eprintln!("Failed to start inbox monitor service: {}", e);
+        }
+    });
+    
     Ok("Application initialized successfully".to_string())
 }
 
@@ -203,7 +320,11 @@ fn create_email_account(
     account_data: CreateEmailAccount,
 ) -> Result<EmailAccount, String> {
     let user = state.auth_service.extract_user_from_token(&token)?;
-    let encrypted_password = state.encryption_service.encrypt(&account_data.password)?;
+    RateLimiter::validate(&account_data.rate_limits)?;
+    if let Some(pem) = &account_data.tls_ca_certificate {
+        connection::validate_ca_certificate(pem)?;
+    }
+    let encrypted_password = state.credential_service.seal_password(&account_data.password)?;
     
     let account_with_user = CreateEmailAccountWithUser {
         user_id: user.id,
@@ -215,13 +336,76 @@ fn create_email_account(
         smtp_port: account_data.smtp_port,
         username: account_data.username,
         password_encrypted: encrypted_password,
+        auth_type: "password".to_string(),
+        oauth_provider: None,
+        smtp_security: account_data.smtp_security
+            .unwrap_or_else(|| ConnectionSecurity::for_port(account_data.smtp_port.unwrap_or(587))),
+        imap_security: account_data.imap_security
+            .unwrap_or_else(|| ConnectionSecurity::for_port(account_data.imap_port.unwrap_or(993))),
+        tls_ca_certificate: account_data.tls_ca_certificate,
+        tls_accept_invalid_certs: account_data.tls_accept_invalid_certs,
         is_active: Some(true),
+        rate_limits: account_data.rate_limits,
     };
     
     state.database.create_email_account(account_with_user)
         .map_err(|e| e.to_string())
 }
 
+// Account setup commands
+#[tauri::command]
+fn get_provider_presets(
+    state: tauri::State<'_, AppState>,
+    token: String,
+) -> Result<Vec<ProviderPreset>, String> {
+    state.auth_service.extract_user_from_token(&token)?;
+    Ok(state.autodiscovery_service.presets())
+}
+
+#[tauri::command]
+async fn discover_email_settings(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    email_address: String,
+) -> Result<SuggestedAccountSettings, String> {
+    state.auth_service.extract_user_from_token(&token)?;
+    state.autodiscovery_service.discover(&email_address).await
+        .map_err(|e| e.to_string())
+}
+
+// OAuth2 account commands
+#[tauri::command]
+fn get_oauth_providers(
+    state: tauri::State<'_, AppState>,
+    token: String,
+) -> Result<Vec<String>, String> {
+    state.auth_service.extract_user_from_token(&token)?;
+    Ok(state.oauth_service.provider_names())
+}
+
+#[tauri::command]
+async fn start_oauth_account(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    request: StartOAuthAccount,
+) -> Result<OAuthAuthorization, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    RateLimiter::validate(&request.rate_limits)?;
+    state.oauth_service.start_authorization(user.id, request).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn finish_oauth_account(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    oauth_state: String,
+) -> Result<EmailAccount, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.oauth_service.finish_authorization(user.id, &oauth_state).await
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn get_email_accounts(
     state: tauri::State<'_, AppState>,
@@ -233,15 +417,70 @@ fn get_email_accounts(
 }
 
 #[tauri::command]
-fn test_email_connection(
-    _state: tauri::State<'_, AppState>,
-    _token: String,
-    _account_id: i32,
+fn update_email_account_rate_limits(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    account_id: i32,
+    rate_limits: RateLimits,
+) -> Result<String, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    RateLimiter::validate(&rate_limits)?;
+    
+    let updated = state.database.update_email_account_rate_limits(user.id, account_id, &rate_limits)
+        .map_err(|e| e.to_string())?;
+    
+    if updated {
+        Ok("Rate limits updated successfully".to_string())
+    } else {
+        Err("Email account not found".to_string())
+    }
+}
+
+#[tauri::command]
+fn get_rate_limit_status(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    account_id: i32,
+) -> Result<RateLimitStatus, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.rate_limiter.get_status(user.id, account_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn test_email_connection(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    account_id: i32,
 ) -> Result<ConnectionTest, String> {
-    // TODO: Implement email connection testing
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    let (account, credential) = state.credential_service.resolve(user.id, account_id).await
+        .map_err(|e| e.to_string())?;
+    
+    // Test both servers, each with the security mode it is configured for; the probes
+    // block on the network, so they run off the async runtime
+    let results = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<(String, ConnectionTest)>> {
+        let mut results = vec![(
+            format!("SMTP {}:{} ({})", account.smtp_server.as_deref().unwrap_or("-"), account.smtp_port.unwrap_or(587), account.smtp_security.as_str()),
+            EmailService::test_smtp_connection(&account, &credential)?,
+        )];
+        if account.imap_server.is_some() {
+            results.push((
+                format!("IMAP {}:{} ({})", account.imap_server.as_deref().unwrap_or("-"), account.imap_port.unwrap_or(993), account.imap_security.as_str()),
+                EmailService::test_imap_connection(&account, &credential)?,
+            ));
+        }
+        Ok(results)
+    }).await
+        .map_err(|e| format!("Connection test aborted: {}", e))?
+        .map_err(|e| e.to_string())?;
+    
     Ok(ConnectionTest {
-        success: true,
-        message: "Connection test not implemented yet".to_string(),
+        success: results.iter().all(|(_, result)| result.success),
+        message: results.iter()
+            .map(|(server, result)| format!("{}: {}", server, result.message))
+            .collect::<Vec<_>>()
+            .join("; "),
     })
 }
 
@@ -295,6 +534,9 @@ fn create_automation_rule(
     rule_data: CreateAutomationRule,
 ) -> Result<AutomationRule, String> {
     let user = state.auth_service.extract_user_from_token(&token)?;
+    Condition::parse(&rule_data.conditions)?;
+    state.automation_engine.validate_actions(user.id, &rule_data.actions)?;
+    let folders = AutomationEngine::validate_folders(rule_data.folders)?;
     
     // Create a new rule with user_id from token
     let rule_with_user = CreateAutomationRuleWithUser {
@@ -304,6 +546,9 @@ fn create_automation_rule(
         conditions: rule_data.conditions,
         actions: rule_data.actions,
         is_active: Some(true),
+        priority: rule_data.priority,
+        stop_processing: rule_data.stop_processing,
+        folders,
     };
     
     state.database.create_automation_rule(rule_with_user)
@@ -320,6 +565,90 @@ fn get_automation_rules(
         .map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+fn reorder_automation_rules(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    rule_ids: Vec<i32>,
+) -> Result<Vec<AutomationRule>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.database.reorder_automation_rules(user.id, &rule_ids)
+        .map_err(|e| e.to_string())?;
+    
+    state.database.get_automation_rules(user.id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn set_automation_rule_stop_processing(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    rule_id: i32,
+    stop_processing: bool,
+) -> Result<String, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    let updated = state.database.set_automation_rule_stop_processing(user.id, rule_id, stop_processing)
+        .map_err(|e| e.to_string())?;
+    
+    if updated {
+        Ok("Automation rule updated successfully".to_string())
+    } else {
+        Err("Automation rule not found".to_string())
+    }
+}
+
+// Dry run: tries a saved rule or an unsaved draft against messages without performing any action
+#[tauri::command]
+async fn simulate_automation_rule(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    rule_id: Option<i32>,
+    draft: Option<CreateAutomationRule>,
+    source: SimulationSource,
+) -> Result<Vec<RuleSimulation>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    
+    let rule = match (rule_id, draft) {
+        (Some(rule_id), _) => state.database.get_automation_rules(user.id)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .find(|rule| rule.id == rule_id)
+            .ok_or_else(|| "Automation rule not found".to_string())?,
+        (None, Some(draft)) => {
+            Condition::parse(&draft.conditions)?;
+            state.automation_engine.validate_actions(user.id, &draft.actions)?;
+            let folders = AutomationEngine::validate_folders(draft.folders)?;
+            
+            AutomationRule {
+                id: 0,
+                user_id: user.id,
+                rule_name: draft.rule_name,
+                keywords: draft.keywords,
+                conditions: draft.conditions,
+                actions: draft.actions,
+                is_active: true,
+                priority: draft.priority.unwrap_or(0),
+                stop_processing: draft.stop_processing,
+                folders,
+                created_at: Utc::now(),
+            }
+        }
+        (None, None) => return Err("Either rule_id or draft is required".to_string()),
+    };
+    
+    let (emails, account_id, folder) = match source {
+        SimulationSource::Raw { message } => (vec![InboxService::parse_message("raw".to_string(), message.as_bytes())?], None, None),
+        SimulationSource::Email { email } => (vec![*email], None, None),
+        SimulationSource::Recent { email_account_id, limit, folder } => {
+            let limit = limit.unwrap_or(10).min(50);
+            let folder = folder.unwrap_or_else(|| "INBOX".to_string());
+            (state.inbox_service.fetch_recent(user.id, email_account_id, &folder, limit).await?, Some(email_account_id), Some(folder))
+        }
+    };
+    
+    Ok(AutomationEngine::simulate(&rule, &emails, account_id, folder.as_deref())?)
+}
+
 // Email operations
 #[tauri::command]
 async fn send_email(
@@ -336,33 +665,15 @@ async fn send_email(
         .map_err(|e| e.to_string())?
         .ok_or_else(|| "Email account not found".to_string())?;
     
-    // Get the decrypted password
-    let password = state.encryption_service.decrypt(&account.password_encrypted)
-        .map_err(|e| format!("Failed to decrypt password: {}", e))?;
-    
-    // Send the email using the email service
-    let mut email_service = state.email_service.lock().await;
-    email_service.send_email(&account, &password, &email_data).await
-        .map_err(|e| format!("Failed to send email: {}", e))?;
-    
-    // Log the email
-    let log_entry = CreateEmailLog {
+    // Hand the email to the outbox; the worker delivers and logs it
+    state.outbox_service.enqueue(CreateOutboxMessage {
         user_id: user.id,
-        email_account_id: Some(account.id),
-        direction: "sent".to_string(),
-        recipient_email: Some(email_data.to.join(", ")),
-        sender_email: Some(account.email_address.clone()),
-        subject: Some(email_data.subject.clone()),
-        status: "success".to_string(),
-        error_message: None,
-        sent_at: Some(chrono::Utc::now()),
-    };
-    
-    if let Err(e) = state.database.log_email(log_entry) {
-        eprintln!("Failed to log email: {}", e);
-    }
+        email_account_id: account.id,
+        campaign_id: None,
+        message: email_data,
+    }).map_err(|e| format!("Failed to queue email: {}", e))?;
     
-    Ok("Email sent successfully".to_string())
+    Ok("Email queued for delivery".to_string())
 }
 
 #[tauri::command]
@@ -376,13 +687,21 @@ fn send_batch_emails(
 }
 
 #[tauri::command]
-fn check_emails(
-    _state: tauri::State<'_, AppState>,
-    _token: String,
-    _account_id: i32,
-) -> Result<Vec<EmailMessage>, String> {
-    // TODO: Implement email checking
-    Ok(Vec::new())
+async fn check_emails(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    account_id: i32,
+) -> Result<Vec<InboxEmail>, String> {
+    // Manual checks run the same fetch and rule engine as the inbox monitor, on a blocking
+    // thread since the IMAP client is synchronous
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    let inbox_service = Arc::clone(&state.inbox_service);
+    
+    tokio::task::spawn_blocking(move || {
+        tokio::runtime::Handle::current().block_on(inbox_service.check_inbox(user.id, account_id))
+    }).await
+        .map_err(|e| format!("Inbox check aborted: {}", e))?
+        .map_err(|e| e.to_string())
 }
 
 // Scheduling commands
@@ -518,7 +837,24 @@ async fn check_inbox(
     account_id: i32,
 ) -> Result<Vec<InboxEmail>, String> {
     let user = state.auth_service.extract_user_from_token(&token)?;
-    state.inbox_service.check_inbox(user.id, account_id).await
+    let inbox_service = Arc::clone(&state.inbox_service);
+    
+    // The check talks to the IMAP server synchronously, so it gets a blocking thread
+    tokio::task::spawn_blocking(move || {
+        tokio::runtime::Handle::current().block_on(inbox_service.check_inbox(user.id, account_id))
+    }).await
+        .map_err(|e| format!("Inbox check aborted: {}", e))?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_mailboxes(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    account_id: i32,
+) -> Result<Vec<Mailbox>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.inbox_service.list_mailboxes(user.id, account_id).await
         .map_err(|e| e.to_string())
 }
 
@@ -534,6 +870,131 @@ fn toggle_inbox_monitor(
         .map_err(|e| e.to_string())
 }
 
+// The monitor service runs for everyone from startup; these only resume or pause the
+// caller's own monitors
+#[tauri::command]
+fn start_inbox_monitoring(
+    state: tauri::State<'_, AppState>,
+    token: String,
+) -> Result<String, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    let count = state.inbox_service.set_user_monitors_active(user.id, true)
+        .map_err(|e| e.to_string())?;
+    
+    Ok(format!("Inbox monitoring started for {} monitor(s)", count))
+}
+
+#[tauri::command]
+fn stop_inbox_monitoring(
+    state: tauri::State<'_, AppState>,
+    token: String,
+) -> Result<String, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    let count = state.inbox_service.set_user_monitors_active(user.id, false)
+        .map_err(|e| e.to_string())?;
+    
+    Ok(format!("Inbox monitoring stopped for {} monitor(s)", count))
+}
+
+#[tauri::command]
+async fn get_inbox_monitoring_status(
+    state: tauri::State<'_, AppState>,
+    token: String,
+) -> Result<MonitorServiceStatus, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    Ok(state.monitor_service.status(user.id).await)
+}
+
+#[tauri::command]
+fn get_automation_action_log(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    limit: Option<i32>,
+) -> Result<Vec<AutomationActionLog>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.automation_engine.get_action_log(user.id, limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_automation_runs(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    filter: Option<AutomationRunFilter>,
+) -> Result<Vec<AutomationRun>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.automation_engine.get_runs(user.id, &filter.unwrap_or_default())
+        .map_err(|e| e.to_string())
+}
+
+// Stored message commands
+#[tauri::command]
+fn get_messages(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    filter: Option<MessageFilter>,
+) -> Result<Vec<StoredMessage>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.message_store.get_messages(user.id, &filter.unwrap_or_default())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_message(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    message_id: i32,
+) -> Result<StoredMessage, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.message_store.get_message(user.id, message_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn delete_message(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    message_id: i32,
+) -> Result<(), String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.message_store.delete_message(user.id, message_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn rerun_automation_rules(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    message_id: i32,
+) -> Result<InboxEmail, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.inbox_service.rerun_rules(user.id, message_id).await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn search_mail(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    query: String,
+    limit: Option<i32>,
+) -> Result<Vec<SearchResult>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.search_service.search(user.id, &query, limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn get_thread(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    thread_id: i32,
+) -> Result<MessageThread, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.threading_service.get_thread(user.id, thread_id)
+        .map_err(|e| e.to_string())
+}
+
 // Campaign Management Commands
 #[tauri::command]
 fn create_campaign(
@@ -563,9 +1024,9 @@ async fn send_campaign(
     batch_request: BatchEmailRequest,
 ) -> Result<String, String> {
     let user = state.auth_service.extract_user_from_token(&token)?;
-    state.campaign_service.send_batch_emails(user.id, batch_request).await
+    let campaign_id = state.campaign_service.send_batch_emails(user.id, batch_request).await
         .map_err(|e| e.to_string())?;
-    Ok("Campaign sent successfully".to_string())
+    Ok(format!("Campaign {} queued for delivery", campaign_id))
 }
 
 #[tauri::command]
@@ -579,6 +1040,19 @@ fn get_campaign_stats(
         .map_err(|e| e.to_string())
 }
 
+// Outbox Commands
+#[tauri::command]
+fn get_outbox(
+    state: tauri::State<'_, AppState>,
+    token: String,
+    status: Option<String>,
+    limit: Option<i32>,
+) -> Result<Vec<OutboxMessage>, String> {
+    let user = state.auth_service.extract_user_from_token(&token)?;
+    state.outbox_service.get_user_outbox(user.id, status, limit)
+        .map_err(|e| e.to_string())
+}
+
 // Attachment Management Commands
 #[tauri::command]
 fn get_attachments(
@@ -704,13 +1178,23 @@ pub fn run() {
             login_user,
             verify_token,
             create_email_account,
+            get_provider_presets,
+            discover_email_settings,
+            get_oauth_providers,
+            start_oauth_account,
+            finish_oauth_account,
             get_email_accounts,
+            update_email_account_rate_limits,
+            get_rate_limit_status,
             test_email_connection,
             create_email_template,
             get_email_templates,
             get_email_template,
             create_automation_rule,
             get_automation_rules,
+            reorder_automation_rules,
+            set_automation_rule_stop_processing,
+            simulate_automation_rule,
             send_email,
             send_batch_emails,
             check_emails,
@@ -728,12 +1212,27 @@ pub fn run() {
             create_inbox_monitor,
             get_inbox_monitors,
             check_inbox,
+            list_mailboxes,
             toggle_inbox_monitor,
+            start_inbox_monitoring,
+            stop_inbox_monitoring,
+            get_inbox_monitoring_status,
+            get_automation_action_log,
+            get_automation_runs,
+            // Stored messages
+            get_messages,
+            get_message,
+            delete_message,
+            rerun_automation_rules,
+            search_mail,
+            get_thread,
             // Campaign Management
             create_campaign,
             get_campaigns,
             send_campaign,
             get_campaign_stats,
+            // Outbox
+            get_outbox,
             // Attachment Management
             get_attachments,
             get_attachment_categories,
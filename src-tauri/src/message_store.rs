@@ -0,0 +1,308 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::{info, warn};
+use rusqlite::{params, OptionalExtension, Row};
+use crate::models::*;
+use crate::database::Database;
+use crate::threading::{ThreadHeaders, ThreadingService};
+
+const MESSAGE_COLUMNS: &str = "id, user_id, email_account_id, folder, uid, message_id, in_reply_to, references_header, subject, sender, recipients, cc, reply_to, body_text, body_html, attachments, flags, thread_id, size, is_auto_generated, received_at, created_at";
+
+/// Where a message lives on the server. UIDs are only unique within one UIDVALIDITY.
+#[derive(Debug, Clone)]
+pub struct MessageLocation {
+    pub account_id: i32,
+    pub folder: String,
+    pub uid_validity: u32,
+    pub uid: u32,
+}
+
+/// Keeps every received message: parsed fields in the `messages` table and the raw
+/// message as an .eml file, so history survives offline and rules can be run again.
+pub struct MessageStore {
+    database: Arc<Database>,
+    messages_dir: PathBuf,
+}
+
+impl MessageStore {
+    pub fn new(database: Arc<Database>, app_data_dir: &Path) -> Result<Self, AppError> {
+        let messages_dir = app_data_dir.join("messages");
+
+        if !messages_dir.exists() {
+            fs::create_dir_all(&messages_dir)
+                .map_err(|e| AppError::Internal(format!("Failed to create messages directory: {}", e)))?;
+        }
+
+        Ok(Self {
+            database,
+            messages_dir,
+        })
+    }
+
+    /// Saves a fetched message and returns its id. A message that is already stored only
+    /// has its flags refreshed.
+    pub fn store(
+        &self,
+        user_id: i32,
+        location: &MessageLocation,
+        email: &InboxEmail,
+        raw: &[u8],
+        flags: &[String],
+    ) -> Result<i32, AppError> {
+        let flags_json = to_json(flags)?;
+
+        if let Some(id) = self.find(location)? {
+            self.database.transaction(|tx| {
+                tx.execute("UPDATE messages SET flags = ?1 WHERE id = ?2", params![flags_json, id])?;
+                Ok(())
+            })?;
+            return Ok(id);
+        }
+
+        let raw_path = self.raw_path(location);
+        if let Some(dir) = raw_path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| AppError::Internal(format!("Failed to create message directory: {}", e)))?;
+        }
+        fs::write(&raw_path, raw)
+            .map_err(|e| AppError::Internal(format!("Failed to write message file: {}", e)))?;
+
+        let result = self.database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO messages (
+                    user_id, email_account_id, folder, uid_validity, uid, message_id, in_reply_to,
+                    references_header, subject, sender, recipients, cc, reply_to, headers, body_text,
+                    body_html, attachments, flags, raw_path, size, is_auto_generated, received_at
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)",
+                params![
+                    user_id,
+                    location.account_id,
+                    location.folder,
+                    location.uid_validity,
+                    location.uid,
+                    email.message_id,
+                    email.in_reply_to,
+                    email.references,
+                    email.subject,
+                    email.sender,
+                    to_json(&email.to)?,
+                    to_json(&email.cc)?,
+                    to_json(&email.reply_to)?,
+                    to_json(&email.headers)?,
+                    email.body,
+                    email.html_body,
+                    to_json(&email.attachments)?,
+                    flags_json,
+                    raw_path.to_string_lossy().to_string(),
+                    email.size as i64,
+                    email.is_auto_generated,
+                    email.received_at,
+                ],
+            )?;
+            let id = tx.last_insert_rowid() as i32;
+
+            let thread_id = ThreadingService::assign_in(tx, user_id, &ThreadHeaders {
+                message_id: email.message_id.as_deref(),
+                in_reply_to: email.in_reply_to.as_deref(),
+                references: email.references.as_deref(),
+                subject: &email.subject,
+                date: email.received_at,
+            })?;
+            tx.execute("UPDATE messages SET thread_id = ?1 WHERE id = ?2", [thread_id, id])?;
+
+            Ok(id)
+        });
+
+        // Don't leave a file behind that no row points at
+        if result.is_err() {
+            let _ = fs::remove_file(&raw_path);
+        }
+
+        let id = result?;
+        info!("Stored message {} ({} UID {}) for account {}", id, location.folder, location.uid, location.account_id);
+        Ok(id)
+    }
+
+    pub fn get_message(&self, user_id: i32, message_id: i32) -> Result<StoredMessage, AppError> {
+        let conn = self.database.get_connection()?;
+        conn.query_row(
+            &format!("SELECT {} FROM messages WHERE id = ?1 AND user_id = ?2", MESSAGE_COLUMNS),
+            [message_id, user_id],
+            Self::message_from_row,
+        ).optional()?
+            .ok_or_else(|| AppError::NotFound("Message not found".to_string()))
+    }
+
+    /// Newest first.
+    pub fn get_messages(&self, user_id: i32, filter: &MessageFilter) -> Result<Vec<StoredMessage>, AppError> {
+        let conn = self.database.get_connection()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE user_id = ?1
+               AND (?2 IS NULL OR email_account_id = ?2)
+               AND (?3 IS NULL OR folder = ?3)
+             ORDER BY received_at DESC, id DESC
+             LIMIT ?4 OFFSET ?5",
+            MESSAGE_COLUMNS
+        ))?;
+
+        let messages = stmt.query_map(
+            params![
+                user_id,
+                filter.email_account_id,
+                filter.folder,
+                filter.limit.unwrap_or(50),
+                filter.offset.unwrap_or(0),
+            ],
+            Self::message_from_row,
+        )?.collect::<Result<Vec<_>, _>>()?;
+
+        Ok(messages)
+    }
+
+    /// The stored message with its server location and the raw bytes it was parsed from.
+    pub fn get_raw(&self, user_id: i32, message_id: i32) -> Result<(MessageLocation, Vec<u8>), AppError> {
+        let (location, raw_path) = {
+            let conn = self.database.get_connection()?;
+            conn.query_row(
+                "SELECT email_account_id, folder, uid_validity, uid, raw_path FROM messages WHERE id = ?1 AND user_id = ?2",
+                [message_id, user_id],
+                |row| Ok((
+                    MessageLocation {
+                        account_id: row.get(0)?,
+                        folder: row.get(1)?,
+                        uid_validity: row.get(2)?,
+                        uid: row.get(3)?,
+                    },
+                    row.get::<_, String>(4)?,
+                )),
+            ).optional()?
+                .ok_or_else(|| AppError::NotFound("Message not found".to_string()))?
+        };
+
+        let raw = fs::read(&raw_path)
+            .map_err(|e| AppError::Internal(format!("Failed to read message file {}: {}", raw_path, e)))?;
+
+        Ok((location, raw))
+    }
+
+    pub fn delete_message(&self, user_id: i32, message_id: i32) -> Result<(), AppError> {
+        let raw_path = self.database.transaction(|tx| {
+            let raw_path: Option<String> = tx.query_row(
+                "SELECT raw_path FROM messages WHERE id = ?1 AND user_id = ?2",
+                [message_id, user_id],
+                |row| row.get(0),
+            ).optional()?;
+            let raw_path = raw_path.ok_or_else(|| AppError::NotFound("Message not found".to_string()))?;
+
+            tx.execute("DELETE FROM messages WHERE id = ?1", [message_id])?;
+            Ok(raw_path)
+        })?;
+
+        if let Err(e) = fs::remove_file(&raw_path) {
+            warn!("Failed to delete message file {}: {}", raw_path, e);
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, location: &MessageLocation) -> Result<Option<i32>, AppError> {
+        let conn = self.database.get_connection()?;
+        Ok(conn.query_row(
+            "SELECT id FROM messages WHERE email_account_id = ?1 AND folder = ?2 AND uid_validity = ?3 AND uid = ?4",
+            params![location.account_id, location.folder, location.uid_validity, location.uid],
+            |row| row.get(0),
+        ).optional()?)
+    }
+
+    /// `<messages>/<account>/<folder>/<uidvalidity>-<uid>.eml`, with the folder name
+    /// reduced to characters that are safe in a path on every platform.
+    fn raw_path(&self, location: &MessageLocation) -> PathBuf {
+        let folder: String = location.folder.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+
+        self.messages_dir
+            .join(location.account_id.to_string())
+            .join(folder)
+            .join(format!("{}-{}.eml", location.uid_validity, location.uid))
+    }
+
+    fn message_from_row(row: &Row) -> rusqlite::Result<StoredMessage> {
+        let json_list = |index: usize| -> rusqlite::Result<Vec<String>> {
+            let json: String = row.get(index)?;
+            serde_json::from_str(&json)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+        };
+
+        Ok(StoredMessage {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            email_account_id: row.get(2)?,
+            folder: row.get(3)?,
+            uid: row.get(4)?,
+            message_id: row.get(5)?,
+            in_reply_to: row.get(6)?,
+            references: row.get(7)?,
+            subject: row.get(8)?,
+            sender: row.get(9)?,
+            to: json_list(10)?,
+            cc: json_list(11)?,
+            reply_to: json_list(12)?,
+            body_text: row.get(13)?,
+            body_html: row.get(14)?,
+            attachments: json_list(15)?,
+            flags: json_list(16)?,
+            thread_id: row.get(17)?,
+            size: row.get(18)?,
+            is_auto_generated: row.get(19)?,
+            received_at: row.get(20)?,
+            created_at: row.get(21)?,
+        })
+    }
+}
+
+fn to_json<T: serde::Serialize + ?Sized>(value: &T) -> Result<String, AppError> {
+    serde_json::to_string(value)
+        .map_err(|e| AppError::Internal(format!("Failed to serialize message fields: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_support::{seed_account, temp_database};
+    use crate::inbox_service::InboxService;
+
+    #[test]
+    fn test_store_is_idempotent_per_location_and_keeps_raw() {
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "store".to_string(),
+            email: "store@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+        let account_id = seed_account(&database, user.id, "support@example.com");
+
+        let store = MessageStore::new(database.shared(), database.dir()).unwrap();
+        let raw = b"From: Jane <jane@example.com>\r\nSubject: Invoice\r\nMessage-ID: <i1@example.com>\r\n\r\nSee attached.\r\n";
+        let email = InboxService::parse_message("7".to_string(), raw).unwrap();
+        let location = MessageLocation { account_id, folder: "INBOX/Billing".to_string(), uid_validity: 9, uid: 7 };
+
+        let id = store.store(user.id, &location, &email, raw, &[]).unwrap();
+        let again = store.store(user.id, &location, &email, raw, &["\\Seen".to_string()]).unwrap();
+        assert_eq!(id, again);
+
+        let message = store.get_message(user.id, id).unwrap();
+        assert_eq!(message.subject.as_deref(), Some("Invoice"));
+        assert_eq!(message.flags, vec!["\\Seen"]);
+        assert_eq!(store.get_messages(user.id, &MessageFilter::default()).unwrap().len(), 1);
+
+        let (stored_location, stored_raw) = store.get_raw(user.id, id).unwrap();
+        assert_eq!((stored_location.folder.as_str(), stored_location.uid), ("INBOX/Billing", 7));
+        assert_eq!(stored_raw, raw);
+
+        store.delete_message(user.id, id).unwrap();
+        assert!(matches!(store.get_message(user.id, id), Err(AppError::NotFound(_))));
+    }
+}
@@ -0,0 +1,128 @@
+use rusqlite::{Connection, params};
+use anyhow::Result;
+use chrono::Utc;
+use log::info;
+
+// Numbered schema migrations from the `migrations/` directory, in the order they must be
+// applied. New migrations are appended here; a file that has shipped is never edited.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("../migrations/001_initial.sql")),
+    (2, "reconcile_campaigns", include_str!("../migrations/002_reconcile_campaigns.sql")),
+    (3, "outbox", include_str!("../migrations/003_outbox.sql")),
+    (4, "account_rate_limits", include_str!("../migrations/004_account_rate_limits.sql")),
+    (5, "auto_reply_cooldowns", include_str!("../migrations/005_auto_reply_cooldowns.sql")),
+    (6, "automation_action_log", include_str!("../migrations/006_automation_action_log.sql")),
+    (7, "rule_priority", include_str!("../migrations/007_rule_priority.sql")),
+    (8, "automation_runs", include_str!("../migrations/008_automation_runs.sql")),
+    (9, "monitor_idle", include_str!("../migrations/009_monitor_idle.sql")),
+    (10, "imap_folder_sync", include_str!("../migrations/010_imap_folder_sync.sql")),
+    (11, "messages", include_str!("../migrations/011_messages.sql")),
+    (12, "search_index", include_str!("../migrations/012_search_index.sql")),
+    (13, "threads", include_str!("../migrations/013_threads.sql")),
+    (14, "folders", include_str!("../migrations/014_folders.sql")),
+    (15, "oauth", include_str!("../migrations/015_oauth.sql")),
+    (16, "connection_security", include_str!("../migrations/016_connection_security.sql")),
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|(version, _, _)| *version).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    let version: Option<i64> = conn.query_row(
+        "SELECT MAX(version) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(version.unwrap_or(0))
+}
+
+/// Brings the database up to the latest known schema version.
+///
+/// Each pending migration runs in its own transaction together with the row that records
+/// it, so a failed migration leaves the database at the previous version. A database that
+/// was written by a newer build is rejected rather than silently used.
+pub fn run_migrations(conn: &mut Connection) -> Result<()> {
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )
+        "#,
+        [],
+    )?;
+
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(anyhow::anyhow!(
+            "Database schema version {} is newer than the latest version {} supported by this build",
+            current,
+            latest
+        ));
+    }
+
+    for (version, name, sql) in MIGRATIONS.iter().filter(|(version, _, _)| *version > current) {
+        let tx = conn.transaction()?;
+
+        tx.execute_batch(sql)
+            .map_err(|e| anyhow::anyhow!("Migration {:03}_{} failed: {}", version, name, e))?;
+
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![version, name, Utc::now().to_rfc3339()],
+        )?;
+
+        tx.commit()?;
+        info!("Applied database migration {:03}_{}", version, name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_apply_in_order_and_are_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        run_migrations(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        conn.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+
+        conn.execute(
+            "INSERT INTO users (username, email, password_hash) VALUES ('test', 'test@example.com', 'hash')",
+            [],
+        ).unwrap();
+        let user_id = conn.last_insert_rowid();
+
+        // The reconciled campaign table exposes the columns CampaignService queries
+        conn.execute(
+            "INSERT INTO email_campaigns (user_id, name, subject, status) VALUES (?1, 'Test', 'Hello', 'partial')",
+            [user_id],
+        ).unwrap();
+
+        run_migrations(&mut conn).unwrap();
+        let applied: i64 = conn.query_row("SELECT COUNT(*) FROM schema_migrations", [], |row| row.get(0)).unwrap();
+        assert_eq!(applied, latest_version());
+    }
+
+    #[test]
+    fn test_newer_schema_is_rejected() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run_migrations(&mut conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, 'future', ?2)",
+            params![latest_version() + 1, Utc::now().to_rfc3339()],
+        ).unwrap();
+
+        assert!(run_migrations(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use chrono::{DateTime, Utc};
+use mailparse::{DispositionType, MailAddr, MailHeaderMap, ParsedMail};
+use regex::Regex;
+use crate::models::AppError;
+
+/// An incoming message with its headers decoded and its MIME tree flattened into bodies
+/// and attachments. Transfer encodings, charsets and RFC 2047 encoded words are all
+/// decoded, so callers only ever see UTF-8 text and raw attachment bytes.
+#[derive(Debug, Default)]
+pub struct MimeMessage {
+    /// "Name <address>", or just the address when there is no display name
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub reply_to: Vec<String>,
+    pub subject: Option<String>,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub headers: Vec<(String, String)>,
+    pub text_body: Option<String>,
+    pub html_body: Option<String>,
+    pub attachments: Vec<MimeAttachment>,
+}
+
+#[derive(Debug)]
+pub struct MimeAttachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub content_id: Option<String>,
+    /// Displayed inside the message (e.g. images referenced from the HTML) rather than attached
+    pub inline: bool,
+    pub content: Vec<u8>,
+}
+
+pub fn parse(raw: &[u8]) -> Result<MimeMessage, AppError> {
+    let mail = mailparse::parse_mail(raw)
+        .map_err(|e| AppError::Validation(format!("Failed to parse message: {}", e)))?;
+    Ok(MimeMessage::from_mail(&mail))
+}
+
+impl MimeMessage {
+    pub fn from_mail(mail: &ParsedMail) -> Self {
+        let header = |name: &str| mail.headers.get_first_value(name)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+
+        let mut message = MimeMessage {
+            from: addresses(mail, "From", true).into_iter().next(),
+            to: addresses(mail, "To", false),
+            cc: addresses(mail, "Cc", false),
+            reply_to: addresses(mail, "Reply-To", false),
+            subject: header("Subject"),
+            message_id: header("Message-ID"),
+            in_reply_to: header("In-Reply-To"),
+            references: header("References"),
+            date: header("Date")
+                .and_then(|date| mailparse::dateparse(&date).ok())
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)),
+            headers: mail.headers.iter().map(|h| (h.get_key(), h.get_value())).collect(),
+            ..Default::default()
+        };
+
+        message.collect_parts(mail);
+
+        // HTML-only mail still needs a text body for rules and templates
+        if message.text_body.is_none() {
+            message.text_body = message.html_body.as_deref().map(html_to_text);
+        }
+
+        message
+    }
+
+    /// Walks the MIME tree; the first text/plain and text/html leaves that are not
+    /// attachments become the bodies, everything else with content is an attachment.
+    fn collect_parts(&mut self, part: &ParsedMail) {
+        let mimetype = part.ctype.mimetype.to_lowercase();
+
+        if mimetype.starts_with("multipart/") {
+            for subpart in &part.subparts {
+                self.collect_parts(subpart);
+            }
+            return;
+        }
+
+        let disposition = part.get_content_disposition();
+        let filename = disposition.params.get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .map(|name| decode_words(name));
+        let attached = disposition.disposition == DispositionType::Attachment;
+
+        if !attached && filename.is_none() {
+            let body = match mimetype.as_str() {
+                "text/plain" => Some(&mut self.text_body),
+                "text/html" => Some(&mut self.html_body),
+                _ => None,
+            };
+            if let Some(body) = body.filter(|body| body.is_none()) {
+                *body = Some(part.get_body().unwrap_or_default());
+                return;
+            }
+        }
+
+        let content = match part.get_body_raw() {
+            Ok(content) if !content.is_empty() => content,
+            _ => return,
+        };
+
+        let filename = filename.unwrap_or_else(|| match mimetype.as_str() {
+            // A forwarded message, which has no filename of its own
+            "message/rfc822" => "forwarded.eml".to_string(),
+            _ => format!("attachment-{}", self.attachments.len() + 1),
+        });
+
+        let content_id = part.headers.get_first_value("Content-ID")
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string());
+
+        self.attachments.push(MimeAttachment {
+            filename,
+            mime_type: mimetype,
+            // Parts the HTML refers to by Content-ID; an inline PDF is still a file the sender attached
+            inline: !attached && content_id.is_some(),
+            content_id,
+            content,
+        });
+    }
+
+    /// Attachments the sender attached, leaving out images embedded in the HTML.
+    pub fn attached_files(&self) -> impl Iterator<Item = &MimeAttachment> {
+        self.attachments.iter().filter(|attachment| !attachment.inline)
+    }
+}
+
+fn addresses(mail: &ParsedMail, name: &str, with_display_name: bool) -> Vec<String> {
+    let header = match mail.headers.get_first_header(name) {
+        Some(header) => header,
+        None => return Vec::new(),
+    };
+
+    let render = |single: &mailparse::SingleInfo| match (&single.display_name, with_display_name) {
+        (Some(display_name), true) => format!("{} <{}>", display_name, single.addr),
+        _ => single.addr.clone(),
+    };
+
+    mailparse::addrparse_header(header)
+        .map(|addresses| addresses.iter()
+            .flat_map(|address| match address {
+                MailAddr::Single(single) => vec![render(single)],
+                MailAddr::Group(group) => group.addrs.iter().map(render).collect(),
+            })
+            .collect())
+        .unwrap_or_default()
+}
+
+/// Decodes RFC 2047 encoded words, which some clients put in filename parameters even
+/// though only RFC 2231 encoding is allowed there.
+fn decode_words(value: &str) -> String {
+    if !value.contains("=?") {
+        return value.to_string();
+    }
+
+    mailparse::parse_header(format!("X: {}", value).as_bytes())
+        .map(|(header, _)| header.get_value())
+        .unwrap_or_else(|_| value.to_string())
+}
+
+/// A readable plain-text rendering of an HTML body: scripts and styles dropped, block
+/// elements turned into line breaks, tags stripped and common entities decoded.
+pub fn html_to_text(html: &str) -> String {
+    let hidden = Regex::new(r"(?is)<(script|style|head)\b.*?</(script|style|head)\s*>").unwrap();
+    let breaks = Regex::new(r"(?i)<br\s*/?>|</(p|div|li|tr|h[1-6])\s*>").unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let blank_lines = Regex::new(r"\n[ \t]*\n(\s*\n)+").unwrap();
+
+    let text = hidden.replace_all(html, "");
+    let text = breaks.replace_all(&text, "\n");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    blank_lines.replace_all(text.trim(), "\n\n").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decodes_bodies_headers_and_attachments() {
+        let raw = b"From: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\r\n\
+            To: support@example.com\r\n\
+            Reply-To: Billing <billing@example.com>\r\n\
+            Subject: =?UTF-8?B?UmVjaG51bmcgZsO8ciBNw6Ryeg==?=\r\n\
+            Message-ID: <m2@example.com>\r\n\
+            In-Reply-To: <m1@example.com>\r\n\
+            MIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+            \r\n\
+            --outer\r\n\
+            Content-Type: multipart/alternative; boundary=\"inner\"\r\n\
+            \r\n\
+            --inner\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Gr=FC=DFe, siehe Anhang.\r\n\
+            --inner\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            \r\n\
+            <p>Gr\xc3\xbc\xc3\x9fe</p>\r\n\
+            --inner--\r\n\
+            --outer\r\n\
+            Content-Type: application/pdf; name=\"=?UTF-8?Q?Rechnung_M=C3=A4rz.pdf?=\"\r\n\
+            Content-Disposition: attachment\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            JVBERi0xLjQ=\r\n\
+            --outer--\r\n";
+
+        let message = parse(raw).unwrap();
+        assert_eq!(message.from.as_deref(), Some("Andr\u{e9} <andre@example.com>"));
+        assert_eq!(message.reply_to, vec!["billing@example.com"]);
+        assert_eq!(message.subject.as_deref(), Some("Rechnung f\u{fc}r M\u{e4}rz"));
+        assert_eq!(message.in_reply_to.as_deref(), Some("<m1@example.com>"));
+        assert_eq!(message.text_body.as_deref().map(str::trim), Some("Gr\u{fc}\u{df}e, siehe Anhang."));
+        assert_eq!(message.html_body.as_deref().map(str::trim), Some("<p>Gr\u{fc}\u{df}e</p>"));
+
+        let attachment = &message.attachments[0];
+        assert_eq!((attachment.filename.as_str(), attachment.mime_type.as_str()), ("Rechnung M\u{e4}rz.pdf", "application/pdf"));
+        assert_eq!(attachment.content, b"%PDF-1.4");
+        assert_eq!(message.attached_files().count(), 1);
+    }
+
+    #[test]
+    fn test_html_only_mail_gets_a_text_body() {
+        let raw = b"From: news@example.com\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <html><head><style>p { color: red }</style></head>\
+            <body><p>Tom &amp; Jerry</p><p>Line&nbsp;two<br>three</p></body></html>\r\n";
+
+        let message = parse(raw).unwrap();
+        assert_eq!(message.text_body.as_deref(), Some("Tom & Jerry\nLine two\nthree"));
+        assert!(message.attachments.is_empty());
+    }
+}
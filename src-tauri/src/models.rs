@@ -50,10 +50,73 @@ pub struct EmailAccount {
     pub smtp_port: Option<i32>,
     pub username: String,
     pub password_encrypted: String,
+    /// "password", or "oauth2" for accounts that sign in through `oauth_provider`
+    pub auth_type: String,
+    pub oauth_provider: Option<String>,
+    pub smtp_security: ConnectionSecurity,
+    pub imap_security: ConnectionSecurity,
+    /// A PEM certificate to trust in addition to the system roots, for self-hosted servers
+    pub tls_ca_certificate: Option<String>,
+    pub tls_accept_invalid_certs: bool,
     pub is_active: bool,
+    pub rate_limits: RateLimits,
     pub created_at: DateTime<Utc>,
 }
 
+/// How a connection to a mail server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionSecurity {
+    /// TLS from the first byte (IMAP on 993, SMTP on 465)
+    ImplicitTls,
+    /// Upgrade with STARTTLS and fail if the server doesn't offer it
+    StartTlsRequired,
+    /// Upgrade with STARTTLS when the server offers it, otherwise stay in plaintext
+    StartTlsOpportunistic,
+    /// No TLS at all, e.g. for a local development relay
+    Plaintext,
+}
+
+impl ConnectionSecurity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionSecurity::ImplicitTls => "implicit_tls",
+            ConnectionSecurity::StartTlsRequired => "start_tls_required",
+            ConnectionSecurity::StartTlsOpportunistic => "start_tls_opportunistic",
+            ConnectionSecurity::Plaintext => "plaintext",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "implicit_tls" => Some(ConnectionSecurity::ImplicitTls),
+            "start_tls_required" => Some(ConnectionSecurity::StartTlsRequired),
+            "start_tls_opportunistic" => Some(ConnectionSecurity::StartTlsOpportunistic),
+            "plaintext" => Some(ConnectionSecurity::Plaintext),
+            _ => None,
+        }
+    }
+
+    /// The usual mode for a port: 465 and 993 are implicit TLS, anything else upgrades with STARTTLS.
+    pub fn for_port(port: i32) -> Self {
+        match port {
+            465 | 993 => ConnectionSecurity::ImplicitTls,
+            _ => ConnectionSecurity::StartTlsRequired,
+        }
+    }
+}
+
+/// Sending quotas for an account. `None` means unlimited; the window is in local hours
+/// (0-23) and wraps past midnight when `send_window_start` is after `send_window_end`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    pub max_per_minute: Option<i32>,
+    pub max_per_hour: Option<i32>,
+    pub max_per_day: Option<i32>,
+    pub send_window_start: Option<i32>,
+    pub send_window_end: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateEmailAccount {
     pub account_name: String,
@@ -64,6 +127,17 @@ pub struct CreateEmailAccount {
     pub smtp_port: Option<i32>,
     pub username: String,
     pub password: String,
+    /// Defaults to what the port usually means, see [`ConnectionSecurity::for_port`]
+    #[serde(default)]
+    pub smtp_security: Option<ConnectionSecurity>,
+    #[serde(default)]
+    pub imap_security: Option<ConnectionSecurity>,
+    #[serde(default)]
+    pub tls_ca_certificate: Option<String>,
+    #[serde(default)]
+    pub tls_accept_invalid_certs: bool,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,7 +151,92 @@ pub struct CreateEmailAccountWithUser {
     pub smtp_port: Option<i32>,
     pub username: String,
     pub password_encrypted: String,
+    pub auth_type: String,
+    pub oauth_provider: Option<String>,
+    pub smtp_security: ConnectionSecurity,
+    pub imap_security: ConnectionSecurity,
+    pub tls_ca_certificate: Option<String>,
+    pub tls_accept_invalid_certs: bool,
     pub is_active: Option<bool>,
+    pub rate_limits: RateLimits,
+}
+
+/// The SASL mechanism an OAuth2 access token is presented with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SaslMechanism {
+    #[serde(rename = "XOAUTH2")]
+    Xoauth2,
+    #[serde(rename = "OAUTHBEARER")]
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaslMechanism::Xoauth2 => "XOAUTH2",
+            SaslMechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// What an account logs in to SMTP and IMAP with, ready to use.
+#[derive(Clone)]
+pub enum MailCredential {
+    Password(String),
+    /// A short-lived access token from the account's OAuth2 provider
+    OAuth2 { access_token: String, mechanism: SaslMechanism },
+}
+
+/// Starts adding an account that signs in with OAuth2 instead of a password.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartOAuthAccount {
+    /// A configured provider, e.g. "google" or "microsoft"
+    pub provider: String,
+    pub account_name: String,
+    pub email_address: String,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+/// Where to send the user to grant access; `state` identifies the pending sign-in.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthAuthorization {
+    pub authorization_url: String,
+    pub state: String,
+}
+
+/// A well-known mail provider's servers, offered when setting up an account.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProviderPreset {
+    pub id: String,
+    pub name: String,
+    /// Address domains the provider hosts, e.g. "gmail.com" and "googlemail.com"
+    pub domains: Vec<String>,
+    pub imap_server: String,
+    pub imap_port: i32,
+    pub smtp_server: String,
+    pub smtp_port: i32,
+    /// The OAuth2 provider to sign in with, when one is supported
+    pub oauth_provider: Option<String>,
+    /// Whether password sign-in needs an app-specific password rather than the account's own
+    pub requires_app_password: bool,
+}
+
+/// Server settings suggested for an address, in the shape `CreateEmailAccount` takes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SuggestedAccountSettings {
+    /// Where the settings came from: "preset", "autoconfig", "ispdb" or "srv"
+    pub source: String,
+    pub provider_name: Option<String>,
+    pub imap_server: Option<String>,
+    pub imap_port: Option<i32>,
+    pub smtp_server: Option<String>,
+    pub smtp_port: Option<i32>,
+    pub smtp_security: Option<ConnectionSecurity>,
+    pub imap_security: Option<ConnectionSecurity>,
+    pub username: String,
+    pub oauth_provider: Option<String>,
+    pub requires_app_password: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -118,9 +277,49 @@ pub struct AutomationRule {
     pub conditions: serde_json::Value,
     pub actions: serde_json::Value,
     pub is_active: bool,
+    /// Rules run in ascending priority order
+    pub priority: i32,
+    /// When this rule matches, no later rule runs on the same message
+    pub stop_processing: bool,
+    /// Folders whose mail the rule runs on; `*` in an entry matches any run of characters
+    pub folders: Vec<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// One step of an automation rule, stored in `automation_rules.actions` as a JSON array of
+/// objects tagged by `type`, e.g. `{"type": "move_to_folder", "folder": "Invoices"}`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AutomationAction {
+    AutoReply { template_id: i32 },
+    MarkAsRead,
+    MoveToFolder { folder: String },
+    Forward { to: Vec<String>, note: Option<String> },
+    Flag,
+    AddLabel { label: String },
+    SaveAttachments,
+    AddToContactList { contact_list_id: i32 },
+    Delete,
+    HttpWebhook { url: String },
+}
+
+impl AutomationAction {
+    pub fn action_type(&self) -> &'static str {
+        match self {
+            AutomationAction::AutoReply { .. } => "auto_reply",
+            AutomationAction::MarkAsRead => "mark_as_read",
+            AutomationAction::MoveToFolder { .. } => "move_to_folder",
+            AutomationAction::Forward { .. } => "forward",
+            AutomationAction::Flag => "flag",
+            AutomationAction::AddLabel { .. } => "add_label",
+            AutomationAction::SaveAttachments => "save_attachments",
+            AutomationAction::AddToContactList { .. } => "add_to_contact_list",
+            AutomationAction::Delete => "delete",
+            AutomationAction::HttpWebhook { .. } => "http_webhook",
+        }
+    }
+}
+
 #[derive(Debug)]
 struct AutomationRuleRow {
     pub id: i32,
@@ -139,6 +338,14 @@ pub struct CreateAutomationRule {
     pub keywords: Vec<String>,
     pub conditions: serde_json::Value,
     pub actions: serde_json::Value,
+    /// Defaults to after the user's existing rules
+    #[serde(default)]
+    pub priority: Option<i32>,
+    #[serde(default)]
+    pub stop_processing: bool,
+    /// Defaults to INBOX only
+    #[serde(default)]
+    pub folders: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -149,6 +356,9 @@ pub struct CreateAutomationRuleWithUser {
     pub conditions: serde_json::Value,
     pub actions: serde_json::Value,
     pub is_active: Option<bool>,
+    pub priority: Option<i32>,
+    pub stop_processing: bool,
+    pub folders: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -220,7 +430,7 @@ pub struct CreateScheduledEmailWithUser {
     pub recurrence_pattern: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EmailMessage {
     pub to: Vec<String>,
     pub cc: Option<Vec<String>>,
@@ -228,6 +438,40 @@ pub struct EmailMessage {
     pub subject: String,
     pub body: String,
     pub attachments: Option<Vec<String>>,
+    /// This message's own Message-ID, assigned when it is queued
+    #[serde(default)]
+    pub message_id: Option<String>,
+    /// Message-ID of the message being replied to, for threading
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    #[serde(default)]
+    pub references: Option<String>,
+    /// Value for the RFC 3834 `Auto-Submitted` header, e.g. "auto-replied"
+    #[serde(default)]
+    pub auto_submitted: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboxMessage {
+    pub id: i32,
+    pub user_id: i32,
+    pub email_account_id: i32,
+    pub campaign_id: Option<i32>,
+    pub message: EmailMessage,
+    pub status: String, // 'queued', 'sending', 'sent', 'deferred', 'bounced'
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct CreateOutboxMessage {
+    pub user_id: i32,
+    pub email_account_id: i32,
+    pub campaign_id: Option<i32>,
+    pub message: EmailMessage,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -261,6 +505,8 @@ pub struct ConnectionTest {
 pub enum AppError {
     #[error("Database error: {0}")]
     Database(#[from] rusqlite::Error),
+    #[error("Connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
     #[error("Authentication error: {0}")]
     Auth(String),
     #[error("Email error: {0}")]
@@ -293,7 +539,8 @@ impl From<anyhow::Error> for AppError {
 pub struct EmailAttachment {
     pub id: i32,
     pub user_id: i32,
-    pub email_log_id: i32,
+    pub email_log_id: Option<i32>,
+    pub message_id: Option<i32>, // stored message the attachment came from
     pub filename: String,
     pub original_filename: String,
     pub file_path: String,
@@ -308,7 +555,9 @@ pub struct EmailAttachment {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateEmailAttachment {
     pub user_id: i32,
-    pub email_log_id: i32,
+    pub email_log_id: Option<i32>,
+    #[serde(default)]
+    pub message_id: Option<i32>,
     pub filename: String,
     pub original_filename: String,
     pub file_path: String,
@@ -397,6 +646,11 @@ pub struct InboxMonitor {
     pub check_interval: i32,
     pub last_check: Option<DateTime<Utc>>,
     pub auto_reply_template_id: Option<i32>,
+    pub auto_reply_cooldown: i32,
+    /// Keep an IMAP IDLE connection open and check as soon as mail arrives
+    pub use_idle: bool,
+    /// Mailbox names or LIST patterns such as `Projects/*`
+    pub folders: Vec<String>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -405,17 +659,228 @@ pub struct CreateInboxMonitor {
     pub email_account_id: i32,
     pub check_interval: Option<i32>,
     pub auto_reply_template_id: Option<i32>,
+    pub auto_reply_cooldown: Option<i32>, // seconds between auto-replies to the same sender
+    #[serde(default)]
+    pub use_idle: bool,
+    /// Defaults to INBOX only
+    #[serde(default)]
+    pub folders: Option<Vec<String>>,
+}
+
+/// A mailbox on an account's IMAP server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mailbox {
+    pub name: String,
+    pub delimiter: Option<String>,
+    pub attributes: Vec<String>,
+    /// The RFC 6154 role ("sent", "junk", "archive", "trash", "drafts", ...), from the
+    /// server's special-use attributes or, failing those, the folder's usual name
+    pub special_use: Option<String>,
+    pub selectable: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AutomationActionLog {
+    pub id: i32,
+    pub run_id: Option<i32>,
+    pub rule_id: Option<i32>,
+    pub email_account_id: Option<i32>,
+    pub message_uid: String,
+    pub action_type: String,
+    pub status: String,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One rule matching one message: why it matched and what each of its actions did.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AutomationRun {
+    pub id: i32,
+    pub rule_id: Option<i32>,
+    pub rule_name: String,
+    pub email_account_id: Option<i32>,
+    pub message_uid: String,
+    pub message_id: Option<String>,
+    pub sender: String,
+    pub subject: String,
+    pub predicates: Vec<PredicateTrace>,
+    /// running, success, partial or failed
+    pub status: String,
+    pub actions: Vec<AutomationActionLog>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A received message kept in the local store.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredMessage {
+    pub id: i32,
+    pub user_id: i32,
+    pub email_account_id: i32,
+    pub folder: String,
+    pub uid: u32,
+    pub message_id: Option<String>, // Message-ID header
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub reply_to: Vec<String>,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
+    pub attachments: Vec<String>, // filenames
+    pub flags: Vec<String>,
+    pub thread_id: Option<i32>,
+    pub size: i64,
+    pub is_auto_generated: bool,
+    pub received_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A conversation: received and sent messages linked by Message-ID or subject.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageThread {
+    pub id: i32,
+    pub subject: String, // normalized base subject
+    pub last_activity: DateTime<Utc>,
+    pub messages: Vec<ThreadMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThreadMessage {
+    pub direction: String, // "received" (id in messages) or "sent" (id in outbox)
+    pub id: i32,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub recipients: Option<String>,
+    pub date: DateTime<Utc>,
+    pub body: Option<String>,
+    pub status: Option<String>, // outbox status for sent messages
+}
+
+/// One hit from the full-text index: a stored message or an email log entry.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchResult {
+    pub source: String, // "message" or "log"
+    pub id: i32, // id in messages or email_logs, depending on source
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    pub recipients: Option<String>,
+    pub occurred_at: Option<DateTime<Utc>>,
+    pub has_attachment: bool,
+    /// Matching text with the hits in [brackets]
+    pub snippet: String,
+    pub rank: f64, // lower is better
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct MessageFilter {
+    pub email_account_id: Option<i32>,
+    pub folder: Option<String>,
+    pub limit: Option<i32>,
+    pub offset: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AutomationRunFilter {
+    pub rule_id: Option<i32>,
+    /// Inclusive lower bound on when the run happened
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive upper bound
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InboxEmail {
-    pub id: String,
+    pub id: String, // IMAP UID in INBOX
+    pub message_id: Option<String>,
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
     pub subject: String,
     pub sender: String,
+    #[serde(default)]
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub cc: Vec<String>,
+    #[serde(default)]
+    pub reply_to: Vec<String>,
     pub received_at: DateTime<Utc>,
-    pub body: String,
-    pub attachments: Vec<String>,
+    pub body: String, // plain text, derived from the HTML part when there is no text part
+    #[serde(default)]
+    pub html_body: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub attachments: Vec<String>, // filenames
+    #[serde(default)]
+    pub attachment_types: Vec<String>,
+    #[serde(default)]
+    pub size: usize, // bytes
     pub is_read: bool,
+    /// Set for bulk, list and machine-generated mail that must never get an auto-reply (RFC 3834)
+    pub is_auto_generated: bool,
+    /// How each active automation rule fared against this message, filled in after rules run
+    #[serde(default)]
+    pub automation_trace: Vec<RuleTrace>,
+    /// Row in the local message store, once the message has been saved there
+    #[serde(default)]
+    pub stored_id: Option<i32>,
+}
+
+/// The outcome of one automation rule against one message, for debugging rules.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RuleTrace {
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub matched: bool,
+    /// Why the rule was not evaluated, e.g. conditions that no longer parse
+    pub skipped: Option<String>,
+    pub predicates: Vec<PredicateTrace>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PredicateTrace {
+    /// `keywords`, or the predicate's place in the condition tree, e.g. `conditions.all[1].not`
+    pub path: String,
+    pub predicate: serde_json::Value,
+    pub matched: bool,
+}
+
+/// The messages a rule is tried against in a dry run.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum SimulationSource {
+    /// A pasted RFC 5322 message
+    Raw { message: String },
+    Email { email: Box<InboxEmail> },
+    /// The newest messages in a folder of an account (INBOX by default), read without
+    /// marking or moving anything
+    Recent {
+        email_account_id: i32,
+        limit: Option<usize>,
+        #[serde(default)]
+        folder: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleSimulation {
+    pub message_id: String,
+    pub subject: String,
+    pub sender: String,
+    pub trace: RuleTrace,
+    /// The actions that would run, in order; empty when the rule does not match
+    pub actions: Vec<SimulatedAction>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimulatedAction {
+    pub action: AutomationAction,
+    /// Set when the action would run but have no effect on this message
+    pub note: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
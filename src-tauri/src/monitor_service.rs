@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{interval, Duration as TokioDuration};
+use chrono::{DateTime, Duration, Utc};
+use log::{info, error, warn};
+use serde::Serialize;
+use imap::extensions::idle::WaitOutcome;
+use crate::models::*;
+use crate::inbox_service::InboxService;
+
+const TICK_SECS: u64 = 15;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+// Servers may drop an IDLE after 29 minutes; re-issuing it well before that also bounds
+// how long a stopped watcher keeps its connection open.
+const IDLE_REFRESH_SECS: u64 = 5 * 60;
+const IDLE_MAX_RECONNECT_SECS: u64 = 5 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitorMode {
+    Polling,
+    Idle,
+    Reconnecting,
+}
+
+/// Sent from a blocking IDLE watcher to the async supervisor.
+#[derive(Debug)]
+enum IdleEvent {
+    Connected,
+    Unsupported,
+    MailboxChanged,
+    Disconnected(String),
+}
+
+enum IdleExit {
+    Stopped,
+    Unsupported,
+}
+
+type IdleEvents = mpsc::UnboundedSender<(i32, IdleEvent)>;
+
+/// What the supervisor knows about one active monitor.
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorState {
+    pub monitor_id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    pub email_account_id: i32,
+    pub check_interval: i32,
+    pub mode: MonitorMode,
+    pub checking: bool,
+    pub last_check: Option<DateTime<Utc>>,
+    pub next_check: Option<DateTime<Utc>>,
+    pub last_message_count: usize,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// `None` until an IDLE watcher has connected once
+    pub idle_supported: Option<bool>,
+    /// Mail arrived while a check was running, so check again once it finishes
+    #[serde(skip)]
+    recheck: bool,
+    #[serde(skip)]
+    idle_stop: Option<Arc<AtomicBool>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonitorServiceStatus {
+    pub running: bool,
+    pub monitors: Vec<MonitorState>,
+}
+
+/// Polls every active inbox monitor on its own `check_interval`.
+///
+/// Each check runs in its own task, so a slow server only delays its own monitor and a
+/// panic is recorded as a failed check instead of ending the loop. Failing monitors back
+/// off exponentially, up to an hour, until a check succeeds again.
+///
+/// Monitors with `use_idle` also keep an IMAP IDLE connection open on a blocking thread
+/// and are checked as soon as the server reports new mail. Polling keeps running behind
+/// IDLE as a safety net, and is all that's left if the server lacks the IDLE capability.
+/// IDLE only watches the selected mailbox, so it watches INBOX; a monitor's other folders
+/// are picked up by polling.
+pub struct MonitorService {
+    inbox_service: Arc<InboxService>,
+    is_running: Arc<Mutex<bool>>,
+    states: Arc<Mutex<HashMap<i32, MonitorState>>>,
+}
+
+impl MonitorService {
+    pub fn new(inbox_service: Arc<InboxService>) -> Self {
+        Self {
+            inbox_service,
+            is_running: Arc::new(Mutex::new(false)),
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(&self) -> Result<(), AppError> {
+        let mut is_running = self.is_running.lock().await;
+        if *is_running {
+            return Ok(());
+        }
+        *is_running = true;
+        drop(is_running);
+
+        info!("Starting inbox monitor service");
+
+        let inbox_service = Arc::clone(&self.inbox_service);
+        let states = Arc::clone(&self.states);
+        let (idle_events, mut idle_receiver) = mpsc::unbounded_channel();
+
+        {
+            let inbox_service = Arc::clone(&inbox_service);
+            let states = Arc::clone(&states);
+            tokio::spawn(async move {
+                while let Some((monitor_id, event)) = idle_receiver.recv().await {
+                    Self::handle_idle_event(&inbox_service, &states, monitor_id, event).await;
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut interval = interval(TokioDuration::from_secs(TICK_SECS));
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = Self::check_due_monitors(&inbox_service, &states, &idle_events).await {
+                    error!("Error polling inbox monitors: {}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub async fn status(&self, user_id: i32) -> MonitorServiceStatus {
+        let running = *self.is_running.lock().await;
+        let mut monitors: Vec<MonitorState> = self.states.lock().await
+            .values()
+            .filter(|state| state.user_id == user_id)
+            .cloned()
+            .collect();
+        monitors.sort_by_key(|state| state.monitor_id);
+
+        MonitorServiceStatus { running, monitors }
+    }
+
+    async fn check_due_monitors(
+        inbox_service: &Arc<InboxService>,
+        states: &Arc<Mutex<HashMap<i32, MonitorState>>>,
+        idle_events: &IdleEvents,
+    ) -> Result<(), AppError> {
+        let monitors = inbox_service.get_active_inbox_monitors()?;
+        let now = Utc::now();
+        let mut guard = states.lock().await;
+
+        // Forget monitors that were deleted or paused since the last tick
+        guard.retain(|monitor_id, state| {
+            let active = monitors.iter().any(|monitor| monitor.id == *monitor_id);
+            if !active {
+                Self::stop_idle(state);
+            }
+            active
+        });
+
+        for monitor in monitors {
+            let state = guard.entry(monitor.id).or_insert_with(|| MonitorState {
+                monitor_id: monitor.id,
+                user_id: monitor.user_id,
+                email_account_id: monitor.email_account_id,
+                check_interval: monitor.check_interval,
+                mode: MonitorMode::Polling,
+                checking: false,
+                last_check: monitor.last_check,
+                next_check: monitor.last_check.map(|last| last + Duration::seconds(monitor.check_interval as i64)),
+                last_message_count: 0,
+                consecutive_failures: 0,
+                last_error: None,
+                idle_supported: None,
+                recheck: false,
+                idle_stop: None,
+            });
+            state.check_interval = monitor.check_interval;
+
+            if !monitor.use_idle {
+                Self::stop_idle(state);
+            } else if state.idle_stop.is_none() && state.idle_supported != Some(false) {
+                Self::start_idle(inbox_service, state, idle_events);
+            }
+
+            if state.checking || state.next_check.is_some_and(|next| next > now) {
+                continue;
+            }
+            Self::spawn_check(inbox_service, states, state);
+        }
+
+        Ok(())
+    }
+
+    /// Starts a check for `state` in its own task; the caller holds the `states` lock.
+    fn spawn_check(
+        inbox_service: &Arc<InboxService>,
+        states: &Arc<Mutex<HashMap<i32, MonitorState>>>,
+        state: &mut MonitorState,
+    ) {
+        state.checking = true;
+
+        let inbox_service = Arc::clone(inbox_service);
+        let states = Arc::clone(states);
+        let (monitor_id, user_id, account_id) = (state.monitor_id, state.user_id, state.email_account_id);
+
+        tokio::spawn(async move {
+            let check = {
+                let inbox_service = Arc::clone(&inbox_service);
+                // The check talks to the IMAP server synchronously, so it gets a blocking thread
+                tokio::task::spawn_blocking(move || {
+                    tokio::runtime::Handle::current().block_on(inbox_service.check_inbox(user_id, account_id))
+                })
+            };
+
+            let outcome = match check.await {
+                Ok(result) => result.map(|emails| emails.len()),
+                Err(e) => Err(AppError::Internal(format!("Inbox check aborted: {}", e))),
+            };
+
+            let mut guard = states.lock().await;
+            if let Some(state) = guard.get_mut(&monitor_id) {
+                Self::record_check(state, outcome, Utc::now());
+
+                if state.recheck && state.consecutive_failures == 0 {
+                    state.recheck = false;
+                    Self::spawn_check(&inbox_service, &states, state);
+                }
+            }
+        });
+    }
+
+    fn record_check(state: &mut MonitorState, outcome: Result<usize, AppError>, now: DateTime<Utc>) {
+        let check_interval = state.check_interval as i64;
+        state.checking = false;
+        state.last_check = Some(now);
+
+        let delay = match outcome {
+            Ok(count) => {
+                state.last_message_count = count;
+                state.consecutive_failures = 0;
+                state.last_error = None;
+                check_interval
+            }
+            Err(e) => {
+                warn!("Inbox monitor {} check failed: {}", state.monitor_id, e);
+                state.consecutive_failures += 1;
+                state.last_error = Some(e.to_string());
+                state.recheck = false;
+
+                // Double the wait for every consecutive failure, capped at an hour
+                let factor = 1i64 << state.consecutive_failures.min(10);
+                (check_interval * factor).min(MAX_BACKOFF_SECS.max(check_interval))
+            }
+        };
+
+        state.next_check = Some(now + Duration::seconds(delay));
+    }
+
+    async fn handle_idle_event(
+        inbox_service: &Arc<InboxService>,
+        states: &Arc<Mutex<HashMap<i32, MonitorState>>>,
+        monitor_id: i32,
+        event: IdleEvent,
+    ) {
+        let mut guard = states.lock().await;
+        let Some(state) = guard.get_mut(&monitor_id) else {
+            return;
+        };
+        // Events from a watcher that has already been told to stop are stale
+        if state.idle_stop.is_none() {
+            return;
+        }
+
+        match event {
+            IdleEvent::Connected => {
+                state.mode = MonitorMode::Idle;
+                state.idle_supported = Some(true);
+
+                // Catch up on anything that arrived while the connection was down
+                if state.checking {
+                    state.recheck = true;
+                } else {
+                    Self::spawn_check(inbox_service, states, state);
+                }
+            }
+            IdleEvent::Unsupported => {
+                info!("Inbox monitor {}: server does not support IDLE, polling instead", monitor_id);
+                state.mode = MonitorMode::Polling;
+                state.idle_supported = Some(false);
+                state.idle_stop = None;
+            }
+            IdleEvent::MailboxChanged => {
+                if state.checking {
+                    state.recheck = true;
+                } else {
+                    Self::spawn_check(inbox_service, states, state);
+                }
+            }
+            IdleEvent::Disconnected(e) => {
+                warn!("Inbox monitor {} lost its IDLE connection: {}", monitor_id, e);
+                state.mode = MonitorMode::Reconnecting;
+                state.last_error = Some(e);
+            }
+        }
+    }
+
+    fn start_idle(inbox_service: &Arc<InboxService>, state: &mut MonitorState, idle_events: &IdleEvents) {
+        let stop = Arc::new(AtomicBool::new(false));
+        state.idle_stop = Some(Arc::clone(&stop));
+        state.mode = MonitorMode::Reconnecting;
+
+        let inbox_service = Arc::clone(inbox_service);
+        let idle_events = idle_events.clone();
+        let (monitor_id, user_id, account_id) = (state.monitor_id, state.user_id, state.email_account_id);
+
+        tokio::task::spawn_blocking(move || {
+            Self::idle_loop(&inbox_service, monitor_id, user_id, account_id, &stop, &idle_events);
+        });
+    }
+
+    fn stop_idle(state: &mut MonitorState) {
+        if let Some(stop) = state.idle_stop.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+        state.mode = MonitorMode::Polling;
+    }
+
+    /// Keeps an IDLE connection open until `stop` is set, reconnecting with backoff.
+    fn idle_loop(
+        inbox_service: &InboxService,
+        monitor_id: i32,
+        user_id: i32,
+        account_id: i32,
+        stop: &AtomicBool,
+        events: &IdleEvents,
+    ) {
+        let mut failures = 0u32;
+
+        while !stop.load(Ordering::Relaxed) {
+            match Self::idle_session(inbox_service, monitor_id, user_id, account_id, stop, events, &mut failures) {
+                Ok(IdleExit::Stopped) => break,
+                Ok(IdleExit::Unsupported) => {
+                    let _ = events.send((monitor_id, IdleEvent::Unsupported));
+                    break;
+                }
+                Err(e) => {
+                    failures += 1;
+                    let _ = events.send((monitor_id, IdleEvent::Disconnected(e.to_string())));
+
+                    // Sleep in one-second steps so a stop request is noticed promptly
+                    let delay = (1u64 << failures.min(9)).min(IDLE_MAX_RECONNECT_SECS);
+                    for _ in 0..delay {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        std::thread::sleep(std::time::Duration::from_secs(1));
+                    }
+                }
+            }
+        }
+
+        info!("Inbox monitor {} IDLE watcher stopped", monitor_id);
+    }
+
+    fn idle_session(
+        inbox_service: &InboxService,
+        monitor_id: i32,
+        user_id: i32,
+        account_id: i32,
+        stop: &AtomicBool,
+        events: &IdleEvents,
+        failures: &mut u32,
+    ) -> Result<IdleExit, AppError> {
+        // Watchers run on blocking threads, which can still wait on the runtime for a token refresh
+        let mut session = tokio::runtime::Handle::current().block_on(inbox_service.open_session(user_id, account_id))?;
+
+        let has_idle = session.capabilities()
+            .map_err(|e| AppError::Email(format!("IMAP capability error: {}", e)))?
+            .has_str("IDLE");
+        if !has_idle {
+            let _ = session.logout();
+            return Ok(IdleExit::Unsupported);
+        }
+
+        session.select("INBOX")
+            .map_err(|e| AppError::Email(format!("Failed to select INBOX: {}", e)))?;
+
+        *failures = 0;
+        let _ = events.send((monitor_id, IdleEvent::Connected));
+
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                let _ = session.logout();
+                return Ok(IdleExit::Stopped);
+            }
+
+            let idle = session.idle()
+                .map_err(|e| AppError::Email(format!("IMAP IDLE error: {}", e)))?;
+
+            match idle.wait_with_timeout(std::time::Duration::from_secs(IDLE_REFRESH_SECS)) {
+                // Any untagged response (EXISTS, EXPUNGE, flag changes) ends the IDLE
+                Ok(WaitOutcome::MailboxChanged) => {
+                    if !stop.load(Ordering::Relaxed) {
+                        let _ = events.send((monitor_id, IdleEvent::MailboxChanged));
+                    }
+                }
+                // Nothing happened; loop around and re-issue IDLE before the server drops it
+                Ok(WaitOutcome::TimedOut) => {}
+                Err(e) => return Err(AppError::Email(format!("IMAP IDLE error: {}", e))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> MonitorState {
+        MonitorState {
+            monitor_id: 1,
+            user_id: 1,
+            email_account_id: 1,
+            check_interval: 300,
+            mode: MonitorMode::Polling,
+            checking: true,
+            last_check: None,
+            next_check: None,
+            last_message_count: 0,
+            consecutive_failures: 0,
+            last_error: None,
+            idle_supported: None,
+            recheck: false,
+            idle_stop: None,
+        }
+    }
+
+    #[test]
+    fn test_failed_checks_back_off_until_success() {
+        let now = Utc::now();
+        let mut state = state();
+
+        MonitorService::record_check(&mut state, Err(AppError::Email("timeout".to_string())), now);
+        assert_eq!(state.next_check, Some(now + Duration::seconds(600)));
+        assert!(!state.checking);
+
+        MonitorService::record_check(&mut state, Err(AppError::Email("timeout".to_string())), now);
+        MonitorService::record_check(&mut state, Err(AppError::Email("timeout".to_string())), now);
+        MonitorService::record_check(&mut state, Err(AppError::Email("timeout".to_string())), now);
+        assert_eq!(state.next_check, Some(now + Duration::seconds(MAX_BACKOFF_SECS)));
+        assert_eq!(state.consecutive_failures, 4);
+
+        MonitorService::record_check(&mut state, Ok(3), now);
+        assert_eq!(state.next_check, Some(now + Duration::seconds(300)));
+        assert_eq!((state.consecutive_failures, state.last_error.as_deref(), state.last_message_count), (0, None, 3));
+    }
+}
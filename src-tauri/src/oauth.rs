@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use rand::RngCore;
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use crate::models::*;
+use crate::database::Database;
+use crate::encryption::EncryptionService;
+
+// How long a started sign-in waits for the browser to come back
+const CALLBACK_TIMEOUT_SECS: u64 = 5 * 60;
+// Refresh a little early so a token doesn't expire between checkout and login
+const EXPIRY_MARGIN_SECS: i64 = 60;
+
+/// An OAuth2 authorization server and the mail servers that accept its tokens.
+#[derive(Debug, Clone)]
+pub struct OAuthProvider {
+    pub name: String,
+    pub authorization_url: String,
+    pub token_url: String,
+    pub scopes: Vec<String>,
+    pub client_id: String,
+    /// Installed apps are public clients, but Google still issues them a (non-secret) secret
+    pub client_secret: Option<String>,
+    /// Provider-specific parameters for the authorization request
+    pub extra_params: Vec<(String, String)>,
+    pub mechanism: SaslMechanism,
+    pub imap_server: String,
+    pub imap_port: i32,
+    pub smtp_server: String,
+    pub smtp_port: i32,
+}
+
+impl OAuthProvider {
+    /// Gmail and Microsoft 365, for each of them that has a client id configured in
+    /// `GOOGLE_OAUTH_CLIENT_ID` (and `GOOGLE_OAUTH_CLIENT_SECRET`) or `MICROSOFT_OAUTH_CLIENT_ID`.
+    pub fn from_env() -> Vec<OAuthProvider> {
+        let mut providers = Vec::new();
+
+        if let Ok(client_id) = env::var("GOOGLE_OAUTH_CLIENT_ID") {
+            providers.push(OAuthProvider {
+                name: "google".to_string(),
+                authorization_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+                token_url: "https://oauth2.googleapis.com/token".to_string(),
+                scopes: vec!["https://mail.google.com/".to_string()],
+                client_id,
+                client_secret: env::var("GOOGLE_OAUTH_CLIENT_SECRET").ok(),
+                // Without these Google only returns a refresh token on the first consent
+                extra_params: vec![
+                    ("access_type".to_string(), "offline".to_string()),
+                    ("prompt".to_string(), "consent".to_string()),
+                ],
+                mechanism: SaslMechanism::Xoauth2,
+                imap_server: "imap.gmail.com".to_string(),
+                imap_port: 993,
+                smtp_server: "smtp.gmail.com".to_string(),
+                smtp_port: 465,
+            });
+        }
+
+        if let Ok(client_id) = env::var("MICROSOFT_OAUTH_CLIENT_ID") {
+            providers.push(OAuthProvider {
+                name: "microsoft".to_string(),
+                authorization_url: "https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string(),
+                token_url: "https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string(),
+                scopes: vec![
+                    "https://outlook.office.com/IMAP.AccessAsUser.All".to_string(),
+                    "https://outlook.office.com/SMTP.Send".to_string(),
+                    "offline_access".to_string(),
+                ],
+                client_id,
+                client_secret: None,
+                extra_params: Vec::new(),
+                mechanism: SaslMechanism::Xoauth2,
+                imap_server: "outlook.office365.com".to_string(),
+                imap_port: 993,
+                smtp_server: "smtp.office365.com".to_string(),
+                smtp_port: 587,
+            });
+        }
+
+        providers
+    }
+}
+
+/// The token endpoint's answer to both the code exchange and a refresh.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    scope: Option<String>,
+}
+
+/// A sign-in waiting for the browser to return to the loopback redirect.
+struct PendingAuthorization {
+    user_id: i32,
+    request: StartOAuthAccount,
+    provider: OAuthProvider,
+    code_verifier: String,
+    redirect_uri: String,
+    listener: TcpListener,
+    started_at: DateTime<Utc>,
+}
+
+/// Adds accounts that sign in with OAuth2 and keeps their access tokens fresh.
+///
+/// Sign-in is the authorization-code flow with PKCE for installed apps (RFC 8252): the
+/// browser is sent to the provider and comes back to a one-off listener on 127.0.0.1, and
+/// the code is exchanged for tokens there. Refresh tokens are stored encrypted; access
+/// tokens are cached until shortly before they expire and refreshed on demand.
+pub struct OAuthService {
+    database: Arc<Database>,
+    encryption_service: Arc<EncryptionService>,
+    providers: HashMap<String, OAuthProvider>,
+    http_client: reqwest::Client,
+    pending: Mutex<HashMap<String, PendingAuthorization>>,
+    // One refresh at a time, so concurrent logins don't each spend the refresh token
+    refresh_lock: Mutex<()>,
+}
+
+impl OAuthService {
+    pub fn new(database: Arc<Database>, encryption_service: Arc<EncryptionService>, providers: Vec<OAuthProvider>) -> Self {
+        Self {
+            database,
+            encryption_service,
+            providers: providers.into_iter().map(|provider| (provider.name.clone(), provider)).collect(),
+            http_client: reqwest::Client::new(),
+            pending: Mutex::new(HashMap::new()),
+            refresh_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Opens the loopback listener and returns the URL to show the user in their browser.
+    pub async fn start_authorization(&self, user_id: i32, request: StartOAuthAccount) -> Result<OAuthAuthorization, AppError> {
+        let provider = self.providers.get(&request.provider)
+            .ok_or_else(|| AppError::Validation(format!("OAuth provider '{}' is not configured", request.provider)))?
+            .clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await
+            .map_err(|e| AppError::Internal(format!("Failed to open OAuth redirect listener: {}", e)))?;
+        let port = listener.local_addr()
+            .map_err(|e| AppError::Internal(format!("Failed to open OAuth redirect listener: {}", e)))?
+            .port();
+        let redirect_uri = format!("http://127.0.0.1:{}/callback", port);
+
+        let state = random_token(16);
+        let code_verifier = random_token(32);
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let mut query = vec![
+            ("response_type".to_string(), "code".to_string()),
+            ("client_id".to_string(), provider.client_id.clone()),
+            ("redirect_uri".to_string(), redirect_uri.clone()),
+            ("scope".to_string(), provider.scopes.join(" ")),
+            ("state".to_string(), state.clone()),
+            ("code_challenge".to_string(), code_challenge),
+            ("code_challenge_method".to_string(), "S256".to_string()),
+            ("login_hint".to_string(), request.email_address.clone()),
+        ];
+        query.extend(provider.extra_params.iter().cloned());
+
+        let authorization_url = reqwest::Url::parse_with_params(&provider.authorization_url, &query)
+            .map_err(|e| AppError::Config(format!("Invalid authorization URL for {}: {}", provider.name, e)))?
+            .to_string();
+
+        let mut pending = self.pending.lock().await;
+        // Sign-ins the user walked away from
+        let cutoff = Utc::now() - Duration::seconds(CALLBACK_TIMEOUT_SECS as i64);
+        pending.retain(|_, authorization| authorization.started_at > cutoff);
+        pending.insert(state.clone(), PendingAuthorization {
+            user_id,
+            request,
+            provider,
+            code_verifier,
+            redirect_uri,
+            listener,
+            started_at: Utc::now(),
+        });
+
+        Ok(OAuthAuthorization { authorization_url, state })
+    }
+
+    /// Waits for the browser to return with the code, exchanges it for tokens and creates
+    /// the account with the provider's IMAP and SMTP servers.
+    pub async fn finish_authorization(&self, user_id: i32, state: &str) -> Result<EmailAccount, AppError> {
+        let pending = self.pending.lock().await.remove(state)
+            .filter(|pending| pending.user_id == user_id)
+            .ok_or_else(|| AppError::NotFound("No pending sign-in for this state".to_string()))?;
+
+        let code = tokio::time::timeout(
+            std::time::Duration::from_secs(CALLBACK_TIMEOUT_SECS),
+            Self::wait_for_code(&pending.listener, state),
+        ).await
+            .map_err(|_| AppError::Auth("Timed out waiting for the provider to redirect back".to_string()))??;
+
+        let tokens = self.request_tokens(&pending.provider, &[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &pending.redirect_uri),
+            ("code_verifier", &pending.code_verifier),
+        ]).await?;
+        let refresh_token = tokens.refresh_token.clone()
+            .ok_or_else(|| AppError::Auth(format!("{} did not return a refresh token", pending.provider.name)))?;
+
+        let request = pending.request;
+        let provider = pending.provider;
+        let refresh_encrypted = self.encryption_service.encrypt(&refresh_token)?;
+        let access_encrypted = self.encryption_service.encrypt(&tokens.access_token)?;
+        let expires_at = tokens.expires_in.map(|seconds| Utc::now() + Duration::seconds(seconds));
+
+        // One transaction, since an account without tokens could never log in
+        let account = self.database.transaction(|tx| {
+            let account = Database::create_email_account_in(tx, CreateEmailAccountWithUser {
+                user_id,
+                account_name: request.account_name,
+                email_address: request.email_address.clone(),
+                imap_server: Some(provider.imap_server.clone()),
+                imap_port: Some(provider.imap_port),
+                smtp_server: Some(provider.smtp_server.clone()),
+                smtp_port: Some(provider.smtp_port),
+                username: request.email_address,
+                password_encrypted: String::new(),
+                auth_type: "oauth2".to_string(),
+                oauth_provider: Some(provider.name.clone()),
+                smtp_security: ConnectionSecurity::for_port(provider.smtp_port),
+                imap_security: ConnectionSecurity::for_port(provider.imap_port),
+                tls_ca_certificate: None,
+                tls_accept_invalid_certs: false,
+                is_active: Some(true),
+                rate_limits: request.rate_limits,
+            })?;
+            tx.execute(
+                "INSERT INTO oauth_tokens (email_account_id, provider, refresh_token_encrypted, access_token_encrypted, expires_at, scope)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![account.id, &provider.name, refresh_encrypted, access_encrypted, expires_at, tokens.scope],
+            )?;
+            Ok(account)
+        })?;
+
+        info!("Added OAuth2 account {} ({}) for user {}", account.id, provider.name, user_id);
+        Ok(account)
+    }
+
+    /// A ready-to-use credential for an OAuth2 account, refreshing the access token first
+    /// if it has expired or is about to.
+    pub async fn credential(&self, account_id: i32) -> Result<MailCredential, AppError> {
+        let _refreshing = self.refresh_lock.lock().await;
+
+        let (provider_name, refresh_encrypted, access_encrypted, expires_at) = {
+            let conn = self.database.get_connection()?;
+            conn.query_row(
+                "SELECT provider, refresh_token_encrypted, access_token_encrypted, expires_at
+                 FROM oauth_tokens WHERE email_account_id = ?1",
+                [account_id],
+                |row| Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<DateTime<Utc>>>(3)?,
+                )),
+            ).optional()?
+                .ok_or_else(|| AppError::Auth(format!("Account {} has no OAuth2 tokens; sign in again", account_id)))?
+        };
+
+        let provider = self.providers.get(&provider_name)
+            .ok_or_else(|| AppError::Config(format!("OAuth provider '{}' is not configured", provider_name)))?;
+
+        let fresh = expires_at.is_some_and(|expires_at| expires_at > Utc::now() + Duration::seconds(EXPIRY_MARGIN_SECS));
+        if let (Some(access_encrypted), true) = (access_encrypted, fresh) {
+            return Ok(MailCredential::OAuth2 {
+                access_token: self.encryption_service.decrypt(&access_encrypted)?,
+                mechanism: provider.mechanism,
+            });
+        }
+
+        let refresh_token = self.encryption_service.decrypt(&refresh_encrypted)?;
+        let tokens = self.request_tokens(provider, &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+        ]).await?;
+        self.store_access_token(account_id, &tokens)?;
+        info!("Refreshed OAuth2 access token for account {}", account_id);
+
+        Ok(MailCredential::OAuth2 {
+            access_token: tokens.access_token,
+            mechanism: provider.mechanism,
+        })
+    }
+
+    /// Caches a refreshed access token, and the new refresh token if the provider rotated it.
+    fn store_access_token(&self, account_id: i32, tokens: &TokenResponse) -> Result<(), AppError> {
+        let access_encrypted = self.encryption_service.encrypt(&tokens.access_token)?;
+        let refresh_encrypted = tokens.refresh_token.as_deref()
+            .map(|token| self.encryption_service.encrypt(token))
+            .transpose()?;
+        let expires_at = tokens.expires_in.map(|seconds| Utc::now() + Duration::seconds(seconds));
+
+        self.database.transaction(|tx| {
+            tx.execute(
+                "UPDATE oauth_tokens SET
+                     refresh_token_encrypted = COALESCE(?1, refresh_token_encrypted),
+                     access_token_encrypted = ?2,
+                     expires_at = ?3,
+                     updated_at = CURRENT_TIMESTAMP
+                 WHERE email_account_id = ?4",
+                params![refresh_encrypted, access_encrypted, expires_at, account_id],
+            )?;
+            Ok(())
+        })
+    }
+
+    async fn request_tokens(&self, provider: &OAuthProvider, grant: &[(&str, &str)]) -> Result<TokenResponse, AppError> {
+        let mut form: Vec<(&str, &str)> = grant.to_vec();
+        form.push(("client_id", &provider.client_id));
+        if let Some(secret) = &provider.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self.http_client.post(&provider.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| AppError::Auth(format!("Token request to {} failed: {}", provider.name, e)))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            // invalid_grant: the refresh token was revoked or has expired
+            if body.contains("invalid_grant") {
+                warn!("{} rejected the stored grant: {}", provider.name, body);
+                return Err(AppError::Auth(format!("{} access was revoked or has expired; sign in again", provider.name)));
+            }
+            return Err(AppError::Auth(format!("{} token endpoint returned {}: {}", provider.name, status, body)));
+        }
+
+        response.json::<TokenResponse>().await
+            .map_err(|e| AppError::Auth(format!("Invalid token response from {}: {}", provider.name, e)))
+    }
+
+    /// Answers requests on the loopback listener until one carries the authorization
+    /// response for `state`. Other requests (such as a browser's favicon) get a 404.
+    async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String, AppError> {
+        loop {
+            let (mut stream, _) = listener.accept().await
+                .map_err(|e| AppError::Internal(format!("OAuth redirect listener failed: {}", e)))?;
+
+            let target = read_request_target(&mut stream).await.unwrap_or_default();
+            let url = match reqwest::Url::parse(&format!("http://127.0.0.1{}", target)) {
+                Ok(url) if url.path() == "/callback" => url,
+                _ => {
+                    respond(&mut stream, "404 Not Found", "Not found").await;
+                    continue;
+                }
+            };
+
+            let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            if query.get("state").map(String::as_str) != Some(state) {
+                respond(&mut stream, "400 Bad Request", "This sign-in link is no longer valid.").await;
+                continue;
+            }
+
+            if let Some(error) = query.get("error") {
+                respond(&mut stream, "200 OK", "Access was not granted. You can close this window.").await;
+                let description = query.get("error_description").cloned().unwrap_or_default();
+                return Err(AppError::Auth(format!("Authorization denied: {} {}", error, description).trim().to_string()));
+            }
+
+            if let Some(code) = query.get("code") {
+                respond(&mut stream, "200 OK", "Signed in. You can close this window and return to the app.").await;
+                return Ok(code.clone());
+            }
+
+            respond(&mut stream, "400 Bad Request", "The authorization response has no code.").await;
+        }
+    }
+}
+
+/// Logs an IMAP client in with a password, or with an access token over SASL.
+pub fn imap_login<T: Read + Write>(
+    client: imap::Client<T>,
+    username: &str,
+    host: &str,
+    port: u16,
+    credential: &MailCredential,
+) -> Result<imap::Session<T>, AppError> {
+    match credential {
+        MailCredential::Password(password) => client.login(username, password)
+            .map_err(|e| AppError::Email(format!("IMAP login error: {:?}", e.0))),
+        MailCredential::OAuth2 { access_token, mechanism } => {
+            let authenticator = SaslAuthenticator(sasl_response(*mechanism, username, host, port, access_token));
+            client.authenticate(mechanism.name(), &authenticator)
+                .map_err(|e| AppError::Auth(format!("IMAP {} authentication error: {:?}", mechanism.name(), e.0)))
+        }
+    }
+}
+
+/// The client's SASL response for an access token, before base64 encoding.
+pub fn sasl_response(mechanism: SaslMechanism, username: &str, host: &str, port: u16, access_token: &str) -> String {
+    match mechanism {
+        SaslMechanism::Xoauth2 => format!("user={}\x01auth=Bearer {}\x01\x01", username, access_token),
+        // RFC 7628
+        SaslMechanism::OAuthBearer => format!(
+            "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            username.replace('=', "=3D").replace(',', "=2C"), host, port, access_token
+        ),
+    }
+}
+
+struct SaslAuthenticator(String);
+
+impl imap::Authenticator for SaslAuthenticator {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> String {
+        // A non-empty challenge is the server's JSON error; an empty reply ends the exchange
+        if challenge.is_empty() {
+            self.0.clone()
+        } else {
+            String::new()
+        }
+    }
+}
+
+fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buffer);
+    URL_SAFE_NO_PAD.encode(buffer)
+}
+
+/// Reads an HTTP request's head and returns its target, e.g. `/callback?code=...`.
+async fn read_request_target(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    while !buffer.windows(4).any(|window| window == b"\r\n\r\n") && buffer.len() < 8192 {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&buffer);
+    Ok(request.lines().next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default()
+        .to_string())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) {
+    let body = format!("<!doctype html><html><body><p>{}</p></body></html>", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_support::temp_database;
+
+    #[test]
+    fn test_sasl_responses() {
+        assert_eq!(
+            sasl_response(SaslMechanism::Xoauth2, "jane@example.com", "imap.example.com", 993, "tok"),
+            "user=jane@example.com\x01auth=Bearer tok\x01\x01"
+        );
+        assert_eq!(
+            sasl_response(SaslMechanism::OAuthBearer, "jane@example.com", "imap.example.com", 993, "tok"),
+            "n,a=jane@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer tok\x01\x01"
+        );
+
+        let authenticator = SaslAuthenticator("payload".to_string());
+        assert_eq!(imap::Authenticator::process(&authenticator, b""), "payload");
+        assert_eq!(imap::Authenticator::process(&authenticator, b"{\"status\":\"401\"}"), "");
+    }
+
+    /// A token endpoint that checks PKCE and records each grant it serves.
+    async fn mock_token_server(listener: TcpListener, challenge: Arc<std::sync::Mutex<String>>, grants: Arc<std::sync::Mutex<Vec<String>>>) {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else { return };
+
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let body = loop {
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                    assert!(read > 0, "connection closed mid-request");
+                    continue;
+                };
+                let length = head.lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                if body.len() >= length || read == 0 {
+                    break body.to_string();
+                }
+            };
+
+            let form: HashMap<&str, &str> = body.split('&').filter_map(|pair| pair.split_once('=')).collect();
+            let grant = form["grant_type"].to_string();
+            let json = match grant.as_str() {
+                "authorization_code" => {
+                    let verifier = form["code_verifier"];
+                    let ok = form["code"] == "abc"
+                        && URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == *challenge.lock().unwrap();
+                    if ok {
+                        r#"{"access_token":"access-1","refresh_token":"refresh-1","expires_in":3600}"#
+                    } else {
+                        r#"{"error":"invalid_grant"}"#
+                    }
+                }
+                _ if form["refresh_token"] == "refresh-1" => r#"{"access_token":"access-2","expires_in":3600}"#,
+                _ => r#"{"error":"invalid_grant"}"#,
+            };
+            grants.lock().unwrap().push(grant);
+
+            let status = if json.contains("error") { "400 Bad Request" } else { "200 OK" };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, json.len(), json
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_with_pkce_then_refresh_against_mock_server() {
+        if env::var("ENCRYPTION_KEY").is_err() {
+            env::set_var("ENCRYPTION_KEY", "0".repeat(64));
+        }
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "oauth".to_string(),
+            email: "oauth@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+
+        let token_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_port = token_listener.local_addr().unwrap().port();
+        let challenge = Arc::new(std::sync::Mutex::new(String::new()));
+        let grants = Arc::new(std::sync::Mutex::new(Vec::new()));
+        tokio::spawn(mock_token_server(token_listener, Arc::clone(&challenge), Arc::clone(&grants)));
+
+        let provider = OAuthProvider {
+            name: "mock".to_string(),
+            authorization_url: format!("http://127.0.0.1:{}/authorize", token_port),
+            token_url: format!("http://127.0.0.1:{}/token", token_port),
+            scopes: vec!["mail".to_string()],
+            client_id: "client".to_string(),
+            client_secret: None,
+            extra_params: Vec::new(),
+            mechanism: SaslMechanism::OAuthBearer,
+            imap_server: "imap.example.com".to_string(),
+            imap_port: 993,
+            smtp_server: "smtp.example.com".to_string(),
+            smtp_port: 465,
+        };
+        let service = OAuthService::new(
+            database.shared(),
+            Arc::new(EncryptionService::new().unwrap()),
+            vec![provider],
+        );
+
+        let authorization = service.start_authorization(user.id, StartOAuthAccount {
+            provider: "mock".to_string(),
+            account_name: "Work".to_string(),
+            email_address: "jane@example.com".to_string(),
+            rate_limits: RateLimits::default(),
+        }).await.unwrap();
+
+        let url = reqwest::Url::parse(&authorization.authorization_url).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+        *challenge.lock().unwrap() = query["code_challenge"].clone();
+
+        // The browser coming back to the loopback redirect, after an unrelated request
+        let redirect = reqwest::Url::parse(&query["redirect_uri"]).unwrap();
+        let address = format!("127.0.0.1:{}", redirect.port().unwrap());
+        let state = authorization.state.clone();
+        tokio::spawn(async move {
+            for target in ["/favicon.ico".to_string(), format!("/callback?code=abc&state={}", state)] {
+                let mut browser = TcpStream::connect(&address).await.unwrap();
+                browser.write_all(format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", target).as_bytes()).await.unwrap();
+                let mut response = Vec::new();
+                browser.read_to_end(&mut response).await.unwrap();
+            }
+        });
+
+        let account = service.finish_authorization(user.id, &authorization.state).await.unwrap();
+        assert_eq!((account.auth_type.as_str(), account.oauth_provider.as_deref()), ("oauth2", Some("mock")));
+        assert_eq!(account.imap_server.as_deref(), Some("imap.example.com"));
+
+        let access_token = |credential: MailCredential| match credential {
+            MailCredential::OAuth2 { access_token, .. } => access_token,
+            MailCredential::Password(_) => panic!("expected an OAuth2 credential"),
+        };
+        assert_eq!(access_token(service.credential(account.id).await.unwrap()), "access-1");
+
+        database.transaction(|tx| {
+            tx.execute("UPDATE oauth_tokens SET expires_at = datetime('now', '-1 minute')", [])?;
+            Ok(())
+        }).unwrap();
+        assert_eq!(access_token(service.credential(account.id).await.unwrap()), "access-2");
+        assert_eq!(*grants.lock().unwrap(), vec!["authorization_code", "refresh_token"]);
+
+        // A finished sign-in can't be replayed
+        assert!(service.finish_authorization(user.id, &authorization.state).await.is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{interval, Duration as TokioDuration};
+use chrono::{Duration, Utc};
+use log::{info, error, warn};
+use rusqlite::{params, OptionalExtension, Row, Transaction};
+use uuid::Uuid;
+use crate::models::*;
+use crate::database::Database;
+use crate::email_service::EmailService;
+use crate::credentials::CredentialService;
+use crate::rate_limiter::RateLimiter;
+use crate::threading::{ThreadHeaders, ThreadingService};
+
+const POLL_INTERVAL_SECS: u64 = 5;
+const BATCH_SIZE: i32 = 50;
+const MAX_ATTEMPTS: i32 = 8;
+const BASE_BACKOFF_SECS: i64 = 60;
+const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+const OUTBOX_COLUMNS: &str = "id, user_id, email_account_id, campaign_id, message, status, attempts, next_attempt_at, last_error, sent_at, created_at";
+
+#[derive(Debug, PartialEq)]
+enum DeliveryFailure {
+    /// Worth retrying later: SMTP 4xx replies, connection and TLS errors.
+    Transient(String),
+    /// Will never succeed: SMTP 5xx replies, bad addresses, missing account.
+    Permanent(String),
+}
+
+pub struct OutboxService {
+    database: Arc<Database>,
+    email_service: Arc<Mutex<EmailService>>,
+    credential_service: Arc<CredentialService>,
+    is_running: Arc<Mutex<bool>>,
+}
+
+impl OutboxService {
+    pub fn new(
+        database: Arc<Database>,
+        email_service: Arc<Mutex<EmailService>>,
+        credential_service: Arc<CredentialService>,
+    ) -> Self {
+        Self {
+            database,
+            email_service,
+            credential_service,
+            is_running: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    pub fn enqueue(&self, message: CreateOutboxMessage) -> Result<i32, AppError> {
+        self.database.transaction(|tx| Self::enqueue_in(tx, &message))
+    }
+
+    /// Queues a message inside the caller's transaction, so it is enqueued atomically with
+    /// whatever else the caller writes (for example the campaign it belongs to).
+    pub fn enqueue_in(tx: &Transaction, message: &CreateOutboxMessage) -> Result<i32, AppError> {
+        // Fix the Message-ID now, so replies to this message can be threaded with it
+        let mut email = message.message.clone();
+        if email.message_id.is_none() {
+            let domain: Option<String> = tx.query_row(
+                "SELECT substr(email_address, instr(email_address, '@') + 1) FROM email_accounts WHERE id = ?1",
+                [message.email_account_id],
+                |row| row.get(0),
+            ).optional()?;
+            email.message_id = Some(format!("<{}@{}>", Uuid::new_v4(), domain.filter(|d| !d.is_empty()).unwrap_or_else(|| "localhost".to_string())));
+        }
+
+        let message_json = serde_json::to_string(&email)
+            .map_err(|e| AppError::Internal(format!("Failed to serialize message: {}", e)))?;
+        let now = Utc::now();
+
+        let thread_id = ThreadingService::assign_in(tx, message.user_id, &ThreadHeaders {
+            message_id: email.message_id.as_deref(),
+            in_reply_to: email.in_reply_to.as_deref(),
+            references: email.references.as_deref(),
+            subject: &email.subject,
+            date: now,
+        })?;
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO outbox (user_id, email_account_id, campaign_id, recipient_email, subject, message, message_id, thread_id, status, next_attempt_at, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'queued', ?9, ?9, ?9)"
+        )?;
+
+        let outbox_id = stmt.insert(params![
+            message.user_id,
+            message.email_account_id,
+            message.campaign_id,
+            email.to.join(", "),
+            &email.subject,
+            &message_json,
+            &email.message_id,
+            thread_id,
+            now.to_rfc3339(),
+        ])?;
+
+        Ok(outbox_id as i32)
+    }
+
+    pub fn get_user_outbox(&self, user_id: i32, status: Option<String>, limit: Option<i32>) -> Result<Vec<OutboxMessage>, AppError> {
+        let conn = self.database.get_connection()?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM outbox
+             WHERE user_id = ?1 AND (?2 IS NULL OR status = ?2)
+             ORDER BY created_at DESC LIMIT ?3",
+            OUTBOX_COLUMNS
+        ))?;
+
+        let message_iter = stmt.query_map(
+            params![user_id, status, limit.unwrap_or(100)],
+            Self::row_to_message,
+        )?;
+
+        let mut messages = Vec::new();
+        for message in message_iter {
+            messages.push(message?);
+        }
+
+        Ok(messages)
+    }
+
+    pub async fn start(&self) -> Result<(), AppError> {
+        let mut is_running = self.is_running.lock().await;
+        if *is_running {
+            return Ok(());
+        }
+        *is_running = true;
+        drop(is_running);
+
+        let resumed = Self::resume_interrupted(&self.database)?;
+        info!("Starting outbox worker ({} interrupted messages re-queued)", resumed);
+
+        let database = Arc::clone(&self.database);
+        let email_service = Arc::clone(&self.email_service);
+        let credential_service = Arc::clone(&self.credential_service);
+        let is_running_flag = Arc::clone(&self.is_running);
+
+        tokio::spawn(async move {
+            let mut interval = interval(TokioDuration::from_secs(POLL_INTERVAL_SECS));
+
+            loop {
+                interval.tick().await;
+
+                // Check if we should stop
+                {
+                    let running = is_running_flag.lock().await;
+                    if !*running {
+                        break;
+                    }
+                }
+
+                if let Err(e) = Self::process_due_messages(
+                    &database,
+                    &email_service,
+                    &credential_service,
+                ).await {
+                    error!("Error processing outbox: {}", e);
+                }
+            }
+
+            info!("Outbox worker stopped");
+        });
+
+        Ok(())
+    }
+
+    pub async fn stop(&self) {
+        let mut is_running = self.is_running.lock().await;
+        *is_running = false;
+        info!("Stopping outbox worker");
+    }
+
+    /// Messages left in 'sending' were interrupted by a crash or shutdown mid-delivery.
+    fn resume_interrupted(database: &Database) -> Result<usize, AppError> {
+        database.transaction(|tx| {
+            Ok(tx.execute(
+                "UPDATE outbox SET status = 'queued', updated_at = ?1 WHERE status = 'sending'",
+                params![Utc::now().to_rfc3339()],
+            )?)
+        })
+    }
+
+    async fn process_due_messages(
+        database: &Database,
+        email_service: &Arc<Mutex<EmailService>>,
+        credential_service: &CredentialService,
+    ) -> Result<(), AppError> {
+        let due_messages = Self::claim_due_messages(database)?;
+
+        for message in due_messages {
+            let result = Self::deliver(database, email_service, credential_service, &message).await;
+
+            match &result {
+                Ok(_) => info!("Delivered outbox message {} to {}", message.id, message.message.to.join(", ")),
+                Err(DeliveryFailure::Transient(reason)) => warn!("Outbox message {} deferred: {}", message.id, reason),
+                Err(DeliveryFailure::Permanent(reason)) => error!("Outbox message {} bounced: {}", message.id, reason),
+            }
+
+            Self::record_outcome(database, &message, result)?;
+        }
+
+        Ok(())
+    }
+
+    fn claim_due_messages(database: &Database) -> Result<Vec<OutboxMessage>, AppError> {
+        database.transaction(|tx| {
+            let now = Utc::now();
+
+            let candidates = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {} FROM outbox
+                     WHERE status IN ('queued', 'deferred') AND next_attempt_at <= ?1
+                     ORDER BY next_attempt_at, id LIMIT ?2",
+                    OUTBOX_COLUMNS
+                ))?;
+
+                let message_iter = stmt.query_map(params![now.to_rfc3339(), BATCH_SIZE], Self::row_to_message)?;
+                message_iter.collect::<Result<Vec<_>, _>>()?
+            };
+
+            let mut limits_by_account = HashMap::new();
+            let mut messages = Vec::with_capacity(candidates.len());
+
+            for mut message in candidates {
+                let limits = match limits_by_account.entry(message.email_account_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(RateLimiter::load_limits(tx, message.email_account_id)?),
+                };
+
+                // Over quota or outside the sending window: hold the message until the account frees up
+                if let Some(available_at) = RateLimiter::next_available_at(tx, message.email_account_id, limits, now)? {
+                    tx.execute(
+                        "UPDATE outbox SET next_attempt_at = ?1, updated_at = ?2 WHERE id = ?3",
+                        params![available_at.to_rfc3339(), now.to_rfc3339(), message.id],
+                    )?;
+                    continue;
+                }
+
+                tx.execute(
+                    "UPDATE outbox SET status = 'sending', attempts = attempts + 1, updated_at = ?1 WHERE id = ?2",
+                    params![now.to_rfc3339(), message.id],
+                )?;
+                message.status = "sending".to_string();
+                message.attempts += 1;
+                messages.push(message);
+            }
+
+            Ok(messages)
+        })
+    }
+
+    async fn deliver(
+        database: &Database,
+        email_service: &Arc<Mutex<EmailService>>,
+        credential_service: &CredentialService,
+        message: &OutboxMessage,
+    ) -> Result<(), DeliveryFailure> {
+        let account = database.get_email_account(message.user_id, message.email_account_id)
+            .map_err(|e| DeliveryFailure::Transient(e.to_string()))?
+            .ok_or_else(|| DeliveryFailure::Permanent("Email account not found".to_string()))?;
+
+        let credential = credential_service.credential(&account).await
+            .map_err(|e| match e {
+                // An undecryptable password or unconfigured provider won't fix itself
+                AppError::Config(_) => DeliveryFailure::Permanent(format!("Failed to get credentials: {}", e)),
+                // A failed refresh may be a network blip; a revoked grant keeps failing until the attempts run out
+                _ => DeliveryFailure::Transient(format!("Failed to get credentials: {}", e)),
+            })?;
+
+        // Only building the message needs the service; the lock is released before sending
+        let prepared = email_service.lock().await
+            .prepare_email(&account, &credential, &message.message)
+            .map_err(|e| Self::classify_error(&e))?;
+
+        tokio::task::spawn_blocking(move || prepared.send())
+            .await
+            .map_err(|e| DeliveryFailure::Transient(format!("Delivery task failed: {}", e)))?
+            .map_err(|e| Self::classify_error(&e))
+    }
+
+    fn classify_error(error: &anyhow::Error) -> DeliveryFailure {
+        let reason = format!("{:#}", error);
+
+        if let Some(smtp_error) = error.downcast_ref::<lettre::transport::smtp::Error>() {
+            return if smtp_error.is_permanent() {
+                DeliveryFailure::Permanent(reason)
+            } else {
+                // 4xx replies and failures without a reply code (connection, TLS, timeouts)
+                DeliveryFailure::Transient(reason)
+            };
+        }
+
+        // An unparsable address or a message lettre can't build fails the same way every time
+        if error.downcast_ref::<lettre::address::AddressError>().is_some()
+            || error.downcast_ref::<lettre::error::Error>().is_some()
+        {
+            return DeliveryFailure::Permanent(reason);
+        }
+
+        // Anything else, such as a transport that couldn't be set up, is worth retrying
+        DeliveryFailure::Transient(reason)
+    }
+
+    fn backoff_delay(attempts: i32) -> Duration {
+        let exponent = (attempts - 1).clamp(0, 16) as u32;
+        let seconds = BASE_BACKOFF_SECS.saturating_mul(1i64 << exponent).min(MAX_BACKOFF_SECS);
+        Duration::seconds(seconds)
+    }
+
+    fn record_outcome(
+        database: &Database,
+        message: &OutboxMessage,
+        result: Result<(), DeliveryFailure>,
+    ) -> Result<(), AppError> {
+        let now = Utc::now();
+
+        database.transaction(|tx| {
+            let log_entry = match result {
+                Ok(()) => {
+                    tx.execute(
+                        "UPDATE outbox SET status = 'sent', sent_at = ?1, last_error = NULL, updated_at = ?1 WHERE id = ?2",
+                        params![now.to_rfc3339(), message.id],
+                    )?;
+                    Some(("sent", None))
+                }
+                Err(DeliveryFailure::Transient(reason)) if message.attempts < MAX_ATTEMPTS => {
+                    let next_attempt_at = now + Self::backoff_delay(message.attempts);
+                    tx.execute(
+                        "UPDATE outbox SET status = 'deferred', next_attempt_at = ?1, last_error = ?2, updated_at = ?3 WHERE id = ?4",
+                        params![next_attempt_at.to_rfc3339(), reason, now.to_rfc3339(), message.id],
+                    )?;
+                    None
+                }
+                Err(DeliveryFailure::Transient(reason)) | Err(DeliveryFailure::Permanent(reason)) => {
+                    tx.execute(
+                        "UPDATE outbox SET status = 'bounced', last_error = ?1, updated_at = ?2 WHERE id = ?3",
+                        params![&reason, now.to_rfc3339(), message.id],
+                    )?;
+                    Some(("failed", Some(reason)))
+                }
+            };
+
+            if let Some((status, error_message)) = log_entry {
+                tx.execute(
+                    "INSERT INTO email_logs (user_id, email_account_id, campaign_id, direction, recipient_email, sender_email, subject, status, error_message, sent_at, created_at)
+                     VALUES (?1, ?2, ?3, 'sent', ?4, (SELECT email_address FROM email_accounts WHERE id = ?2), ?5, ?6, ?7, ?8, ?8)",
+                    params![
+                        message.user_id,
+                        message.email_account_id,
+                        message.campaign_id,
+                        message.message.to.join(", "),
+                        &message.message.subject,
+                        status,
+                        error_message,
+                        now.to_rfc3339(),
+                    ],
+                )?;
+
+                if let Some(campaign_id) = message.campaign_id {
+                    Self::update_campaign_progress(tx, campaign_id)?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Recounts a campaign from its outbox rows and closes it once nothing is left to send.
+    fn update_campaign_progress(tx: &Transaction, campaign_id: i32) -> Result<(), AppError> {
+        tx.execute(
+            "UPDATE email_campaigns SET
+                 sent_count = (SELECT COUNT(*) FROM outbox WHERE campaign_id = ?1 AND status = 'sent'),
+                 failed_count = (SELECT COUNT(*) FROM outbox WHERE campaign_id = ?1 AND status = 'bounced'),
+                 updated_at = CURRENT_TIMESTAMP
+             WHERE id = ?1",
+            [campaign_id],
+        )?;
+
+        tx.execute(
+            "UPDATE email_campaigns SET status = CASE
+                 WHEN failed_count = 0 THEN 'completed'
+                 WHEN sent_count = 0 THEN 'failed'
+                 ELSE 'partial'
+             END
+             WHERE id = ?1 AND status = 'sending'
+               AND NOT EXISTS (
+                   SELECT 1 FROM outbox WHERE campaign_id = ?1 AND status IN ('queued', 'sending', 'deferred')
+               )",
+            [campaign_id],
+        )?;
+
+        Ok(())
+    }
+
+    fn row_to_message(row: &Row) -> rusqlite::Result<OutboxMessage> {
+        let message_json: String = row.get(4)?;
+        let message = serde_json::from_str(&message_json)
+            .map_err(|_| rusqlite::Error::InvalidColumnType(4, "message".to_string(), rusqlite::types::Type::Text))?;
+
+        Ok(OutboxMessage {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            email_account_id: row.get(2)?,
+            campaign_id: row.get(3)?,
+            message,
+            status: row.get(5)?,
+            attempts: row.get(6)?,
+            next_attempt_at: row.get(7)?,
+            last_error: row.get(8)?,
+            sent_at: row.get(9)?,
+            created_at: row.get(10)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_exponentially_and_is_capped() {
+        assert_eq!(OutboxService::backoff_delay(1), Duration::seconds(60));
+        assert_eq!(OutboxService::backoff_delay(2), Duration::seconds(120));
+        assert_eq!(OutboxService::backoff_delay(4), Duration::seconds(480));
+        assert_eq!(OutboxService::backoff_delay(30), Duration::seconds(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn test_unparsable_addresses_are_permanent() {
+        let parse_error = "not-an-address".parse::<lettre::message::Mailbox>().unwrap_err();
+        let error = anyhow::Error::from(parse_error).context("Invalid to address not-an-address");
+        assert!(matches!(OutboxService::classify_error(&error), DeliveryFailure::Permanent(_)));
+    }
+
+    #[test]
+    fn test_other_errors_before_sending_are_transient() {
+        let error = anyhow::Error::from(AppError::Email("Failed to load CA certificate".to_string()));
+        assert!(matches!(OutboxService::classify_error(&error), DeliveryFailure::Transient(_)));
+    }
+}
@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use chrono::{DateTime, Duration, Local, TimeZone, Timelike, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use crate::models::*;
+use crate::database::Database;
+
+#[derive(Debug, Serialize)]
+pub struct QuotaStatus {
+    pub limit: Option<i32>,
+    pub used: i32,
+    pub remaining: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateLimitStatus {
+    pub email_account_id: i32,
+    pub per_minute: QuotaStatus,
+    pub per_hour: QuotaStatus,
+    pub per_day: QuotaStatus,
+    pub in_send_window: bool,
+    /// When the account may send again, or `None` if it can send right now.
+    pub next_available_at: Option<DateTime<Utc>>,
+}
+
+/// Enforces the per-account quotas configured in [`RateLimits`].
+///
+/// Every outgoing message is delivered by the outbox worker, which asks the limiter before
+/// claiming a message, so manual sends, campaigns, scheduled mail and auto-replies all
+/// draw on the same quota. Usage is counted from the outbox itself: messages sent within
+/// the window plus messages currently being sent.
+pub struct RateLimiter {
+    database: Arc<Database>,
+}
+
+impl RateLimiter {
+    pub fn new(database: Arc<Database>) -> Self {
+        Self { database }
+    }
+
+    pub fn get_status(&self, user_id: i32, account_id: i32) -> Result<RateLimitStatus, AppError> {
+        let account = self.database.get_email_account(user_id, account_id)
+            .map_err(|e| AppError::Internal(e.to_string()))?
+            .ok_or_else(|| AppError::NotFound("Email account not found".to_string()))?;
+
+        let conn = self.database.get_connection()?;
+        Self::status(&conn, account.id, &account.rate_limits, Utc::now())
+    }
+
+    pub fn validate(limits: &RateLimits) -> Result<(), AppError> {
+        for (name, limit) in [
+            ("max_per_minute", limits.max_per_minute),
+            ("max_per_hour", limits.max_per_hour),
+            ("max_per_day", limits.max_per_day),
+        ] {
+            if matches!(limit, Some(value) if value <= 0) {
+                return Err(AppError::Validation(format!("{} must be greater than zero", name)));
+            }
+        }
+
+        match (limits.send_window_start, limits.send_window_end) {
+            (None, None) => Ok(()),
+            (Some(start), Some(end)) if (0..24).contains(&start) && (0..24).contains(&end) => Ok(()),
+            (Some(_), Some(_)) => Err(AppError::Validation("Send window hours must be between 0 and 23".to_string())),
+            _ => Err(AppError::Validation("Send window needs both a start and an end hour".to_string())),
+        }
+    }
+
+    /// Loads an account's limits; a missing account has none, and fails later at delivery.
+    pub fn load_limits(conn: &Connection, account_id: i32) -> Result<RateLimits, AppError> {
+        let limits = conn.query_row(
+            "SELECT max_per_minute, max_per_hour, max_per_day, send_window_start, send_window_end
+             FROM email_accounts WHERE id = ?1",
+            [account_id],
+            |row| Ok(RateLimits {
+                max_per_minute: row.get(0)?,
+                max_per_hour: row.get(1)?,
+                max_per_day: row.get(2)?,
+                send_window_start: row.get(3)?,
+                send_window_end: row.get(4)?,
+            }),
+        ).optional()?;
+
+        Ok(limits.unwrap_or_default())
+    }
+
+    /// Returns when the account may next send, or `None` if it may send at `now`.
+    ///
+    /// Takes a plain connection so it can run inside the outbox's claim transaction and see
+    /// the messages claimed earlier in the same batch.
+    pub fn next_available_at(
+        conn: &Connection,
+        account_id: i32,
+        limits: &RateLimits,
+        now: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, AppError> {
+        Ok(Self::status(conn, account_id, limits, now)?.next_available_at)
+    }
+
+    fn status(
+        conn: &Connection,
+        account_id: i32,
+        limits: &RateLimits,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitStatus, AppError> {
+        let mut next_available_at = None;
+        let mut quotas = Vec::with_capacity(3);
+
+        for (limit, period) in [
+            (limits.max_per_minute, Duration::minutes(1)),
+            (limits.max_per_hour, Duration::hours(1)),
+            (limits.max_per_day, Duration::days(1)),
+        ] {
+            let (used, oldest) = Self::usage_since(conn, account_id, now - period)?;
+
+            if let Some(limit) = limit {
+                if used >= limit {
+                    // A slot frees up once the oldest counted send leaves the period
+                    let frees_at = oldest.unwrap_or(now) + period;
+                    next_available_at = next_available_at.max(Some(frees_at));
+                }
+            }
+
+            quotas.push(QuotaStatus {
+                limit,
+                used,
+                remaining: limit.map(|limit| (limit - used).max(0)),
+            });
+        }
+
+        let window_opens_at = Self::window_opens_at(limits, now.with_timezone(&Local))
+            .map(|opens_at| opens_at.with_timezone(&Utc));
+        next_available_at = next_available_at.max(window_opens_at);
+
+        let mut quotas = quotas.into_iter();
+        Ok(RateLimitStatus {
+            email_account_id: account_id,
+            per_minute: quotas.next().unwrap(),
+            per_hour: quotas.next().unwrap(),
+            per_day: quotas.next().unwrap(),
+            in_send_window: window_opens_at.is_none(),
+            next_available_at,
+        })
+    }
+
+    /// Counts sends since `since` plus in-flight messages, with the earliest of their times.
+    fn usage_since(conn: &Connection, account_id: i32, since: DateTime<Utc>) -> Result<(i32, Option<DateTime<Utc>>), AppError> {
+        Ok(conn.query_row(
+            "SELECT COUNT(*), MIN(COALESCE(sent_at, updated_at)) FROM outbox
+             WHERE email_account_id = ?1
+               AND (status = 'sending' OR (status = 'sent' AND sent_at > ?2))",
+            params![account_id, since.to_rfc3339()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?)
+    }
+
+    /// Returns the start of the next sending window, or `None` if `now` is inside it.
+    fn window_opens_at<Tz: TimeZone>(limits: &RateLimits, now: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let (start, end) = match (limits.send_window_start, limits.send_window_end) {
+            (Some(start), Some(end)) if start != end => (start as u32, end as u32),
+            _ => return None,
+        };
+
+        let hour = now.hour();
+        let inside = if start < end {
+            hour >= start && hour < end
+        } else {
+            hour >= start || hour < end
+        };
+
+        if inside {
+            return None;
+        }
+
+        let mut date = now.date_naive();
+        if hour >= start {
+            date = date.succ_opt()?;
+        }
+
+        now.timezone()
+            .from_local_datetime(&date.and_hms_opt(start, 0, 0)?)
+            .earliest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_support::{seed_account, temp_database};
+
+    fn limits_with_window(start: i32, end: i32) -> RateLimits {
+        RateLimits {
+            send_window_start: Some(start),
+            send_window_end: Some(end),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_send_window_handles_same_day_and_overnight_ranges() {
+        let at = |hour| Utc.with_ymd_and_hms(2024, 3, 4, hour, 30, 0).unwrap();
+
+        let office_hours = limits_with_window(9, 17);
+        assert_eq!(RateLimiter::window_opens_at(&office_hours, at(10)), None);
+        assert_eq!(RateLimiter::window_opens_at(&office_hours, at(7)), Some(Utc.with_ymd_and_hms(2024, 3, 4, 9, 0, 0).unwrap()));
+        assert_eq!(RateLimiter::window_opens_at(&office_hours, at(18)), Some(Utc.with_ymd_and_hms(2024, 3, 5, 9, 0, 0).unwrap()));
+
+        let overnight = limits_with_window(22, 6);
+        assert_eq!(RateLimiter::window_opens_at(&overnight, at(23)), None);
+        assert_eq!(RateLimiter::window_opens_at(&overnight, at(3)), None);
+        assert_eq!(RateLimiter::window_opens_at(&overnight, at(12)), Some(Utc.with_ymd_and_hms(2024, 3, 4, 22, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn test_exhausted_quota_defers_until_oldest_send_expires() {
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "quota".to_string(),
+            email: "quota@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+
+        let now = Utc::now();
+        let account_id = seed_account(&database, user.id, "sales@example.com");
+        database.transaction(|tx| {
+            for seconds_ago in [50, 20] {
+                let sent_at = (now - Duration::seconds(seconds_ago)).to_rfc3339();
+                tx.execute(
+                    "INSERT INTO outbox (user_id, email_account_id, recipient_email, message, status, next_attempt_at, sent_at, created_at, updated_at)
+                     VALUES (?1, ?2, 'a@example.com', '{}', 'sent', ?3, ?3, ?3, ?3)",
+                    params![user.id, account_id, &sent_at],
+                )?;
+            }
+            Ok(())
+        }).unwrap();
+        let conn = database.get_connection().unwrap();
+
+        let limits = RateLimits { max_per_minute: Some(2), ..Default::default() };
+        let status = RateLimiter::status(&conn, account_id, &limits, now).unwrap();
+        assert_eq!(status.per_minute.remaining, Some(0));
+        assert_eq!(status.per_hour.remaining, None);
+        assert_eq!(status.next_available_at, Some(now - Duration::seconds(50) + Duration::minutes(1)));
+
+        let relaxed = RateLimits { max_per_minute: Some(3), ..Default::default() };
+        assert_eq!(RateLimiter::next_available_at(&conn, account_id, &relaxed, now).unwrap(), None);
+    }
+}
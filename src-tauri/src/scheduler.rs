@@ -5,15 +5,14 @@ use tokio::time::{interval, Duration as TokioDuration};
 use crate::models::*;
 use crate::database::Database;
 use crate::email_service::EmailService;
-use crate::encryption::EncryptionService;
+use crate::outbox_service::OutboxService;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use log::{info, error, warn};
+use log::{info, error};
 
 pub struct SchedulerService {
     database: Arc<Database>,
     email_service: Arc<Mutex<EmailService>>,
-    encryption_service: Arc<EncryptionService>,
     is_running: Arc<Mutex<bool>>,
 }
 
@@ -21,12 +20,10 @@ impl SchedulerService {
     pub fn new(
         database: Arc<Database>,
         email_service: Arc<Mutex<EmailService>>,
-        encryption_service: Arc<EncryptionService>,
     ) -> Self {
         SchedulerService {
             database,
             email_service,
-            encryption_service,
             is_running: Arc::new(Mutex::new(false)),
         }
     }
@@ -43,7 +40,6 @@ impl SchedulerService {
         
         let database = Arc::clone(&self.database);
         let email_service = Arc::clone(&self.email_service);
-        let encryption_service = Arc::clone(&self.encryption_service);
         let is_running_flag = Arc::clone(&self.is_running);
 
         tokio::spawn(async move {
@@ -63,7 +59,6 @@ impl SchedulerService {
                 if let Err(e) = Self::process_scheduled_emails(
                     &database,
                     &email_service,
-                    &encryption_service,
                 ).await {
                     error!("Error processing scheduled emails: {}", e);
                 }
@@ -84,7 +79,6 @@ impl SchedulerService {
     async fn process_scheduled_emails(
         database: &Database,
         email_service: &Arc<Mutex<EmailService>>,
-        encryption_service: &EncryptionService,
     ) -> Result<(), AppError> {
         let pending_emails = database.get_pending_scheduled_emails()
             .map_err(|e| AppError::Internal(e.to_string()))?;
@@ -93,11 +87,10 @@ impl SchedulerService {
             match Self::send_scheduled_email(
                 database,
                 email_service,
-                encryption_service,
                 &scheduled_email,
             ).await {
                 Ok(_) => {
-                    info!("Successfully sent scheduled email ID: {}", scheduled_email.id);
+                    info!("Queued scheduled email ID: {} for delivery", scheduled_email.id);
                     
                     // Handle recurrence
                     if let Some(pattern) = &scheduled_email.recurrence_pattern {
@@ -138,7 +131,6 @@ impl SchedulerService {
     async fn send_scheduled_email(
         database: &Database,
         email_service: &Arc<Mutex<EmailService>>,
-        encryption_service: &EncryptionService,
         scheduled_email: &ScheduledEmail,
     ) -> Result<(), AppError> {
         // Get user's email accounts
@@ -148,13 +140,9 @@ impl SchedulerService {
             .find(|acc| acc.is_active)
             .ok_or_else(|| AppError::NotFound("No active email account found".to_string()))?;
         
-        // Decrypt password
-        let password = encryption_service.decrypt(&active_account.password_encrypted)
-            .map_err(|e| AppError::Internal(format!("Failed to decrypt password: {}", e)))?;
-        
         // Get template if specified
         let template = if let Some(template_id) = scheduled_email.template_id {
-            database.get_email_template(scheduled_email.user_id, template_id)
+            database.get_email_template(template_id, scheduled_email.user_id)
                 .map_err(|e| AppError::Internal(e.to_string()))?
                 .ok_or_else(|| AppError::NotFound("Email template not found".to_string()))?
         } else {
@@ -169,35 +157,39 @@ impl SchedulerService {
             })
             .collect();
         
-        // Send batch emails
-        let mut email_service_guard = email_service.lock().await;
-        let results = email_service_guard.send_batch_emails(&active_account, &password, &template, &recipients).await?;
-        drop(email_service_guard);
+        // Render each recipient's copy and hand them all to the outbox together
+        let messages: Vec<CreateOutboxMessage> = {
+            let mut email_service_guard = email_service.lock().await;
+            recipients.iter()
+                .map(|recipient| {
+                    let (subject, body) = email_service_guard.render_template(&template, recipient);
+                    CreateOutboxMessage {
+                        user_id: scheduled_email.user_id,
+                        email_account_id: active_account.id,
+                        campaign_id: None,
+                        message: EmailMessage {
+                            to: vec![recipient.email.clone()],
+                            cc: None,
+                            bcc: None,
+                            subject,
+                            body,
+                            attachments: None,
+                            message_id: None,
+                            in_reply_to: None,
+                            references: None,
+                            auto_submitted: None,
+                        },
+                    }
+                })
+                .collect()
+        };
         
-        // Log results
-        for result in results {
-            let (status, recipient) = if result.starts_with("Success:") {
-                ("success", result.strip_prefix("Success: ").unwrap_or(""))
-            } else {
-                ("failed", result.strip_prefix("Failed ").unwrap_or("").split(':').next().unwrap_or(""))
-            };
-            
-            let log_entry = CreateEmailLog {
-                user_id: scheduled_email.user_id,
-                email_account_id: Some(active_account.id),
-                direction: "sent".to_string(),
-                recipient_email: Some(recipient.to_string()),
-                sender_email: Some(active_account.email_address.clone()),
-                subject: template.subject.clone(),
-                status: status.to_string(),
-                error_message: if status == "failed" { Some(result.clone()) } else { None },
-                sent_at: Some(Utc::now()),
-            };
-            
-            if let Err(e) = database.log_email(log_entry) {
-                warn!("Failed to create email log: {}", e);
+        database.transaction(|tx| {
+            for message in &messages {
+                OutboxService::enqueue_in(tx, message)?;
             }
-        }
+            Ok(())
+        })?;
         
         Ok(())
     }
@@ -265,6 +257,49 @@ impl SchedulerService {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::test_support::{seed_account, temp_database};
+
+    #[tokio::test]
+    async fn test_scheduled_email_renders_its_own_template() {
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "scheduler".to_string(),
+            email: "scheduler@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+        seed_account(&database, user.id, "news@example.com");
+
+        // Two templates, so the one scheduled has an id other than the user's
+        let template = |name: &str, subject: &str| database.create_email_template(CreateEmailTemplateWithUser {
+            user_id: user.id,
+            name: name.to_string(),
+            subject: Some(subject.to_string()),
+            body: Some("Hello".to_string()),
+            template_type: None,
+        }).unwrap();
+        template("Welcome", "Welcome aboard");
+        let digest = template("Digest", "Your weekly digest");
+        assert_ne!(digest.id, user.id);
+
+        let scheduled = ScheduledEmail {
+            id: 1,
+            user_id: user.id,
+            template_id: Some(digest.id),
+            recipient_list: vec!["reader@example.com".to_string()],
+            scheduled_time: Utc::now(),
+            recurrence_pattern: None,
+            status: "pending".to_string(),
+            created_at: Utc::now(),
+        };
+        let email_service = Arc::new(Mutex::new(EmailService::new()));
+        SchedulerService::send_scheduled_email(&database, &email_service, &scheduled).await.unwrap();
+
+        let conn = database.get_connection().unwrap();
+        let queued: String = conn.query_row("SELECT message FROM outbox", [], |row| row.get(0)).unwrap();
+        let message: EmailMessage = serde_json::from_str(&queued).unwrap();
+        assert_eq!(message.subject, "Your weekly digest");
+        assert_eq!(message.to, vec!["reader@example.com"]);
+    }
 
     #[test]
     fn test_cron_validation() {
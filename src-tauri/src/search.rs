@@ -0,0 +1,277 @@
+use std::sync::Arc;
+use chrono::NaiveDate;
+use rusqlite::params;
+use crate::models::*;
+use crate::database::Database;
+
+const DEFAULT_LIMIT: i32 = 50;
+
+// bm25 weights for subject, body, sender, recipients and attachments
+const RANK: &str = "bm25(search_index, 10.0, 1.0, 5.0, 2.0, 3.0)";
+
+/// A parsed search box query: free text and `field:` terms become an FTS5 match
+/// expression, the rest become filters on the index's unindexed columns.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub match_expr: Option<String>,
+    pub has_attachment: Option<bool>,
+    /// Exclusive: only mail from before this day
+    pub before: Option<NaiveDate>,
+    /// Inclusive: mail from this day on
+    pub after: Option<NaiveDate>,
+}
+
+/// Parses Gmail-style queries such as `from:acme subject:"march invoice" has:attachment
+/// after:2024-03-01 -draft`. Unknown `word:` prefixes are searched as plain text.
+pub fn parse_query(input: &str) -> Result<SearchQuery, AppError> {
+    let mut query = SearchQuery::default();
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+
+    for token in tokenize(input) {
+        let (negated, token) = match token.strip_prefix('-') {
+            Some(rest) if !rest.is_empty() => (true, rest),
+            _ => (false, token.as_str()),
+        };
+
+        let (column, value) = match token.split_once(':') {
+            Some((field, value)) if !field.contains('"') => match field.to_lowercase().as_str() {
+                "from" => (Some("sender"), value),
+                "to" => (Some("recipients"), value),
+                "subject" => (Some("subject"), value),
+                "body" => (Some("body"), value),
+                "filename" => (Some("attachments"), value),
+                "has" if matches!(value.to_lowercase().as_str(), "attachment" | "attachments") => {
+                    query.has_attachment = Some(!negated);
+                    continue;
+                }
+                "before" => {
+                    query.before = Some(parse_date(value)?);
+                    continue;
+                }
+                "after" => {
+                    query.after = Some(parse_date(value)?);
+                    continue;
+                }
+                _ => (None, token),
+            },
+            _ => (None, token),
+        };
+
+        let term = match fts_term(value) {
+            Some(term) => term,
+            None => continue,
+        };
+        let term = match column {
+            Some(column) => format!("{}:{}", column, term),
+            None => term,
+        };
+
+        if negated {
+            exclude.push(term);
+        } else {
+            include.push(term);
+        }
+    }
+
+    if include.is_empty() && !exclude.is_empty() {
+        return Err(AppError::Validation("Add at least one search term for the excluded terms to narrow down".to_string()));
+    }
+
+    if !include.is_empty() {
+        let mut expr = include.join(" AND ");
+        for term in exclude {
+            expr.push_str(" NOT ");
+            expr.push_str(&term);
+        }
+        query.match_expr = Some(expr);
+    }
+
+    Ok(query)
+}
+
+/// Splits on whitespace, keeping double-quoted phrases (including `field:"a phrase"`) whole.
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+/// Quotes a value as an FTS5 string so operators and punctuation in it are literal;
+/// a trailing `*` on an unquoted word keeps its meaning as a prefix search.
+fn fts_term(value: &str) -> Option<String> {
+    let phrase = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+    let (text, prefix) = match (phrase, value.strip_suffix('*')) {
+        (true, _) => (&value[1..value.len() - 1], false),
+        (false, Some(stem)) => (stem, true),
+        (false, None) => (value, false),
+    };
+    let text = text.trim_matches('"').trim();
+
+    if text.is_empty() {
+        return None;
+    }
+
+    let quoted = format!("\"{}\"", text.replace('"', "\"\""));
+    Some(if prefix { quoted + "*" } else { quoted })
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, AppError> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| AppError::Validation(format!("Invalid date '{}', expected YYYY-MM-DD", value)))
+}
+
+/// Searches the full-text index over stored messages and email logs.
+pub struct SearchService {
+    database: Arc<Database>,
+}
+
+impl SearchService {
+    pub fn new(database: Arc<Database>) -> Self {
+        Self { database }
+    }
+
+    /// Best matches first; a query with only filters lists the newest matching mail.
+    pub fn search(&self, user_id: i32, input: &str, limit: Option<i32>) -> Result<Vec<SearchResult>, AppError> {
+        let query = parse_query(input)?;
+        if query == SearchQuery::default() {
+            return Ok(Vec::new());
+        }
+
+        let (selection, order) = match query.match_expr {
+            Some(_) => (format!("snippet(search_index, -1, '[', ']', '…', 12), {}", RANK), RANK.to_string()),
+            None => ("substr(body, 1, 120), 0.0".to_string(), "occurred_at DESC".to_string()),
+        };
+        let matching = if query.match_expr.is_some() { "search_index MATCH ?1" } else { "?1 IS NULL" };
+
+        let conn = self.database.get_connection()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT source, source_id, subject, sender, recipients, occurred_at, has_attachment, {}
+             FROM search_index
+             WHERE {}
+               AND user_id = ?2
+               AND (?3 IS NULL OR has_attachment = ?3)
+               AND (?4 IS NULL OR occurred_at < ?4)
+               AND (?5 IS NULL OR occurred_at >= ?5)
+             ORDER BY {}
+             LIMIT ?6",
+            selection, matching, order
+        ))?;
+
+        let day = |date: Option<NaiveDate>| date.map(|date| format!("{} 00:00:00", date));
+        let results = stmt.query_map(
+            params![
+                query.match_expr,
+                user_id,
+                query.has_attachment,
+                day(query.before),
+                day(query.after),
+                limit.unwrap_or(DEFAULT_LIMIT),
+            ],
+            |row| Ok(SearchResult {
+                source: row.get(0)?,
+                id: row.get(1)?,
+                subject: row.get(2)?,
+                sender: row.get(3)?,
+                recipients: row.get(4)?,
+                occurred_at: row.get(5)?,
+                has_attachment: row.get(6)?,
+                snippet: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                rank: row.get(8)?,
+            }),
+        )?.collect::<Result<Vec<_>, _>>()?;
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_support::{seed_account, temp_database};
+
+    #[test]
+    fn test_parse_qualifiers_into_match_and_filters() {
+        let query = parse_query(r#"from:acme subject:"march invoice" has:attachment after:2024-03-01 paym* -draft"#).unwrap();
+        assert_eq!(
+            query.match_expr.as_deref(),
+            Some(r#"sender:"acme" AND subject:"march invoice" AND "paym"* NOT "draft""#)
+        );
+        assert_eq!(query.has_attachment, Some(true));
+        assert_eq!(query.after, NaiveDate::from_ymd_opt(2024, 3, 1));
+        assert_eq!(query.before, None);
+
+        // Punctuation and unknown prefixes are plain text, not FTS5 syntax
+        let query = parse_query("re:hello AND -has:attachment").unwrap();
+        assert_eq!(query.match_expr.as_deref(), Some(r#""re:hello" AND "AND""#));
+        assert_eq!(query.has_attachment, Some(false));
+
+        assert!(parse_query("-draft").is_err());
+        assert!(parse_query("before:yesterday").is_err());
+    }
+
+    #[test]
+    fn test_search_ranks_stored_messages_and_logs() {
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "search".to_string(),
+            email: "search@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+
+        let account_id = seed_account(&database, user.id, "support@example.com");
+        database.transaction(|tx| {
+            tx.execute(
+                "INSERT INTO messages (user_id, email_account_id, folder, uid_validity, uid, subject, sender, recipients, attachments, body_text, raw_path, received_at)
+                 VALUES (?1, ?2, 'INBOX', 1, 1, 'Invoice for March', 'Acme Billing <billing@acme.com>', '[\"support@example.com\"]', '[\"invoice-0324.pdf\"]', 'Please find the invoice attached.', 'x', '2024-03-04 09:30:00+00:00')",
+                params![user.id, account_id],
+            )?;
+            Ok(())
+        }).unwrap();
+        database.log_email(CreateEmailLog {
+            user_id: user.id,
+            email_account_id: None,
+            direction: "sent".to_string(),
+            recipient_email: Some("billing@acme.com".to_string()),
+            sender_email: Some("support@example.com".to_string()),
+            subject: Some("Re: Invoice for March".to_string()),
+            status: "success".to_string(),
+            error_message: None,
+            sent_at: Some(chrono::Utc::now()),
+        }).unwrap();
+
+        let search = SearchService::new(database.shared());
+
+        let results = search.search(user.id, "invoice", None).unwrap();
+        assert_eq!(results.len(), 2);
+
+        let results = search.search(user.id, "from:acme has:attachment", None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].source.as_str(), results[0].has_attachment), ("message", true));
+        assert!(results[0].snippet.contains('['));
+
+        assert_eq!(search.search(user.id, "filename:invoice*", None).unwrap().len(), 1);
+        assert_eq!(search.search(user.id, "invoice before:2024-03-04", None).unwrap().len(), 0);
+        assert!(search.search(user.id + 1, "invoice", None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+use std::sync::{Arc, LazyLock};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use rusqlite::{params, OptionalExtension, Transaction};
+use crate::models::*;
+use crate::database::Database;
+
+// How recent a thread must be for a reply with unknown references to join it by subject
+const SUBJECT_WINDOW_DAYS: i64 = 30;
+
+static MESSAGE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^<>\s]+>").unwrap());
+static REPLY_PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^\s*(\[[^\]]*\]\s*)*(re|fw|fwd|aw|wg|sv|vs|antw)(\[\d+\])?\s*:").unwrap()
+});
+static LIST_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*\[[^\]]*\]").unwrap());
+
+/// The headers threading looks at.
+pub struct ThreadHeaders<'a> {
+    pub message_id: Option<&'a str>,
+    pub in_reply_to: Option<&'a str>,
+    pub references: Option<&'a str>,
+    pub subject: &'a str,
+    pub date: DateTime<Utc>,
+}
+
+/// Extracts the `<id>` tokens from a Message-ID, In-Reply-To or References header.
+pub fn parse_ids(header: &str) -> Vec<String> {
+    MESSAGE_ID.find_iter(header).map(|id| id.as_str().to_string()).collect()
+}
+
+/// Reduces a subject to what JWZ calls the base subject: list tags such as `[support]`
+/// and any number of `Re:`/`Fwd:` prefixes (including localized and counted forms like
+/// `AW:` or `Re[2]:`) are removed. Also reports whether a reply prefix was found.
+pub fn normalize_subject(subject: &str) -> (String, bool) {
+    let mut base = subject.to_string();
+    let mut is_reply = false;
+    loop {
+        if let Some(found) = REPLY_PREFIX.find(&base) {
+            is_reply = true;
+            base = base[found.end()..].to_string();
+        } else if let Some(found) = LIST_TAG.find(&base) {
+            base = base[found.end()..].to_string();
+        } else {
+            break;
+        }
+    }
+
+    let base = base.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    (base, is_reply)
+}
+
+/// Groups received and sent mail into conversations.
+///
+/// This is the JWZ algorithm applied one message at a time: every Message-ID a message
+/// mentions (its own, In-Reply-To and References) is linked to one thread, so ids seen
+/// only as ancestors act as JWZ's empty containers. A message linked to two threads
+/// merges them. A reply whose ancestors are all unknown falls back to joining a recent
+/// thread with the same base subject; new messages without a reply prefix never do.
+pub struct ThreadingService {
+    database: Arc<Database>,
+}
+
+impl ThreadingService {
+    pub fn new(database: Arc<Database>) -> Self {
+        Self { database }
+    }
+
+    /// Finds or creates the thread for a message inside the caller's transaction and
+    /// records the message's ids against it.
+    pub fn assign_in(tx: &Transaction, user_id: i32, headers: &ThreadHeaders) -> Result<i32, AppError> {
+        let mut ancestors = headers.references.map(parse_ids).unwrap_or_default();
+        for id in headers.in_reply_to.map(parse_ids).unwrap_or_default() {
+            if !ancestors.contains(&id) {
+                ancestors.push(id);
+            }
+        }
+        let own_id = headers.message_id.and_then(|id| parse_ids(id).into_iter().next());
+        let ids: Vec<&String> = ancestors.iter().chain(own_id.as_ref()).collect();
+
+        let mut linked = Vec::new();
+        {
+            let mut stmt = tx.prepare_cached("SELECT thread_id FROM thread_message_ids WHERE user_id = ?1 AND message_id = ?2")?;
+            for id in &ids {
+                if let Some(thread_id) = stmt.query_row(params![user_id, id], |row| row.get::<_, i32>(0)).optional()? {
+                    linked.push(thread_id);
+                }
+            }
+        }
+        linked.sort_unstable();
+        linked.dedup();
+
+        let (subject, is_reply) = normalize_subject(headers.subject);
+        let date = headers.date.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        let thread_id = match linked.split_first() {
+            Some((&thread_id, others)) => {
+                for &other in others {
+                    Self::merge(tx, thread_id, other)?;
+                }
+                thread_id
+            }
+            None => {
+                let by_subject = if (is_reply || !ancestors.is_empty()) && !subject.is_empty() {
+                    tx.query_row(
+                        "SELECT id FROM threads
+                         WHERE user_id = ?1 AND subject = ?2 AND last_activity >= datetime(?3, ?4)
+                         ORDER BY last_activity DESC LIMIT 1",
+                        params![user_id, subject, date, format!("-{} days", SUBJECT_WINDOW_DAYS)],
+                        |row| row.get(0),
+                    ).optional()?
+                } else {
+                    None
+                };
+
+                match by_subject {
+                    Some(thread_id) => thread_id,
+                    None => {
+                        tx.execute(
+                            "INSERT INTO threads (user_id, subject, last_activity) VALUES (?1, ?2, ?3)",
+                            params![user_id, subject, date],
+                        )?;
+                        tx.last_insert_rowid() as i32
+                    }
+                }
+            }
+        };
+
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO thread_message_ids (user_id, message_id, thread_id) VALUES (?1, ?2, ?3)"
+            )?;
+            for id in &ids {
+                stmt.execute(params![user_id, id, thread_id])?;
+            }
+        }
+
+        tx.execute(
+            "UPDATE threads SET last_activity = MAX(last_activity, ?1) WHERE id = ?2",
+            params![date, thread_id],
+        )?;
+
+        Ok(thread_id)
+    }
+
+    /// Moves everything in `other` into `target` and drops `other`.
+    fn merge(tx: &Transaction, target: i32, other: i32) -> Result<(), AppError> {
+        tx.execute("UPDATE thread_message_ids SET thread_id = ?1 WHERE thread_id = ?2", [target, other])?;
+        tx.execute("UPDATE messages SET thread_id = ?1 WHERE thread_id = ?2", [target, other])?;
+        tx.execute("UPDATE outbox SET thread_id = ?1 WHERE thread_id = ?2", [target, other])?;
+        tx.execute(
+            "UPDATE threads SET last_activity = MAX(last_activity, (SELECT last_activity FROM threads WHERE id = ?2)) WHERE id = ?1",
+            [target, other],
+        )?;
+        tx.execute("DELETE FROM threads WHERE id = ?1", [other])?;
+        Ok(())
+    }
+
+    /// Threads stored and queued mail that has none yet, oldest first, e.g. mail stored
+    /// before threading existed.
+    pub fn assign_missing(&self) -> Result<usize, AppError> {
+        self.database.transaction(|tx| {
+            let mut stmt = tx.prepare(
+                "SELECT id, user_id, message_id, in_reply_to, references_header, COALESCE(subject, ''), received_at
+                 FROM messages WHERE thread_id IS NULL ORDER BY received_at, id"
+            )?;
+            let received = stmt.query_map([], |row| Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, i32>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, DateTime<Utc>>(6)?,
+            )))?.collect::<Result<Vec<_>, _>>()?;
+
+            for (id, user_id, message_id, in_reply_to, references, subject, date) in &received {
+                let thread_id = Self::assign_in(tx, *user_id, &ThreadHeaders {
+                    message_id: message_id.as_deref(),
+                    in_reply_to: in_reply_to.as_deref(),
+                    references: references.as_deref(),
+                    subject,
+                    date: *date,
+                })?;
+                tx.execute("UPDATE messages SET thread_id = ?1 WHERE id = ?2", [thread_id, *id])?;
+            }
+
+            let mut stmt = tx.prepare(
+                "SELECT id, user_id, message_id, message, created_at FROM outbox WHERE thread_id IS NULL ORDER BY created_at, id"
+            )?;
+            let sent = stmt.query_map([], |row| Ok((
+                row.get::<_, i32>(0)?,
+                row.get::<_, i32>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, DateTime<Utc>>(4)?,
+            )))?.collect::<Result<Vec<_>, _>>()?;
+
+            for (id, user_id, message_id, message_json, date) in &sent {
+                let message: EmailMessage = match serde_json::from_str(message_json) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                let thread_id = Self::assign_in(tx, *user_id, &ThreadHeaders {
+                    message_id: message_id.as_deref(),
+                    in_reply_to: message.in_reply_to.as_deref(),
+                    references: message.references.as_deref(),
+                    subject: &message.subject,
+                    date: *date,
+                })?;
+                tx.execute("UPDATE outbox SET thread_id = ?1 WHERE id = ?2", [thread_id, *id])?;
+            }
+
+            Ok(received.len() + sent.len())
+        })
+    }
+
+    /// A thread with its received and sent messages in the order they happened.
+    pub fn get_thread(&self, user_id: i32, thread_id: i32) -> Result<MessageThread, AppError> {
+        let conn = self.database.get_connection()?;
+
+        let (subject, last_activity) = conn.query_row(
+            "SELECT subject, last_activity FROM threads WHERE id = ?1 AND user_id = ?2",
+            [thread_id, user_id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, DateTime<Utc>>(1)?)),
+        ).optional()?
+            .ok_or_else(|| AppError::NotFound("Thread not found".to_string()))?;
+
+        let mut stmt = conn.prepare(
+            "SELECT 'received', m.id, m.message_id, m.in_reply_to, m.subject, m.sender,
+                    (SELECT group_concat(value, ', ') FROM json_each(m.recipients)),
+                    m.received_at, m.body_text, NULL
+             FROM messages m WHERE m.user_id = ?1 AND m.thread_id = ?2
+             UNION ALL
+             SELECT 'sent', o.id, o.message_id, json_extract(o.message, '$.in_reply_to'), o.subject,
+                    (SELECT a.email_address FROM email_accounts a WHERE a.id = o.email_account_id),
+                    o.recipient_email, COALESCE(o.sent_at, o.created_at), json_extract(o.message, '$.body'), o.status
+             FROM outbox o WHERE o.user_id = ?1 AND o.thread_id = ?2"
+        )?;
+
+        let mut messages = stmt.query_map([user_id, thread_id], |row| Ok(ThreadMessage {
+            direction: row.get(0)?,
+            id: row.get(1)?,
+            message_id: row.get(2)?,
+            in_reply_to: row.get(3)?,
+            subject: row.get(4)?,
+            sender: row.get(5)?,
+            recipients: row.get(6)?,
+            date: row.get(7)?,
+            body: row.get(8)?,
+            status: row.get(9)?,
+        }))?.collect::<Result<Vec<_>, _>>()?;
+
+        // Received dates are stored with a space, sent ones in RFC 3339, so sort on the parsed value
+        messages.sort_by_key(|message| message.date);
+
+        Ok(MessageThread {
+            id: thread_id,
+            subject,
+            last_activity,
+            messages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use crate::database::test_support::temp_database;
+
+    #[test]
+    fn test_normalize_subject() {
+        assert_eq!(normalize_subject("Invoice  March"), ("invoice march".to_string(), false));
+        assert_eq!(normalize_subject("RE: Fwd: Invoice March"), ("invoice march".to_string(), true));
+        assert_eq!(normalize_subject("[support] AW: Re[2]: Invoice March"), ("invoice march".to_string(), true));
+        assert_eq!(normalize_subject("Re:"), (String::new(), true));
+        assert_eq!(parse_ids("<a@x.com>\r\n <b@y.com>"), vec!["<a@x.com>", "<b@y.com>"]);
+    }
+
+    #[test]
+    fn test_messages_join_threads_by_reference_then_subject() {
+        let database = temp_database();
+        let user = database.create_user(CreateUser {
+            username: "threads".to_string(),
+            email: "threads@example.com".to_string(),
+            password: "secret".to_string(),
+        }).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 3, 4, 9, 0, 0).unwrap();
+
+        let assign = |message_id: &str, in_reply_to: Option<&str>, references: Option<&str>, subject: &str, hours: i64| {
+            database.transaction(|tx| ThreadingService::assign_in(tx, user.id, &ThreadHeaders {
+                message_id: Some(message_id),
+                in_reply_to,
+                references,
+                subject,
+                date: start + Duration::hours(hours),
+            })).unwrap()
+        };
+
+        let order = assign("<a@x>", None, None, "Order 1234", 0);
+        let reply = assign("<b@y>", Some("<a@x>"), Some("<a@x>"), "Re: Order 1234", 1);
+        assert_eq!(reply, order);
+
+        // Unknown ancestors, but a reply to a recent thread with the same base subject
+        let by_subject = assign("<c@z>", Some("<lost@x>"), None, "RE: order 1234", 2);
+        assert_eq!(by_subject, order);
+
+        // Same subject without a reply prefix or references starts its own thread
+        let unrelated = assign("<d@x>", None, None, "Order 1234", 3);
+        assert_ne!(unrelated, order);
+
+        // A message referring to both threads merges them
+        let merged = assign("<e@y>", Some("<d@x>"), Some("<a@x> <d@x>"), "Re: Order 1234", 4);
+        assert_eq!(merged, order);
+        let remaining: i32 = database.get_connection().unwrap()
+            .query_row("SELECT COUNT(*) FROM threads WHERE user_id = ?1", [user.id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(remaining, 1);
+    }
+}